@@ -0,0 +1,49 @@
+//! Checks that the proof verification path of `mpt-rs` builds and runs 
+//! without `std`. `cargo build --target thumbv7em-none-eabi` fails if 
+//! anything in the dependency graph pulls `std` back in, `cargo test` runs 
+//! the verification below on the host.
+#![no_std]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use mpt_rs::{proof::verify_eth_proof, mpt::KecHash, error::Result};
+
+/// Verifies the account proof of `address` against `state_root`.
+pub fn verify_account(
+    state_root: &KecHash, proof: &[Vec<u8>], address: &[u8; 20]
+) -> Result<Option<Vec<u8>>> {
+    verify_eth_proof(state_root, proof, address)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+    use mpt_rs::keccak256;
+    use super::*;
+
+    #[test]
+    fn test_verify_account() {
+        let address = [0xb4; 20];
+        // [nonce = 1, balance = 1 ether, storage root = empty, code hash = keccak256("")]
+        let mut account = vec![0xf8, 0x4c, 0x01, 0x88, 0x0d, 0xe0, 0xb6, 0xb3, 0xa7, 0x64, 0x00, 0x00, 0xa0];
+        account.extend_from_slice(&keccak256(&[0x80]));
+        account.push(0xa0);
+        account.extend_from_slice(&keccak256(&[]));
+
+        // a state trie holding a single account is a single leaf node:
+        // [hex_prefix(keccak256(address), leaf), account]
+        let mut leaf = vec![0xf8, 0x72, 0xa1, 0x20];
+        leaf.extend_from_slice(&keccak256(&address));
+        leaf.extend_from_slice(&[0xb8, account.len() as u8]);
+        leaf.extend_from_slice(&account);
+        let state_root = keccak256(&leaf);
+
+        let proof = vec![leaf];
+        assert_eq!(verify_account(&state_root, &proof, &address).unwrap(), Some(account));
+
+        let mut other = address;
+        other[0] ^= 1;
+        assert_eq!(verify_account(&state_root, &proof, &other).unwrap(), None);
+    }
+}
@@ -0,0 +1,466 @@
+//! Database backends shipped with this crate.
+
+#[cfg(feature = "std")]
+use std::{
+    collections::HashMap, fs, io,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering}
+};
+
+use alloc::{vec::Vec, collections::BTreeMap, borrow::Cow};
+
+use serde_bytes::{ByteBuf, Bytes};
+use serlp::rlp::{to_bytes, from_bytes};
+
+use crate::{mpt::{Database, KecHash, KEY_LEN, db_error, keccak256}, error::{Error, Result}};
+
+/// An in-memory database backed by a [`HashMap`], handy for tests and
+/// short-lived tries. Keys are hashes of `N` bytes.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Default)]
+pub struct MemoryDb<const N: usize = KEY_LEN>(HashMap<KecHash<N>, Vec<u8>>);
+
+#[cfg(feature = "std")]
+impl MemoryDb {
+    /// An empty database keyed by hashes of [`KEY_LEN`] bytes, 
+    /// use [`Database::new`] for other lengths.
+    pub fn new() -> Self {
+        Self(HashMap::new())
+    }
+}
+
+#[cfg(feature = "std")]
+impl<const N: usize> MemoryDb<N> {
+    /// number of nodes stored
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// number of nodes that can be stored without reallocating
+    pub fn capacity(&self) -> usize {
+        self.0.capacity()
+    }
+
+    /// iterate over all stored (hash, node) pairs in arbitrary order
+    pub fn iter(&self) -> impl Iterator<Item = (&KecHash<N>, &[u8])> {
+        self.0.iter().map(|(key, value)| (key, value.as_slice()))
+    }
+
+    /// remove a node, returning it if it was stored
+    pub fn remove(&mut self, key: &KecHash<N>) -> Option<Vec<u8>> {
+        self.0.remove(key)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<const N: usize> Database<N> for MemoryDb<N> {
+    type Error = Error;
+
+    fn new() -> Self {
+        Self(HashMap::new())
+    }
+
+    fn insert(&mut self, key: &KecHash<N>, value: Vec<u8>) -> Result<()> {
+        self.0.insert(*key, value);
+        Ok(())
+    }
+
+    fn exists(&mut self, key: &KecHash<N>) -> Result<bool> {
+        Ok(self.0.contains_key(key))
+    }
+
+    fn get(&self, key: &KecHash<N>) -> Result<Option<Vec<u8>>> {
+        Ok(self.0.get(key).cloned())
+    }
+
+    fn get_ref(&self, key: &KecHash<N>) -> Result<Option<Cow<'_, [u8]>>> {
+        Ok(self.0.get(key).map(|value| Cow::Borrowed(value.as_slice())))
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        self.0.reserve(additional)
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.0.clear();
+        Ok(())
+    }
+
+    fn retain(&mut self, keep: impl Fn(&KecHash<N>) -> bool) -> Result<usize> {
+        let before = self.0.len();
+        self.0.retain(|key, _| keep(key));
+        Ok(before - self.0.len())
+    }
+}
+
+/// The nodes of one or more proofs, as produced by [`Trie::get_proof`] and 
+/// consumed by [`verify_proof`](crate::proof::verify_proof). Unlike 
+/// [`MemoryDb`] it can be sent over the wire as a single RLP list of nodes.
+/// 
+/// [`Trie::get_proof`]: crate::mpt::Trie::get_proof
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProofDb(BTreeMap<KecHash, Vec<u8>>);
+
+impl ProofDb {
+    /// number of nodes in the proof
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// iterate over all (hash, node) pairs in order of hash
+    pub fn iter(&self) -> impl Iterator<Item = (&KecHash, &[u8])> {
+        self.0.iter().map(|(key, value)| (key, value.as_slice()))
+    }
+
+    /// Encode the nodes as an RLP list of byte strings, sorted by hash so 
+    /// that equal proofs encode to equal bytes.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let nodes: Vec<_> = self.0.values().map(|node| Bytes::new(node)).collect();
+        Ok(to_bytes(&nodes)?)
+    }
+
+    /// Decode a list produced by [`ProofDb::to_bytes`]. Nodes are keyed by 
+    /// their hash, so a tampered node simply fails verification later.
+    pub fn from_bytes(buf: &[u8]) -> Result<Self> {
+        let nodes: Vec<ByteBuf> = from_bytes(buf)?;
+        Ok(Self(nodes.into_iter()
+            .map(|node| (keccak256(&node), node.into_vec()))
+            .collect()))
+    }
+}
+
+impl Database for ProofDb {
+    type Error = Error;
+
+    fn new() -> Self {
+        Self(BTreeMap::new())
+    }
+
+    fn insert(&mut self, key: &KecHash, value: Vec<u8>) -> Result<()> {
+        self.0.insert(*key, value);
+        Ok(())
+    }
+
+    fn exists(&mut self, key: &KecHash) -> Result<bool> {
+        Ok(self.0.contains_key(key))
+    }
+
+    fn get(&self, key: &KecHash) -> Result<Option<Vec<u8>>> {
+        Ok(self.0.get(key).cloned())
+    }
+
+    fn get_ref(&self, key: &KecHash) -> Result<Option<Cow<'_, [u8]>>> {
+        Ok(self.0.get(key).map(|value| Cow::Borrowed(value.as_slice())))
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.0.clear();
+        Ok(())
+    }
+
+    fn retain(&mut self, keep: impl Fn(&KecHash) -> bool) -> Result<usize> {
+        let before = self.0.len();
+        self.0.retain(|key, _| keep(key));
+        Ok(before - self.0.len())
+    }
+}
+
+/// A database storing each node in its own file, named by the hex encoded 
+/// hash, under a directory. Handy for small persistent stores and for 
+/// inspecting individual nodes while debugging.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone)]
+pub struct FileDb {
+    dir: PathBuf,
+}
+
+#[cfg(feature = "std")]
+impl FileDb {
+    /// Open the store in `dir`, creating the directory if needed.
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        fs::create_dir_all(dir.as_ref()).map_err(io_error)?;
+        Ok(Self { dir: dir.as_ref().to_path_buf() })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn path(&self, key: &KecHash) -> PathBuf {
+        self.dir.join(hex::encode(key))
+    }
+}
+
+#[cfg(feature = "std")]
+fn io_error(err: io::Error) -> Error {
+    Error::DatabaseError(err.to_string())
+}
+
+#[cfg(feature = "std")]
+impl Database for FileDb {
+    type Error = Error;
+
+    /// Open a fresh store in a new directory under the system temporary directory.
+    /// Use [`FileDb::open`] to choose where nodes are kept.
+    fn new() -> Self {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "mpt-rs-{}-{}", std::process::id(), COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        Self::open(dir).expect("Failed to create a temporary directory.")
+    }
+
+    /// The node is written to a temporary file first and renamed into place,
+    /// so readers never see a partially written node.
+    fn insert(&mut self, key: &KecHash, value: Vec<u8>) -> Result<()> {
+        let path = self.path(key);
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, value).map_err(io_error)?;
+        fs::rename(&tmp, &path).map_err(io_error)
+    }
+
+    fn exists(&mut self, key: &KecHash) -> Result<bool> {
+        Ok(self.path(key).exists())
+    }
+
+    fn get(&self, key: &KecHash) -> Result<Option<Vec<u8>>> {
+        match fs::read(self.path(key)) {
+            Ok(value) => Ok(Some(value)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(io_error(err))
+        }
+    }
+
+    /// Remove every node file, keeping the directory itself.
+    fn clear(&mut self) -> Result<()> {
+        for entry in fs::read_dir(&self.dir).map_err(io_error)? {
+            let path = entry.map_err(io_error)?.path();
+            if path.is_file() {
+                fs::remove_file(path).map_err(io_error)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Files whose name is not a hex encoded hash are left alone.
+    fn retain(&mut self, keep: impl Fn(&KecHash) -> bool) -> Result<usize> {
+        let mut removed = 0;
+        for entry in fs::read_dir(&self.dir).map_err(io_error)? {
+            let path = entry.map_err(io_error)?.path();
+            let key = path.file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| hex::decode(name).ok())
+                .and_then(|key| KecHash::try_from(key.as_slice()).ok());
+            if let Some(key) = key {
+                if !keep(&key) {
+                    fs::remove_file(path).map_err(io_error)?;
+                    removed += 1;
+                }
+            }
+        }
+        Ok(removed)
+    }
+}
+
+/// A database buffering every insertion in memory on top of a base database,
+/// which is only read from until [`OverlayDb::commit_overlay`] is called. 
+/// Handy to try out changes, e.g. speculative block execution, 
+/// see [`Trie::checkpoint_db`](crate::mpt::Trie::checkpoint_db).
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Default)]
+pub struct OverlayDb<Base> {
+    base: Base,
+    layer: HashMap<KecHash, Vec<u8>>,
+}
+
+#[cfg(feature = "std")]
+impl<Base: Database> OverlayDb<Base> {
+    pub fn with_base(base: Base) -> Self {
+        Self { base, layer: HashMap::new() }
+    }
+
+    pub fn base(&self) -> &Base {
+        &self.base
+    }
+
+    /// number of nodes buffered in the overlay
+    pub fn pending(&self) -> usize {
+        self.layer.len()
+    }
+
+    /// Write every buffered node into the base database.
+    pub fn commit_overlay(&mut self) -> Result<()> {
+        self.base.reserve(self.layer.len());
+        for (key, value) in self.layer.drain() {
+            self.base.insert(&key, value).map_err(db_error)?;
+        }
+        Ok(())
+    }
+
+    /// Drop every buffered node.
+    pub fn discard(&mut self) {
+        self.layer.clear()
+    }
+
+    /// The base database, dropping every buffered node.
+    pub fn into_base(self) -> Base {
+        self.base
+    }
+}
+
+#[cfg(feature = "std")]
+impl<Base: Database> Database for OverlayDb<Base> {
+    type Error = Error;
+
+    fn new() -> Self {
+        Self::with_base(Base::new())
+    }
+
+    fn insert(&mut self, key: &KecHash, value: Vec<u8>) -> Result<()> {
+        self.layer.insert(*key, value);
+        Ok(())
+    }
+
+    fn exists(&mut self, key: &KecHash) -> Result<bool> {
+        if self.layer.contains_key(key) {
+            return Ok(true)
+        }
+        self.base.exists(key).map_err(db_error)
+    }
+
+    fn get(&self, key: &KecHash) -> Result<Option<Vec<u8>>> {
+        match self.layer.get(key) {
+            Some(value) => Ok(Some(value.clone())),
+            None => self.base.get(key).map_err(db_error)
+        }
+    }
+
+    fn get_ref(&self, key: &KecHash) -> Result<Option<Cow<'_, [u8]>>> {
+        match self.layer.get(key) {
+            Some(value) => Ok(Some(Cow::Borrowed(value.as_slice()))),
+            None => self.base.get_ref(key).map_err(db_error)
+        }
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        self.layer.reserve(additional)
+    }
+
+    /// Only flushes the base, buffered nodes stay in the overlay.
+    fn flush(&mut self) -> Result<()> {
+        self.base.flush().map_err(db_error)
+    }
+
+    /// Clears both the overlay and the base.
+    fn clear(&mut self) -> Result<()> {
+        self.layer.clear();
+        self.base.clear().map_err(db_error)
+    }
+
+    /// Removes nodes from both the overlay and the base.
+    fn retain(&mut self, keep: impl Fn(&KecHash) -> bool) -> Result<usize> {
+        let before = self.layer.len();
+        self.layer.retain(|key, _| keep(key));
+        Ok(before - self.layer.len() + self.base.retain(keep).map_err(db_error)?)
+    }
+}
+
+/// length of the keys of the base database of a [`NamespacedDb`]
+pub const NAMESPACED_KEY_LEN: usize = 4 + KEY_LEN;
+
+/// A database storing nodes of one trie under keys prefixed with a namespace, 
+/// so that several tries sharing a base database never share a stored node,
+/// see [`TrieBuilder::namespace`](crate::mpt::TrieBuilder::namespace).
+/// The nodes of a trie can then be pruned without affecting the others, 
+/// at the cost of storing identical nodes once per namespace.
+/// 
+/// Hashes inside the nodes are left untouched, only the keys used to access 
+/// the base database change, which is keyed by [`NAMESPACED_KEY_LEN`] bytes.
+#[derive(Debug, Clone, Default)]
+pub struct NamespacedDb<Base> {
+    base: Base,
+    namespace: [u8; 4],
+}
+
+impl<Base: Database<NAMESPACED_KEY_LEN>> NamespacedDb<Base> {
+    pub fn with_namespace(base: Base, namespace: [u8; 4]) -> Self {
+        Self { base, namespace }
+    }
+
+    pub fn namespace(&self) -> [u8; 4] {
+        self.namespace
+    }
+
+    pub fn base(&self) -> &Base {
+        &self.base
+    }
+
+    pub fn into_base(self) -> Base {
+        self.base
+    }
+
+    /// The key a node hashed to `hash` is stored under in the base database, 
+    /// `namespace || hash`.
+    pub fn base_key(namespace: [u8; 4], hash: &KecHash) -> KecHash<NAMESPACED_KEY_LEN> {
+        let mut key = [0; NAMESPACED_KEY_LEN];
+        key[..4].copy_from_slice(&namespace);
+        key[4..].copy_from_slice(hash);
+        key
+    }
+}
+
+impl<Base: Database<NAMESPACED_KEY_LEN>> Database for NamespacedDb<Base> {
+    type Error = Error;
+
+    /// A fresh base database with the namespace `[0; 4]`.
+    fn new() -> Self {
+        Self::with_namespace(Base::new(), [0; 4])
+    }
+
+    fn insert(&mut self, key: &KecHash, value: Vec<u8>) -> Result<()> {
+        self.base.insert(&Self::base_key(self.namespace, key), value).map_err(db_error)
+    }
+
+    fn exists(&mut self, key: &KecHash) -> Result<bool> {
+        self.base.exists(&Self::base_key(self.namespace, key)).map_err(db_error)
+    }
+
+    fn get(&self, key: &KecHash) -> Result<Option<Vec<u8>>> {
+        self.base.get(&Self::base_key(self.namespace, key)).map_err(db_error)
+    }
+
+    fn get_ref(&self, key: &KecHash) -> Result<Option<Cow<'_, [u8]>>> {
+        self.base.get_ref(&Self::base_key(self.namespace, key)).map_err(db_error)
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        self.base.reserve(additional)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.base.flush().map_err(db_error)
+    }
+
+    /// Only removes the nodes of this namespace from the base.
+    fn clear(&mut self) -> Result<()> {
+        let namespace = self.namespace;
+        self.base.retain(|key| !key.starts_with(&namespace)).map_err(db_error)?;
+        Ok(())
+    }
+
+    /// Only considers the nodes of this namespace, the others are kept.
+    fn retain(&mut self, keep: impl Fn(&KecHash) -> bool) -> Result<usize> {
+        let namespace = self.namespace;
+        self.base.retain(|key| {
+            !key.starts_with(&namespace) || <&KecHash>::try_from(&key[4..]).map_or(true, |hash| keep(hash))
+        }).map_err(db_error)
+    }
+}
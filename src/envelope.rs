@@ -0,0 +1,81 @@
+//! EIP-2718 typed envelopes, as stored in the transaction and receipt tries.
+//! 
+//! A typed transaction or receipt is not RLP encoded as a whole: its encoding 
+//! is the type byte followed by the RLP encoding of the payload. Legacy ones 
+//! are plain RLP lists, which always start with a byte of at least `0xc0`.
+
+use serde::{Serialize, de::DeserializeOwned};
+use serlp::rlp::{to_bytes, from_bytes};
+
+use crate::{mpt::{Trie, Database}, error::{Error, Result}};
+
+/// Largest type byte of a typed envelope, as defined by EIP-2718.
+pub const MAX_ENVELOPE_TYPE: u8 = 0x7f;
+
+/// A value with an optional EIP-2718 type byte, `None` for legacy values.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TypedEnvelope<T> {
+    pub kind: Option<u8>,
+    pub payload: T,
+}
+
+impl<T> TypedEnvelope<T> {
+    pub fn legacy(payload: T) -> Self {
+        Self { kind: None, payload }
+    }
+
+    /// Fails if `kind` is above [`MAX_ENVELOPE_TYPE`].
+    pub fn typed(kind: u8, payload: T) -> Result<Self> {
+        if kind > MAX_ENVELOPE_TYPE {
+            return Err(Error::EncodingError(format!("Invalid envelope type {:#x}.", kind)))
+        }
+        Ok(Self { kind: Some(kind), payload })
+    }
+}
+
+impl<T: Serialize> TypedEnvelope<T> {
+    /// `kind || rlp(payload)`, or just `rlp(payload)` for legacy values.
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let payload = to_bytes(&self.payload)?;
+        Ok(match self.kind {
+            Some(kind) => [&[kind][..], &payload].concat(),
+            None => payload
+        })
+    }
+}
+
+impl<T: DeserializeOwned> TypedEnvelope<T> {
+    /// The inverse of [`TypedEnvelope::encode`], telling typed values apart 
+    /// from legacy ones by their first byte.
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        match bytes.first() {
+            Some(&kind) if kind <= MAX_ENVELOPE_TYPE => {
+                Ok(Self { kind: Some(kind), payload: from_bytes(&bytes[1..])? })
+            },
+            Some(_) => Ok(Self { kind: None, payload: from_bytes(bytes)? }),
+            None => Err(Error::EncodingError("Empty envelope.".into()))
+        }
+    }
+}
+
+impl<Db, K, V> Trie<Db, K, V>
+where
+    Db: Database,
+    K: Serialize,
+    V: Serialize + DeserializeOwned
+{
+    /// Insert an enveloped value, stored as [`TypedEnvelope::encode`] instead 
+    /// of `rlp(value)`, which is what the receipt and transaction tries of 
+    /// go-ethereum hold.
+    pub fn insert_enveloped(self, key: &K, value: &TypedEnvelope<V>) -> Result<Self> {
+        self.insert_raw(&to_bytes(key)?, value.encode()?)
+    }
+
+    /// Get a value inserted with [`Trie::insert_enveloped`].
+    pub fn get_enveloped(&self, key: &K) -> Result<Option<TypedEnvelope<V>>> {
+        match self.get_raw(&to_bytes(key)?)? {
+            Some(bytes) => Ok(Some(TypedEnvelope::decode(&bytes)?)),
+            None => Ok(None)
+        }
+    }
+}
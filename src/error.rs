@@ -1,27 +1,51 @@
-use std::fmt::Display;
+use core::fmt::Display;
+
+use alloc::{string::{String, ToString}, vec::Vec, sync::Arc, format};
 
 use serlp;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum TrieError {
-    SubtreeNotFound
+    SubtreeNotFound,
+    /// the operation needs a committed trie, but there are uncommitted changes
+    Uncommitted,
+    /// a node violating the trie invariants, e.g. one loaded from a corrupt database
+    InvalidNodeShape(String),
+    /// a key was not greater than the previous one fed to a [`StackTrie`](crate::stack_trie::StackTrie)
+    UnorderedKey,
+    /// a node does not hash to the reference pointing to it, 
+    /// holding the bytes of the reference
+    HashMismatch(Vec<u8>),
+    /// a key longer than the limit of the trie, in nibbles
+    KeyTooLong(usize),
+    /// a version not greater than the last one committed, 
+    /// see [`Trie::commit_versioned`](crate::mpt::Trie::commit_versioned)
+    StaleVersion(u64),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Error {
     EncodingError(String),
     DatabaseError(String),
-    StateNotFound,
+    /// the requested root is not in the database, holding the bytes of its hash
+    StateNotFound(Vec<u8>),
     TrieError(TrieError),
+    /// stored value bytes that do not decode as the value type of the trie, 
+    /// e.g. read with another type than the one written. 
+    /// `key_hint` holds the hex encoded key bytes.
+    ValueDecodeError { key_hint: String, cause: String },
+    /// an error raised by a [`Database`](crate::mpt::Database) backend, 
+    /// keeping its original type. Shared, so that errors can be cloned.
+    Backend(Arc<dyn core::error::Error + Send + Sync>),
 }
 
 impl Display for Error {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "{}", format!("{:?}", self))
     }
 }
 
-pub type Result<T> = std::result::Result<T, Error>;
+pub type Result<T> = core::result::Result<T, Error>;
 
 impl From<serlp::error::Error> for Error {
     fn from(ser: serlp::error::Error) -> Self {
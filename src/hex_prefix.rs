@@ -1,18 +1,56 @@
-//! This is an implementation of hex-prefix encoding
+//! This is an implementation of hex-prefix encoding, the compact form of the 
+//! nibble paths stored in leaf and extension nodes (Appendix C of the yellow paper). 
+//! 
+//! The first nibble of the encoding holds the leaf flag and the parity of the 
+//! path, the path itself follows, padded with a zero nibble if its length is even.
+//! 
+//! ```
+//! use mpt_rs::hex_prefix::{hex_prefix_encode, hex_prefix_decode};
+//! 
+//! // the leaf flag set, odd length: the first nibble is 3
+//! let encoded = hex_prefix_encode(&[1, 2, 3], true);
+//! assert_eq!(encoded, vec![0x31, 0x23]);
+//! assert_eq!(hex_prefix_decode(&encoded).unwrap(), (vec![1, 2, 3], true));
+//! 
+//! // an extension path of even length is padded
+//! assert_eq!(hex_prefix_encode(&[0xa, 0xb], false), vec![0x00, 0xab]);
+//! assert!(hex_prefix_decode(&[]).is_err());
+//! ```
+
+use alloc::{vec::Vec, format};
+
+use crate::error::{Error, Result};
 
 /// This type represents a nibble list, in which each element represents a single nibble
 pub(crate) type Nibbles = Vec<u8>;
-/// This type represents a hex-prefix encoded nibble list, 
-/// in which two nibbles are compressed into one bytes
-pub(crate) type HPNibbles = Vec<u8>;
 
 const ODD_MASK: u8 = 0b00010000;
 pub(crate) const FLAG_MASK: u8 = 0b00100000;
 
+/// The kind of node a hex-prefix encoded path belongs to, 
+/// stored in the flag bit of the encoding.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum HpKind {
+    Leaf,
+    Extension,
+}
+
+impl HpKind {
+    pub fn to_flag(self) -> bool {
+        self == HpKind::Leaf
+    }
+
+    pub fn from_flag(flag: bool) -> Self {
+        if flag { HpKind::Leaf } else { HpKind::Extension }
+    }
+}
+
 /// This function encodes an array of nibbles together with a boolean flag into a byte array
-/// Each element of src should all be nibbles. 
-/// Passing slice with element with non-zero high 4-bit will lead to undefined behavior
-pub fn hex_prefix_encode<'a>(src: &'a [u8], flag: bool) -> HPNibbles {
+/// Each element of src should all be nibbles. This is not checked and never panics: 
+/// the high 4 bits of an element that is not a nibble are either dropped or 
+/// ORed into the preceding nibble or the prefix, so the result does not 
+/// decode back to src.
+pub fn hex_prefix_encode<'a>(src: &'a [u8], flag: bool) -> Vec<u8> {
     let encode_nibbles = |x: &'a [u8]| x.chunks(2).map(|two| (two[0] << 4) | two[1]);
     let mut res = Vec::new();
     // the length is odd
@@ -27,9 +65,14 @@ pub fn hex_prefix_encode<'a>(src: &'a [u8], flag: bool) -> HPNibbles {
     res
 }
 
-pub fn hex_prefix_decode(src: &[u8]) -> (Nibbles, bool) {
+/// The inverse of [`hex_prefix_encode`], returning the nibbles and the flag.
+/// Fails on an empty slice or an unknown prefix.
+pub fn hex_prefix_decode(src: &[u8]) -> Result<(Vec<u8>, bool)> {
     if src.is_empty() {
-        panic!("Empty slice met when hex-prefix decoding.");
+        return Err(Error::EncodingError("Empty slice met when hex-prefix decoding.".into()))
+    }
+    if src[0] >> 4 > 3 {
+        return Err(Error::EncodingError(format!("Invalid hex-prefix flag {:#x}.", src[0] >> 4)))
     }
 
     let mut nibbles: Nibbles = Vec::new();
@@ -41,7 +84,7 @@ pub fn hex_prefix_decode(src: &[u8]) -> (Nibbles, bool) {
     }
     nibbles.extend(encoded.iter().map(|i| [(i & 0xf0) >> 4, i & 0x0f]).flatten());
 
-    (nibbles, prefix[0] & FLAG_MASK != 0)
+    Ok((nibbles, prefix[0] & FLAG_MASK != 0))
 }
 
 pub fn common_prefix<'a, 'b>(a: &'a [u8], b: &'b [u8]) -> (&'a [u8], &'a [u8], &'b [u8]) {
@@ -63,9 +106,41 @@ pub fn common_prefix<'a, 'b>(a: &'a [u8], b: &'b [u8]) -> (&'a [u8], &'a [u8], &
     return split(min)
 }
 
-pub fn bytes_to_nibbles(src: &[u8]) -> Nibbles {
+/// Split every byte into two nibbles, high nibble first. An empty slice gives
+/// an empty path. 
+/// 
+/// This never fails, the output is always twice as long as the input: the 
+/// length of keys is checked against [`TrieBuilder::max_key_nibbles`] on 
+/// the nibbles produced here.
+/// 
+/// [`TrieBuilder::max_key_nibbles`]: crate::mpt::TrieBuilder::max_key_nibbles
+pub fn bytes_to_nibbles(src: &[u8]) -> Vec<u8> {
     src.iter().map(|x| {
         [(x & 0xf0) >> 4, x & 0x0f]
     }).flatten().collect()
 }
 
+/// The inverse of [`bytes_to_nibbles`], fails if the number of nibbles is odd
+pub fn nibbles_to_bytes(src: &[u8]) -> Result<Vec<u8>> {
+    if src.len() & 1 == 1 {
+        return Err(Error::EncodingError("Odd number of nibbles.".into()))
+    }
+    Ok(src.chunks(2).map(|two| (two[0] << 4) | two[1]).collect())
+}
+
+#[cfg(test)]
+mod test_hex_prefix {
+    use super::{bytes_to_nibbles, nibbles_to_bytes};
+
+    #[test]
+    fn test_bytes_to_nibbles() {
+        assert_eq!(bytes_to_nibbles(&[]), Vec::<u8>::new());
+        assert_eq!(bytes_to_nibbles(&[0xab]), vec![0xa, 0xb]);
+        assert_eq!(bytes_to_nibbles(&[0x0f, 0xf0, 0x00, 0x12]), vec![0, 0xf, 0xf, 0, 0, 0, 1, 2]);
+
+        for bytes in [&[][..], &[0xab], &[0x0f, 0xf0, 0x00, 0x12]] {
+            assert_eq!(nibbles_to_bytes(&bytes_to_nibbles(bytes)).unwrap(), bytes);
+        }
+        assert!(nibbles_to_bytes(&[0xa]).is_err());
+    }
+}
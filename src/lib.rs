@@ -1,5 +1,27 @@
+//! Without the default `std` feature only the proof verification path is 
+//! built, on `core` and `alloc`: [`proof`], the node and hex-prefix decoding, 
+//! [`keccak256`] and the [`Database`](mpt::Database) trait with 
+//! [`ProofDb`](db::ProofDb). The trie and the other backends need `std`.
+#![cfg_attr(not(feature = "std"), no_std)]
+// the node walkers only used by the trie are unused without it
+#![cfg_attr(not(feature = "std"), allow(dead_code, unused_imports))]
+
+extern crate alloc;
+
 pub mod mpt;
-mod hex_prefix;
+pub mod hex_prefix;
 pub mod error;
 mod node;
 pub mod proof;
+pub mod db;
+#[cfg(feature = "std")]
+pub mod read_only;
+#[cfg(feature = "std")]
+pub mod secure;
+#[cfg(feature = "std")]
+pub mod stack_trie;
+pub mod nibbles;
+#[cfg(feature = "std")]
+pub mod envelope;
+
+pub use mpt::keccak256;
@@ -1,43 +1,358 @@
 //! A Merkle Patricia Tree maps a 256-bit length data structure into arbitary binary data.
 //! This is an implementation of what is described in ETH Yellow Paper.
 
-use std::{marker::PhantomData, mem};
+use core::{marker::PhantomData, mem, fmt};
+#[cfg(feature = "std")]
+use std::{collections::{HashMap, HashSet}, io::{Write, BufRead}};
 
-use serde::{Serialize, de::DeserializeOwned};
-use serlp::rlp::{to_bytes, from_bytes};
+use alloc::{
+    vec::Vec, string::ToString, collections::{BTreeMap, VecDeque}, borrow::Cow, sync::Arc, format
+};
+
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+use serlp::{rlp::{to_bytes, from_bytes}, types::byte_array};
 use sha3::{Keccak256, Digest};
 
 use crate::{
-    hex_prefix::{bytes_to_nibbles, common_prefix},
-    node::{MptNode, LeafNode, Subtree, BranchNode, ExtensionNode}, error::Error,
+    hex_prefix::{bytes_to_nibbles, nibbles_to_bytes, common_prefix},
+    node::{MptNode, LeafNode, Subtree, BranchNode, ExtensionNode, unshare, prepend_path}, error::Error,
+    proof::ProofMetrics,
+    nibbles::Nibbles,
     error::{Result, TrieError}
 };
+#[cfg(feature = "std")]
+use crate::{read_only::ReadOnlyTrie, db::{OverlayDb, NamespacedDb, NAMESPACED_KEY_LEN}};
 
 pub const KEY_LEN: usize = 32;
 
-pub type KecHash = [u8; KEY_LEN];
+/// Nodes whose encoding is shorter than this are embedded in their parent
+/// instead of being stored by hash.
+pub const INLINE_THRESHOLD: usize = 32;
+
+/// Default limit on the length of a key in nibbles, i.e. twice the length 
+/// of its encoding, see [`TrieBuilder::max_key_nibbles`].
+pub const MAX_KEY_NIBBLES: usize = 1 << 16;
+
+/// version tag written at the start of every blob produced by [`Trie::dump`]
+pub const SNAPSHOT_VERSION: u8 = 1;
+
+/// A node hash, [`KEY_LEN`] bytes long unless the trie is built over a 
+/// database keyed by longer hashes, see [`node_hash`].
+pub type KecHash<const N: usize = KEY_LEN> = [u8; N];
+
+/// The database key the index of versioned roots is stored under, 
+/// see [`Trie::commit_versioned`]. No node hashes to it.
+pub const ROOT_INDEX_KEY: KecHash = [0xff; KEY_LEN];
+
+/// [`ROOT_INDEX_KEY`] for hashes of `N` bytes
+#[cfg(feature = "std")]
+fn root_index_key<const N: usize>() -> KecHash<N> {
+    [0xff; N]
+}
+
+pub use crate::node::{NodeKind, TrieNode};
+
+/// Which value wins when both tries hold the same key, see [`Trie::merge`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MergePolicy {
+    KeepSelf,
+    TakeOther,
+}
+
+/// A node visited while looking up a key, see [`Trie::get_path`].
+#[derive(Clone, PartialEq, Eq, Debug, Serialize)]
+pub struct NodeSummary<const N: usize = KEY_LEN> {
+    pub kind: NodeKind,
+    /// nibbles of the key consumed by this node
+    pub consumed: Nibbles,
+    /// hash of the node if it is referenced by hash rather than embedded
+    #[serde(with = "serde_hash")]
+    pub hash: Option<KecHash<N>>,
+}
+
+/// Serialize hashes as hex strings, so that introspection structs read well 
+/// in logs.
+mod serde_hash {
+    use serde::Serializer;
+
+    use super::KecHash;
+
+    pub(super) fn serialize<S, const N: usize>(hash: &Option<KecHash<N>>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match hash {
+            Some(hash) => serializer.serialize_some(&hex::encode(hash)),
+            None => serializer.serialize_none()
+        }
+    }
+}
 
-pub(crate) fn keccak256(rlp: &[u8]) -> KecHash {
+/// Keccak-256 of arbitrary bytes, the hash used for node references. 
+/// Use it to derive keys (e.g. of accounts or storage slots) that agree with 
+/// the ones computed by Ethereum clients.
+pub fn keccak256(data: &[u8]) -> KecHash {
     let mut hasher = Keccak256::default();
-    hasher.update(rlp);
+    hasher.update(data);
     hasher.finalize().into()
 }
 
-pub trait Database 
+/// The hash of an encoded node for references of `N` bytes: [`keccak256`] at 
+/// the default [`KEY_LEN`], otherwise the first `N` bytes of SHAKE256. 
+/// `N` should not be shorter than [`INLINE_THRESHOLD`], or references could 
+/// not be told apart from embedded nodes.
+pub fn node_hash<const N: usize>(data: &[u8]) -> KecHash<N> {
+    let mut hash = [0; N];
+    if N == KEY_LEN {
+        hash.copy_from_slice(&keccak256(data));
+    } else {
+        use sha3::{Shake256, digest::{Update, ExtendableOutput, XofReader}};
+
+        let mut hasher = Shake256::default();
+        Update::update(&mut hasher, data);
+        XofReader::read(&mut hasher.finalize_xof(), &mut hash);
+    }
+    hash
+}
+
+/// A store of encoded nodes keyed by their hashes of `N` bytes.
+pub trait Database<const N: usize = KEY_LEN>
 where
     Self: Sized
 {
+    /// Error type of the backend, converted into the crate [`Error`] when 
+    /// returned from trie operations. Backends without their own error type 
+    /// can simply use the crate [`Error`]. Converting from the crate [`Error`] 
+    /// lets the default [`Database::clear`] and [`Database::retain`] report 
+    /// that they are unsupported.
+    type Error: Into<Error> + From<Error>;
+
     fn new() -> Self;
     /// insert a value
-    fn insert(&mut self, key: &KecHash, value: Vec<u8>) -> Result<()>;
-    fn exists(&mut self, key: &KecHash) -> Result<bool>;
-    fn get(&self, key: &KecHash) -> Result<Option<Vec<u8>>>;
+    fn insert(&mut self, key: &KecHash<N>, value: Vec<u8>) -> core::result::Result<(), Self::Error>;
+    fn exists(&mut self, key: &KecHash<N>) -> core::result::Result<bool, Self::Error>;
+    fn get(&self, key: &KecHash<N>) -> core::result::Result<Option<Vec<u8>>, Self::Error>;
+    /// Get a value, borrowing it from the backend where possible.
+    /// The default implementation falls back to [`Database::get`].
+    fn get_ref(&self, key: &KecHash<N>) -> core::result::Result<Option<Cow<'_, [u8]>>, Self::Error> {
+        Ok(self.get(key)?.map(Cow::Owned))
+    }
+    /// Hint that about `additional` more nodes are going to be inserted.
+    /// Does nothing by default.
+    fn reserve(&mut self, _additional: usize) {}
+    /// Make sure everything inserted so far is durable.
+    /// Does nothing by default, which suits in-memory backends.
+    fn flush(&mut self) -> core::result::Result<(), Self::Error> {
+        Ok(())
+    }
+    /// Remove every stored node. 
+    /// Backends that cannot be wiped fail with `Error::DatabaseError`, which is the default.
+    fn clear(&mut self) -> core::result::Result<(), Self::Error> {
+        Err(Error::DatabaseError("Clearing the database is not supported.".into()).into())
+    }
+    /// Remove every stored node whose key `keep` rejects, returning how many 
+    /// were removed. Backends that cannot enumerate their keys fail with 
+    /// `Error::DatabaseError`, which is the default.
+    fn retain(&mut self, _keep: impl Fn(&KecHash<N>) -> bool) -> core::result::Result<usize, Self::Error> {
+        Err(Error::DatabaseError("Removing nodes from the database is not supported.".into()).into())
+    }
+}
+
+/// convert a backend error into the crate error
+pub(crate) fn db_error<E: Into<Error>>(err: E) -> Error {
+    err.into()
+}
+
+/// decode the value stored under the key bytes, failing with `Error::ValueDecodeError`
+pub(crate) fn decode_value<V: DeserializeOwned>(key: &[u8], value: &[u8]) -> Result<V> {
+    from_bytes(value).map_err(|err| Error::ValueDecodeError {
+        key_hint: hex::encode(key),
+        cause: err.to_string()
+    })
+}
+
+/// load a node referenced by its hash from database
+pub(crate) fn load_node<Db, const N: usize>(db: &Db, key: &KecHash<N>) -> Result<MptNode<N>>
+where
+    Db: Database<N>
+{
+    let rlp = db.get_ref(key).map_err(db_error)?
+        .ok_or(Error::TrieError(TrieError::SubtreeNotFound))?;
+    MptNode::from_rlp(&rlp)
+}
+
+/// index of the branch child selected by `nibble`, rejecting values 
+/// outside of the 16 slots instead of panicking
+pub(crate) fn branch_index(nibble: u8) -> Result<usize> {
+    if nibble >= 16 {
+        return Err(Error::TrieError(TrieError::InvalidNodeShape(
+            format!("Nibble {} is out of the branch range.", nibble)
+        )))
+    }
+    Ok(nibble as usize)
+}
+
+/// Configures and creates a [`Trie`].
+#[cfg(feature = "std")]
+pub struct TrieBuilder<Db> {
+    db: Db,
+    inline_threshold: usize,
+    max_key_nibbles: usize,
+    store_full_keys: bool,
+}
+
+#[cfg(feature = "std")]
+impl<Db> TrieBuilder<Db> {
+    pub fn new(db: Db) -> Self {
+        Self {
+            db,
+            inline_threshold: INLINE_THRESHOLD,
+            max_key_nibbles: MAX_KEY_NIBBLES,
+            store_full_keys: false
+        }
+    }
+
+    /// Embed nodes shorter than `threshold` bytes instead of [`INLINE_THRESHOLD`], 
+    /// clamped to at most [`INLINE_THRESHOLD`]. 
+    /// Lowering it stores more nodes by hash, which is handy in tests, but the 
+    /// resulting root hashes are no longer the Ethereum ones.
+    pub fn inline_threshold(mut self, threshold: usize) -> Self {
+        self.inline_threshold = threshold.min(INLINE_THRESHOLD);
+        self
+    }
+
+    /// Reject keys longer than `limit` nibbles with `TrieError::KeyTooLong` 
+    /// instead of [`MAX_KEY_NIBBLES`], guarding against huge serialized keys.
+    pub fn max_key_nibbles(mut self, limit: usize) -> Self {
+        self.max_key_nibbles = limit;
+        self
+    }
+
+    /// Keep the key bytes of every written leaf in an in-memory side map, 
+    /// see [`Trie::full_keys`]. An audit aid, it does not change any hash and 
+    /// nothing of it is written to the database.
+    pub fn store_full_keys(mut self) -> Self {
+        self.store_full_keys = true;
+        self
+    }
+
+    /// Store the nodes in a [`NamespacedDb`] over the database, isolating them
+    /// from other tries sharing it. The database is keyed by 
+    /// [`NAMESPACED_KEY_LEN`] bytes. This does not change any hash.
+    pub fn namespace(self, namespace: [u8; 4]) -> TrieBuilder<NamespacedDb<Db>>
+    where
+        Db: Database<NAMESPACED_KEY_LEN>
+    {
+        TrieBuilder {
+            db: NamespacedDb::with_namespace(self.db, namespace),
+            inline_threshold: self.inline_threshold,
+            max_key_nibbles: self.max_key_nibbles,
+            store_full_keys: self.store_full_keys
+        }
+    }
+
+    /// Rebuild a trie with the options of this builder from a blob produced by 
+    /// [`Trie::dump`], writing all nodes into the database. Options are not 
+    /// part of the blob, so pass the ones the trie was built with: e.g. with 
+    /// another [`TrieBuilder::inline_threshold`], further changes would be 
+    /// hashed differently.
+    pub fn restore<K, V, const N: usize>(mut self, blob: &[u8]) -> Result<Trie<Db, K, V, N>>
+    where
+        Db: Database<N>,
+        K: Serialize,
+        V: Serialize + DeserializeOwned
+    {
+        let malformed = || Error::EncodingError("Malformed snapshot.".into());
+
+        let (header, mut body) = match blob {
+            [version, flag, rest @ ..] => ((*version, *flag), rest),
+            _ => return Err(malformed())
+        };
+        if header.0 != SNAPSHOT_VERSION {
+            return Err(Error::EncodingError(
+                format!("Unsupported snapshot version {}.", header.0)
+            ))
+        }
+
+        let root_hash = match header.1 {
+            0 => None,
+            1 if body.len() >= N => {
+                let (hash, rest) = body.split_at(N);
+                body = rest;
+                let mut root_hash = [0; N];
+                root_hash.copy_from_slice(hash);
+                Some(root_hash)
+            },
+            _ => return Err(malformed())
+        };
+
+        while !body.is_empty() {
+            if body.len() < 4 {
+                return Err(malformed())
+            }
+            let (len, rest) = body.split_at(4);
+            let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize;
+            if rest.len() < len {
+                return Err(malformed())
+            }
+            let (rlp, rest) = rest.split_at(len);
+            self.db.insert(&node_hash(rlp), rlp.to_vec()).map_err(db_error)?;
+            body = rest;
+        }
+
+        let trie = self.build();
+        match root_hash {
+            Some(root_hash) => trie.revert(root_hash),
+            None => Ok(trie)
+        }
+    }
+
+    /// Build a trie with the options of this builder from lines produced by 
+    /// [`Trie::export_kv`], and commit it. Empty lines are skipped.
+    pub fn import_kv<K, V, R, const N: usize>(self, r: R) -> Result<Trie<Db, K, V, N>>
+    where
+        Db: Database<N>,
+        K: Serialize,
+        V: Serialize + DeserializeOwned,
+        R: BufRead
+    {
+        let mut trie = self.build();
+        for (lineno, line) in r.lines().enumerate() {
+            let line = line.map_err(|err| Error::EncodingError(err.to_string()))?;
+            if line.is_empty() {
+                continue
+            }
+            let malformed = || Error::EncodingError(
+                format!("Malformed record at line {}.", lineno + 1)
+            );
+            let (key, value) = line.split_once('\t').ok_or_else(malformed)?;
+            let key = hex::decode(key).map_err(|_| malformed())?;
+            let value = hex::decode(value).map_err(|_| malformed())?;
+            trie = trie.insert_raw(&key, value)?;
+        }
+        trie.commit()?;
+        Ok(trie)
+    }
+
+    pub fn build<K, V, const N: usize>(self) -> Trie<Db, K, V, N>
+    where
+        Db: Database<N>,
+        K: Serialize,
+        V: Serialize + DeserializeOwned
+    {
+        let mut trie = Trie::with_options(self.db, self.inline_threshold, self.max_key_nibbles);
+        if self.store_full_keys {
+            trie.full_keys = Some(HashMap::new());
+        }
+        trie
+    }
 }
 
+#[cfg(feature = "std")]
 #[derive(Clone)]
-pub struct Trie<Db, K, V> 
+pub struct Trie<Db, K, V, const N: usize = KEY_LEN> 
 where
-    Db: Database,
+    Db: Database<N>,
     K: Serialize,
     V: Serialize + DeserializeOwned
 {
@@ -48,237 +363,2260 @@ where
     ///  1. Branch node cannot be empty because we only use them when nessessary
     ///  2. Extension node cannot be empty because there is no such j != 0
     ///  3. Leaf node cannot be empty because ||J|| == 0 != 1
-    root: Option<MptNode>,
+    root: Option<MptNode<N>>,
     pub db: Db,
     dirty: bool,
-    root_hash: Option<KecHash>,
+    /// nibble paths of the keys written since the last commit
+    dirty_keys: HashSet<Nibbles>,
+    root_hash: Option<KecHash<N>>,
+    inline_threshold: usize,
+    max_key_nibbles: usize,
+    /// key bytes by nibble path of the leaves written, if enabled
+    full_keys: Option<HashMap<Vec<u8>, Vec<u8>>>,
+    /// the error that stopped the last [`Extend::extend`]
+    pending_error: Option<Error>,
+    /// committed roots by version, once loaded from the database
+    root_index: Option<BTreeMap<u64, KecHash<N>>>,
     _k: PhantomData<K>,
     _v: PhantomData<V>
 }
 
-impl<Db, K, V> Trie<Db, K, V>
+/// Loading pairs through the standard trait, with [`Trie::insert_mut`].
+/// As `extend` cannot fail, the first error stops the loading and is kept 
+/// until [`Trie::take_error`]; meanwhile further loads are ignored and 
+/// every [`Trie::commit`] fails with it, so a partially loaded trie is 
+/// never committed by mistake.
+#[cfg(feature = "std")]
+impl<Db, K, V, const N: usize> Extend<(K, V)> for Trie<Db, K, V, N>
+where
+    Db: Database<N>,
+    K: Serialize,
+    V: Serialize + DeserializeOwned
+{
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        if self.pending_error.is_some() {
+            return
+        }
+        for (key, value) in iter {
+            if let Err(err) = self.insert_mut(&key, &value) {
+                self.pending_error = Some(err);
+                return
+            }
+        }
+    }
+}
+
+/// A summary of the trie, nothing is loaded from the database.
+#[cfg(feature = "std")]
+impl<Db, K, V, const N: usize> fmt::Debug for Trie<Db, K, V, N>
 where
-    Db: Database,
+    Db: Database<N>,
+    K: Serialize,
+    V: Serialize + DeserializeOwned
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Trie")
+            .field("empty", &self.root.is_none())
+            .field("dirty", &self.dirty)
+            .field("root_hash", &self.root_hash.map(hex::encode))
+            .field("root", &self.root.as_ref().map(MptNode::kind))
+            .finish()
+    }
+}
+
+#[cfg(feature = "std")]
+impl<Db, K, V, const N: usize> Trie<Db, K, V, N>
+where
+    Db: Database<N>,
     K: Serialize,
     V: Serialize + DeserializeOwned
 {
     pub fn new(db: Db) -> Self {
+        TrieBuilder::new(db).build()
+    }
+
+    fn with_options(db: Db, inline_threshold: usize, max_key_nibbles: usize) -> Self {
         Self {
             root: None,
             db,
             dirty: false,
+            dirty_keys: HashSet::new(),
             root_hash: None,
+            inline_threshold,
+            max_key_nibbles,
+            full_keys: None,
+            pending_error: None,
+            root_index: None,
             _k: PhantomData::default(),
             _v: PhantomData::default()
         }
     }
 
-    pub fn revert(mut self, root_hash: KecHash) -> Result<Self> {
-        if let Some(rlp) = self.db.get(&root_hash)? {
-            self.root = Some(MptNode::from_rlp(&rlp)?);
-            self.dirty = false;
-            Ok(self)
-        } else {
-            Err(Error::StateNotFound)
-        }
+    pub fn revert(mut self, root_hash: KecHash<N>) -> Result<Self> {
+        let root = match self.db.get_ref(&root_hash).map_err(db_error)? {
+            Some(rlp) => MptNode::from_rlp(&rlp)?,
+            None => return Err(Error::StateNotFound(root_hash.to_vec()))
+        };
+        self.root = Some(root);
+        self.root_hash = Some(root_hash);
+        self.dirty = false;
+        self.dirty_keys.clear();
+        Ok(self)
     }
 
-    pub fn insert(mut self, key: &K, value: &V) -> Result<Self> {
+    pub fn insert(self, key: &K, value: &V) -> Result<Self> {
         let ivalue = to_bytes(value)?;
         let rlp_key = to_bytes(key)?;
-        let ikey = bytes_to_nibbles(&rlp_key);
+        self.insert_raw(&rlp_key, ivalue)
+    }
+
+    /// Insert a value under the raw key bytes, bypassing the RLP encoding
+    /// the typed API applies to both keys and values.
+    pub fn insert_raw(mut self, key: &[u8], value: Vec<u8>) -> Result<Self> {
+        self.insert_nibbles(bytes_to_nibbles(key), value)?;
+        Ok(self)
+    }
+
+    /// Insert a value in place. 
+    /// If the database fails in the middle of the insertion, the in-memory 
+    /// state is lost; run it inside [`Trie::transaction`] to be able to recover.
+    pub fn insert_mut(&mut self, key: &K, value: &V) -> Result<()> {
+        let ivalue = to_bytes(value)?;
+        let rlp_key = to_bytes(key)?;
+        self.insert_nibbles(bytes_to_nibbles(&rlp_key), ivalue)
+    }
+
+    /// Insert the value returned by `f`, which is only called, and its result 
+    /// encoded, once the position of the key has been reached. 
+    /// An error from `f` is returned as is.
+    pub fn insert_with<F: FnOnce() -> Result<V>>(mut self, key: &K, f: F) -> Result<Self> {
+        let rlp_key = to_bytes(key)?;
+        self.upsert_nibbles(bytes_to_nibbles(&rlp_key), |_| Ok(Some(to_bytes(&f()?)?)))?;
+        Ok(self)
+    }
+
+    /// Take the error that stopped the last [`Extend::extend`] on this trie, 
+    /// which lets loading and committing resume. 
+    /// The pairs before the failing one are inserted, the ones after it are not.
+    pub fn take_error(&mut self) -> Option<Error> {
+        self.pending_error.take()
+    }
+
+    /// Insert a value only if the key is not present yet, in a single descent.
+    /// Returns whether the value was inserted.
+    pub fn insert_if_absent(mut self, key: &K, value: &V) -> Result<(Self, bool)> {
+        let ivalue = to_bytes(value)?;
+        let rlp_key = to_bytes(key)?;
+        let mut inserted = false;
+        self.upsert_nibbles(bytes_to_nibbles(&rlp_key), |old| {
+            Ok(if old.is_none() {
+                inserted = true;
+                Some(ivalue)
+            } else {
+                None
+            })
+        })?;
+        Ok((self, inserted))
+    }
+
+    /// Replace the raw value under `key` with `f(current value)`, in a single 
+    /// descent. `f` receives `None` if the key is absent.
+    pub fn update_raw<F: FnOnce(Option<&[u8]>) -> Vec<u8>>(mut self, key: &[u8], f: F) -> Result<Self> {
+        self.upsert_nibbles(bytes_to_nibbles(key), |old| Ok(Some(f(old))))?;
+        Ok(self)
+    }
+
+    fn check_key_len(&self, ikey: &[u8]) -> Result<()> {
+        if ikey.len() > self.max_key_nibbles {
+            return Err(Error::TrieError(TrieError::KeyTooLong(ikey.len())))
+        }
+        Ok(())
+    }
+
+    /// Re-inserting the stored value writes nothing and leaves a clean trie clean.
+    fn insert_nibbles(&mut self, ikey: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.upsert_nibbles(ikey, |old| Ok(if old == Some(value.as_slice()) { None } else { Some(value) }))
+    }
+
+    fn upsert_nibbles<F>(&mut self, ikey: Vec<u8>, update: F) -> Result<()>
+    where
+        F: FnOnce(Option<&[u8]>) -> Result<Option<Vec<u8>>>
+    {
+        self.check_key_len(&ikey)?;
+
+        // the comparison with the stored value happens in the same descent, 
+        // and leaves the trie untouched when nothing is written
+        let (root, written) = match self.root.take() {
+            Some(root) => {
+                let (root, written) = node_upsert(root, &mut self.db, &ikey, update)?;
+                (Some(root), written)
+            },
+            None => match update(None)? {
+                Some(value) => (Some(LeafNode { remained: ikey.clone(), value }.into()), true),
+                None => (None, false)
+            }
+        };
+        self.root = root;
+        if written {
+            self.dirty = true;
+            // the committed hash no longer describes the state
+            self.root_hash = None;
+            if let Some(full_keys) = &mut self.full_keys {
+                full_keys.insert(ikey.clone(), nibbles_to_bytes(&ikey)?);
+            }
+            self.dirty_keys.insert(Nibbles(ikey));
+        }
+
+        Ok(())
+    }
+
+    pub fn db(&self) -> &Db {
+        &self.db
+    }
+
+    /// The key bytes recorded for the leaf at each nibble path, or `None` 
+    /// unless enabled with [`TrieBuilder::store_full_keys`]. Only keys written
+    /// through this handle are recorded, not those of a state reverted to.
+    pub fn full_keys(&self) -> Option<&HashMap<Vec<u8>, Vec<u8>>> {
+        self.full_keys.as_ref()
+    }
+
+    pub fn db_mut(&mut self) -> &mut Db {
+        &mut self.db
+    }
+
+    /// Commit pending changes and give back the database together with the 
+    /// root hash, which reopens the state with [`Trie::revert`].
+    pub fn into_db(mut self) -> Result<(Db, Option<KecHash<N>>)> {
+        let root_hash = self.commit()?;
+        Ok((self.db, root_hash))
+    }
+
+    /// Flush the database, see [`Database::flush`]. 
+    /// Call it after [`Trie::commit`] to make the committed state durable.
+    pub fn flush(&mut self) -> Result<()> {
+        self.db.flush().map_err(db_error)
+    }
+
+    /// Drop the trie and wipe its whole database, see [`Database::clear`].
+    /// Any other state sharing the database is lost as well.
+    pub fn destroy(mut self) -> Result<()> {
+        self.root = None;
+        self.db.clear().map_err(db_error)
+    }
+
+    /// A new trie over a clone of the database, starting from the committed 
+    /// root reloaded from it, so no in-memory node is copied. Changes to the 
+    /// fork do not affect this trie. Use a `Db` whose clones share the storage 
+    /// (e.g. behind an `Arc`) to make forking cheap.
+    /// The trie must be committed, otherwise `TrieError::Uncommitted` is returned.
+    pub fn fork(&self) -> Result<Self>
+    where
+        Db: Clone
+    {
+        if self.dirty {
+            return Err(Error::TrieError(TrieError::Uncommitted))
+        }
+        let trie = Self::with_options(self.db.clone(), self.inline_threshold, self.max_key_nibbles);
+        match self.root_hash {
+            Some(root_hash) => trie.revert(root_hash),
+            None => Ok(trie)
+        }
+    }
+
+    /// Move the trie onto an [`OverlayDb`] over its database, so that further 
+    /// commits are buffered in memory. Accept the changes with 
+    /// [`Trie::commit_overlay`] or drop them with [`Trie::discard_overlay`].
+    pub fn checkpoint_db(self) -> Trie<OverlayDb<Db>, K, V, N>
+    where
+        OverlayDb<Db>: Database<N>
+    {
+        self.replace_db(OverlayDb::with_base)
+    }
+
+    fn replace_db<Db2: Database<N>>(self, f: impl FnOnce(Db) -> Db2) -> Trie<Db2, K, V, N> {
+        Trie {
+            root: self.root,
+            db: f(self.db),
+            dirty: self.dirty,
+            dirty_keys: self.dirty_keys,
+            root_hash: self.root_hash,
+            inline_threshold: self.inline_threshold,
+            max_key_nibbles: self.max_key_nibbles,
+            full_keys: self.full_keys,
+            pending_error: self.pending_error,
+            root_index: self.root_index,
+            _k: PhantomData::default(),
+            _v: PhantomData::default()
+        }
+    }
+
+    /// Let the database prepare for about `nodes` more nodes before a bulk 
+    /// load, see [`Database::reserve`].
+    pub fn capacity_hint(&mut self, nodes: usize) {
+        self.db.reserve(nodes)
+    }
+
+    /// Insert many `(key bytes, value bytes)` pairs with [`Trie::insert_raw`].
+    /// Feeding keys in sorted order keeps consecutive inserts on neighbouring 
+    /// paths, which is the fastest way to load a snapshot.
+    pub fn extend_raw<I>(mut self, items: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>
+    {
+        for (key, value) in items {
+            self = self.insert_raw(&key, value)?;
+        }
+        Ok(self)
+    }
+
+    /// Replace every stored value with `f(value)` under the same key, 
+    /// e.g. to migrate the value encoding. This changes the root hash.
+    pub fn map_values<F>(mut self, mut f: F) -> Result<Self>
+    where
+        F: FnMut(&[u8]) -> Result<Vec<u8>>
+    {
+        for (key, value) in self.entries_raw()? {
+            let value = f(&value)?;
+            self = self.insert_raw(&key, value)?;
+        }
+        Ok(self)
+    }
+
+    /// Insert every entry of `other` into this trie, resolving keys present
+    /// in both according to `policy`. Values are copied as raw bytes.
+    pub fn merge(mut self, other: &Trie<Db, K, V, N>, policy: MergePolicy) -> Result<Self> {
+        if let Some(root) = &other.root {
+            node_entries(root, &other.db, &mut Vec::new(), &mut |ikey, value| {
+                if policy == MergePolicy::KeepSelf {
+                    if let Some(root) = &self.root {
+                        if node_get(root, &self.db, ikey)?.is_some() {
+                            return Ok(())
+                        }
+                    }
+                }
+                self.insert_nibbles(ikey.to_vec(), value.to_vec())
+            })?;
+        }
+        Ok(self)
+    }
+
+    /// Remove a key, returning whether it was present.
+    pub fn remove(self, key: &K) -> Result<(Self, bool)> {
+        let rlp_key = to_bytes(key)?;
+        self.remove_raw(&rlp_key)
+    }
+
+    /// Remove the value stored under the raw key bytes, returning whether it
+    /// was present. Removing a missing key leaves the trie untouched, 
+    /// in particular it is not marked dirty.
+    pub fn remove_raw(mut self, key: &[u8]) -> Result<(Self, bool)> {
+        let ikey = bytes_to_nibbles(key);
+        let root = match self.root.take() {
+            Some(root) => root,
+            None => return Ok((self, false))
+        };
+        // check first, removing would load the nodes on the path into memory
+        if node_get(&root, &self.db, &ikey)?.is_none() {
+            self.root = Some(root);
+            return Ok((self, false))
+        }
 
-        let root = mem::replace(&mut self.root, None);
-        self.root = Some(match root {
-            Some(root) => node_insert(root, &mut self.db, &ikey, ivalue)?,
-            None => LeafNode {
-                    remained: ikey,
-                    value: ivalue
-                }.into()
-        });
-        // inserted value, not the trie is dirty 
+        self.root = node_remove(root, &mut self.db, &ikey)?;
         self.dirty = true;
+        self.root_hash = None;
+        if let Some(full_keys) = &mut self.full_keys {
+            full_keys.remove(&ikey);
+        }
+        self.dirty_keys.insert(Nibbles(ikey));
+        Ok((self, true))
+    }
+
+    /// Remove many keys before the next commit, skipping the ones not present.
+    /// All of them are removed in a single descent, and each node left behind 
+    /// is merged once, after the keys below it are gone. The resulting trie 
+    /// is the same as after removing the keys one by one, in any order.
+    pub fn bulk_remove<I: IntoIterator<Item = K>>(mut self, keys: I) -> Result<Self> {
+        let root = match self.root.take() {
+            Some(root) => root,
+            None => return Ok(self)
+        };
+        let mut ikeys = Vec::new();
+        for key in keys {
+            let ikey = bytes_to_nibbles(&to_bytes(&key)?);
+            if node_get(&root, &self.db, &ikey)?.is_some() {
+                ikeys.push(ikey);
+            }
+        }
+        if ikeys.is_empty() {
+            self.root = Some(root);
+            return Ok(self)
+        }
+        ikeys.sort_unstable();
+        ikeys.dedup();
 
+        let targets: Vec<&[u8]> = ikeys.iter().map(|ikey| ikey.as_slice()).collect();
+        self.root = node_remove_batch(root, &mut self.db, &targets)?;
+        self.dirty = true;
+        self.root_hash = None;
+        for ikey in ikeys {
+            if let Some(full_keys) = &mut self.full_keys {
+                full_keys.remove(&ikey);
+            }
+            self.dirty_keys.insert(Nibbles(ikey));
+        }
         Ok(self)
     }
 
     pub fn get(&self, key: &K) -> Result<Option<V>> {
         let rlp_key = to_bytes(key)?;
-        let ikey = bytes_to_nibbles(&rlp_key);
+        Ok(if let Some(value) = self.get_raw(&rlp_key)? {
+            Some(decode_value(&rlp_key, &value)?)
+        } else {
+            None
+        })
+    }
+
+    /// Same as [`Trie::get`], with `V::default()` standing in for absent keys.
+    pub fn get_or_default(&self, key: &K) -> Result<V>
+    where
+        V: Default
+    {
+        Ok(self.get(key)?.unwrap_or_default())
+    }
+
+    pub fn contains_key(&self, key: &K) -> Result<bool> {
+        let rlp_key = to_bytes(key)?;
+        Ok(self.get_raw(&rlp_key)?.is_some())
+    }
+
+    /// Get the value bytes stored under the raw key bytes.
+    pub fn get_raw(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let ikey = bytes_to_nibbles(key);
+        self.check_key_len(&ikey)?;
 
         Ok(if let Some(root) = &self.root {
-            if let Some(value) = node_get(root, &self.db, &ikey)? {
-                Some(from_bytes(&value)?)
-            } else {
-                None
-            }
+            node_get(root, &self.db, &ikey)?
         } else {
             None
         })
     }
 
-    pub fn root_hash(&self) -> Option<KecHash> {
+    /// Hash of the last committed or loaded root. It is reset to `None` as soon 
+    /// as the trie is modified, see [`Trie::current_root_hash`] for the hash 
+    /// of uncommitted state.
+    pub fn root_hash(&self) -> Option<KecHash<N>> {
         self.root_hash
     }
 
-    pub fn commit(&mut self) -> Result<Option<KecHash>> {
+    /// Whether there are changes not committed yet.
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// Nibble paths of the keys written since the last commit, in arbitrary order.
+    pub fn dirty_keys(&self) -> impl Iterator<Item = &Nibbles> {
+        self.dirty_keys.iter()
+    }
+
+    /// Write the modified nodes to the database and return the new root hash.
+    /// Fails with the error kept by a failed [`Extend::extend`], if any, 
+    /// until it is taken with [`Trie::take_error`].
+    pub fn commit(&mut self) -> Result<Option<KecHash<N>>> {
+        if let Some(err) = self.pending_error.as_ref() {
+            return Err(err.clone())
+        }
         if !self.dirty {
             return Ok(self.root_hash)
         }
 
-        let root = mem::replace(&mut self.root, None);
-        self.root = if let Some(root) = root {
-            match node_collapse(root, &mut self.db)? {
-                Subtree::Node(node) => {
-                    let (dbkey, rlp) = node.encode()?;
-                    self.db.insert(&dbkey, rlp)?;
-                    self.root_hash = Some(dbkey);
-                    Some(*node)
-                },
-                Subtree::NodeKey(dbkey) => {
-                    let node = MptNode::from_rlp(
-                        &self.db.get(&dbkey)?
-                            .ok_or(Error::TrieError(TrieError::SubtreeNotFound))?
-                    )?;
-                    self.root_hash = Some(dbkey);
-                    Some(node)
-                },
-                _ => unreachable!()
-            }
+        self.root = if let Some(root) = &self.root {
+            let db = &mut self.db;
+            let (dbkey, root) = root_collapse(root, self.inline_threshold, &mut |dbkey, rlp| {
+                db.insert(&dbkey, rlp).map_err(db_error)
+            })?;
+            self.root_hash = Some(dbkey);
+            Some(root)
         } else {
             self.root_hash = None;
             None
         };
 
         self.dirty = false;
+        self.dirty_keys.clear();
+        Ok(self.root_hash)
+    }
+
+    /// The read-only half of [`Trie::commit`]: collapse the in-memory root and 
+    /// collect the nodes to write, without touching the database. 
+    /// With the trie behind a lock, this can run under a read lock while 
+    /// [`Trie::apply_commit`] only needs the write lock for the writes.
+    pub fn prepare_commit(&self) -> Result<PendingCommit<N>> {
+        let root = match &self.root {
+            Some(root) if self.dirty => root,
+            _ => return Ok(PendingCommit {
+                writes: Vec::new(), root: self.root.clone(), root_hash: self.root_hash
+            })
+        };
+
+        let mut writes = Vec::new();
+        let (root_hash, root) = root_collapse(root, self.inline_threshold, &mut |dbkey, rlp| {
+            writes.push((dbkey, rlp));
+            Ok(())
+        })?;
+        Ok(PendingCommit { writes, root: Some(root), root_hash: Some(root_hash) })
+    }
+
+    /// Write the nodes of a [`PendingCommit`] and mark the trie as committed.
+    /// It must come from [`Trie::prepare_commit`] on this trie, with no changes
+    /// made in between, otherwise those changes are lost.
+    pub fn apply_commit(&mut self, pending: PendingCommit<N>) -> Result<Option<KecHash<N>>> {
+        self.db.reserve(pending.writes.len());
+        for (dbkey, rlp) in pending.writes {
+            self.db.insert(&dbkey, rlp).map_err(db_error)?;
+        }
+        self.root = pending.root;
+        self.root_hash = pending.root_hash;
+        self.dirty = false;
+        self.dirty_keys.clear();
         Ok(self.root_hash)
     }
 
-    pub fn get_proof<ProofDb: Database>(&mut self, key: &K) -> Result<(ProofDb, bool)> {
-        if self.dirty {
-            self.commit()?;
+    /// Open the trie at the latest version committed with [`Trie::commit_versioned`],
+    /// loading the index of versioned roots from `db`. The trie is empty if 
+    /// no version was committed.
+    pub fn open_versioned(db: Db) -> Result<Self> {
+        let mut trie = Self::new(db);
+        let index = load_root_index(&trie.db)?;
+        if let Some(root_hash) = index.values().next_back() {
+            trie = trie.revert(*root_hash)?;
+        }
+        trie.root_index = Some(index);
+        Ok(trie)
+    }
+
+    /// Same as [`Trie::commit`], also recording the root under `version`, 
+    /// which must be greater than the last version recorded, otherwise 
+    /// `TrieError::StaleVersion` is returned. The index of versioned roots is 
+    /// rewritten to the database under [`ROOT_INDEX_KEY`] so that it survives 
+    /// reopening, see [`Trie::open_versioned`] and [`Trie::root_at`].
+    /// An empty trie has no root, so its version is not recorded.
+    pub fn commit_versioned(&mut self, version: u64) -> Result<Option<KecHash<N>>> {
+        let mut index = match self.root_index.take() {
+            Some(index) => index,
+            None => load_root_index(&self.db)?
+        };
+        let result = self.commit_into_index(&mut index, version);
+        self.root_index = Some(index);
+        result
+    }
+
+    fn commit_into_index(&mut self, index: &mut BTreeMap<u64, KecHash<N>>, version: u64) -> Result<Option<KecHash<N>>> {
+        if matches!(index.keys().next_back(), Some(last) if version <= *last) {
+            return Err(Error::TrieError(TrieError::StaleVersion(version)))
+        }
+        let root_hash = self.commit()?;
+        if let Some(root_hash) = root_hash {
+            index.insert(version, root_hash);
+            let entries: Vec<_> = index.iter()
+                .map(|(version, root)| VersionedRoot { version: *version, root: *root })
+                .collect();
+            self.db.insert(&root_index_key(), to_bytes(&entries)?).map_err(db_error)?;
+        }
+        Ok(root_hash)
+    }
+
+    /// The root committed under `version` with [`Trie::commit_versioned`], 
+    /// to [`Trie::revert`] to. `None` until the index is loaded by one of them.
+    pub fn root_at(&self, version: u64) -> Option<KecHash<N>> {
+        self.root_index.as_ref()?.get(&version).copied()
+    }
+
+    /// Commit, then remove every node not reachable from the current root from 
+    /// the database, returning how many were removed. Only the current state 
+    /// is kept: older roots can no longer be reverted to, and tries sharing 
+    /// the database lose their nodes. The index of versioned roots is kept.
+    /// Requires [`Database::retain`].
+    pub fn gc_to_current(&mut self) -> Result<usize> {
+        self.commit()?;
+        let mut reachable = HashSet::new();
+        for node in self.iter_nodes()? {
+            reachable.insert(node?.0);
+        }
+        self.db.retain(|key| reachable.contains(key) || *key == root_index_key()).map_err(db_error)
+    }
+
+    /// Same as [`Trie::commit`], also returning the `(hash, rlp)` pairs written
+    /// to the database, in write order. Shipping them to a replica's database 
+    /// lets it [`Trie::revert`] to the returned root.
+    pub fn commit_collecting_writes(&mut self) -> Result<(Option<KecHash<N>>, Vec<(KecHash<N>, Vec<u8>)>)> {
+        let pending = self.prepare_commit()?;
+        let writes = pending.writes.clone();
+        let root_hash = self.apply_commit(pending)?;
+        Ok((root_hash, writes))
+    }
+
+    /// Freeze a committed trie into a [`ReadOnlyTrie`], 
+    /// fails with `TrieError::Uncommitted` if there are pending changes.
+    pub fn into_read_only(self) -> Result<ReadOnlyTrie<Db, K, V, N>> {
+        if self.dirty {
+            return Err(Error::TrieError(TrieError::Uncommitted))
+        }
+        Ok(ReadOnlyTrie::new(self))
+    }
+
+    /// The root hash the trie would have after [`Trie::commit`], computed 
+    /// without writing anything to the database, see [`Trie::incremental_root`].
+    pub fn compute_root(&self) -> Result<Option<KecHash<N>>> {
+        self.incremental_root()
+    }
+
+    /// The root hash the trie would have after [`Trie::commit`], re-hashing 
+    /// only what changed. Subtrees left untouched since they were loaded or 
+    /// committed are still referenced by hash, so only the nodes on modified 
+    /// paths (and nodes embedded in them) are encoded and hashed again.
+    /// Useful to check the new root while most of the state is unchanged.
+    pub fn incremental_root(&self) -> Result<Option<KecHash<N>>> {
+        if !self.dirty {
+            return Ok(self.root_hash)
+        }
+        self.hash_root()
+    }
+
+    /// The hash of the current state, whether committed or not. 
+    /// Unlike [`Trie::root_hash`] it never lags behind uncommitted changes,
+    /// and nothing is written or cached. `None` for an empty trie.
+    pub fn current_root_hash(&self) -> Result<Option<KecHash<N>>> {
+        match (&self.root, self.root_hash) {
+            (None, _) => Ok(None),
+            (Some(_), Some(root_hash)) if !self.dirty => Ok(Some(root_hash)),
+            (Some(_), _) => self.hash_root()
+        }
+    }
+
+    /// Recompute the hash of the current root and store it as [`Trie::root_hash`],
+    /// without writing to the database or clearing the dirty flag.
+    /// Useful when the root was set up by other means than [`Trie::commit`].
+    pub fn rebuild_hash(&mut self) -> Result<Option<KecHash<N>>> {
+        self.root_hash = self.hash_root()?;
+        Ok(self.root_hash)
+    }
+
+    /// Check that the in-memory root of a clean trie hashes to [`Trie::root_hash`],
+    /// a debugging aid to catch the two drifting apart. Fails with 
+    /// `TrieError::HashMismatch` carrying the recorded hash (or the computed 
+    /// one if none is recorded). Dirty tries are not checked.
+    pub fn assert_committed_consistency(&self) -> Result<()> {
+        if self.dirty {
+            return Ok(())
+        }
+        match (self.root_hash, self.hash_root()?) {
+            (recorded, computed) if recorded == computed => Ok(()),
+            (Some(hash), _) | (None, Some(hash)) => {
+                Err(Error::TrieError(TrieError::HashMismatch(hash.to_vec())))
+            },
+            (None, None) => unreachable!()
+        }
+    }
+
+    fn hash_root(&self) -> Result<Option<KecHash<N>>> {
+        Ok(match &self.root {
+            Some(root) => Some(root_collapse(root, self.inline_threshold, &mut |_, _| Ok(()))?.0),
+            None => None
+        })
+    }
+
+    /// Build a proof against the current in-memory state, see [`Trie::compute_root`].
+    /// Unlike [`Trie::get_proof`] nothing is committed, the hashes of modified 
+    /// nodes are computed on the fly.
+    pub fn prove_in_memory<ProofDb: Database<N>>(&self, key: &K) -> Result<(ProofDb, bool)> {
+        let mut proof = ProofDb::new();
+        let root = match &self.root {
+            Some(root) => root,
+            None => return Ok((proof, false))
+        };
+
+        // nodes changed since the last commit, keyed by their new hashes
+        let mut pending = HashMap::new();
+        let (_, root) = root_collapse(root, self.inline_threshold, &mut |dbkey, rlp| {
+            pending.insert(dbkey, rlp);
+            Ok(())
+        })?;
+
+        let load = |dbkey: &KecHash<N>| -> Result<MptNode<N>> {
+            match pending.get(dbkey) {
+                Some(rlp) => MptNode::from_rlp(rlp),
+                None => load_node(&self.db, dbkey)
+            }
+        };
+        let ikey = bytes_to_nibbles(&to_bytes(key)?);
+        let exists = node_proof(&root, &load, &ikey, &mut |hash, rlp| {
+            proof.insert(&hash, rlp).map_err(db_error)
+        })?;
+        Ok((proof, exists))
+    }
+
+    /// Apply the changes made by `f` atomically.
+    /// 
+    /// If `f` or the following commit fails, the in-memory trie is rolled back
+    /// to its state before the call. Nodes already written to the database by 
+    /// a partial commit stay there, but nothing references them.
+    pub fn transaction<F>(&mut self, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut Self) -> Result<()>
+    {
+        let root = self.root.clone();
+        let dirty_keys = self.dirty_keys.clone();
+        let (root_hash, dirty) = (self.root_hash, self.dirty);
+
+        let result = f(self).and_then(|_| self.commit().map(|_| ()));
+        if result.is_err() {
+            self.root = root;
+            self.root_hash = root_hash;
+            self.dirty = dirty;
+            self.dirty_keys = dirty_keys;
+        }
+        result
+    }
+
+    pub fn get_proof<ProofDb: Database<N>>(&mut self, key: &K) -> Result<(ProofDb, bool)> {
+        self.get_proof_with_metrics(key, None)
+    }
+
+    /// Same as [`Trie::get_proof`], additionally recording the number of nodes 
+    /// and the byte size of the generated proof into `metrics`.
+    pub fn get_proof_with_metrics<ProofDb: Database<N>>(
+        &mut self, key: &K, metrics: Option<&mut ProofMetrics>
+    ) -> Result<(ProofDb, bool)> {
+        if self.dirty {
+            self.commit()?;
+        }
+        self.proof_of(&to_bytes(key)?, metrics, None)
+    }
+
+    /// The byte size [`Trie::get_proof_with_metrics`] would record for the 
+    /// proof of `key`, computed by walking the path without collecting the 
+    /// nodes, so oversized proof requests can be rejected up front.
+    /// Uncommitted changes are collapsed into a throwaway buffer first, as 
+    /// [`Trie::prove_in_memory`] does, so they are accounted for as if they 
+    /// were committed.
+    pub fn estimate_proof_size(&self, key: &K) -> Result<usize> {
+        let root = match &self.root {
+            Some(root) => root,
+            None => return Ok(0)
+        };
+
+        // nodes changed since the last commit, keyed by their new hashes
+        let mut pending = HashMap::new();
+        let collapsed;
+        let root = if self.dirty {
+            collapsed = root_collapse(root, self.inline_threshold, &mut |dbkey, rlp| {
+                pending.insert(dbkey, rlp);
+                Ok(())
+            })?.1;
+            &collapsed
+        } else {
+            root
+        };
+
+        let load = |dbkey: &KecHash<N>| -> Result<MptNode<N>> {
+            match pending.get(dbkey) {
+                Some(rlp) => MptNode::from_rlp(rlp),
+                None => load_node(&self.db, dbkey)
+            }
+        };
+        let ikey = bytes_to_nibbles(&to_bytes(key)?);
+        let mut bytes = 0;
+        node_proof(root, &load, &ikey, &mut |_, rlp| {
+            bytes += rlp.len();
+            Ok(())
+        })?;
+        Ok(bytes)
+    }
+
+    /// Same as [`Trie::get_proof`], for raw key bytes.
+    pub fn get_proof_raw<ProofDb: Database<N>>(&mut self, key: &[u8]) -> Result<(ProofDb, bool)> {
+        if self.dirty {
+            self.commit()?;
+        }
+        self.proof_of(key, None, None)
+    }
+
+    /// Same as [`Trie::get_proof`], but takes the trie by reference and fails 
+    /// with `TrieError::Uncommitted` instead of committing pending changes.
+    pub fn get_proof_no_commit<ProofDb: Database<N>>(&self, key: &K) -> Result<(ProofDb, bool)> {
+        if self.dirty {
+            return Err(Error::TrieError(TrieError::Uncommitted))
+        }
+        self.proof_of(&to_bytes(key)?, None, None)
+    }
+
+    /// Same as [`Trie::get_proof_no_commit`], for serving proofs from several 
+    /// threads at once through a shared reference to a committed trie.
+    /// Fails with `TrieError::Uncommitted` if there are pending changes.
+    pub fn prove_concurrent<ProofDb: Database<N>>(&self, key: &K) -> Result<(ProofDb, bool)>
+    where
+        Db: Sync
+    {
+        self.get_proof_no_commit(key)
+    }
+
+    /// Up to `limit` entries with keys at or after `origin`, in key order, 
+    /// together with a proof of the paths to `origin` and to the last entry, 
+    /// as served to snap sync clients. Check them with 
+    /// [`verify_range_proof`](crate::proof::verify_range_proof).
+    /// Only the nodes on the way from `origin` to the last entry are loaded.
+    pub fn serve_range<ProofDb: Database<N>>(
+        &mut self, origin: &K, limit: usize
+    ) -> Result<(Vec<(Vec<u8>, Vec<u8>)>, ProofDb)> {
+        if self.dirty {
+            self.commit()?;
+        }
+        let origin = to_bytes(origin)?;
+        let mut entries = Vec::new();
+        if let Some(root) = self.root.as_ref().filter(|_| limit > 0) {
+            let from = bytes_to_nibbles(&origin);
+            node_entries_from(root, &self.db, Some(&from), &mut Vec::new(), &mut |ikey, value| {
+                entries.push((self.entry_key(ikey)?, value.to_vec()));
+                Ok(entries.len() < limit)
+            })?;
+        }
+
+        let mut proof = ProofDb::new();
+        if let Some(root) = &self.root {
+            let load = |dbkey: &KecHash<N>| load_node(&self.db, dbkey);
+            let mut sink = |hash: KecHash<N>, rlp: Vec<u8>| proof.insert(&hash, rlp).map_err(db_error);
+            node_proof(root, &load, &bytes_to_nibbles(&origin), &mut sink)?;
+            if let Some((last, _)) = entries.last() {
+                node_proof(root, &load, &bytes_to_nibbles(last), &mut sink)?;
+            }
+        }
+        Ok((entries, proof))
+    }
+
+    /// Same as [`Trie::get_proof`], but also returns the proof as a list of 
+    /// encoded nodes ordered from the root down, as sent over the wire.
+    /// Both forms are collected in a single traversal.
+    pub fn get_proof_both<ProofDb: Database<N>>(&mut self, key: &K) -> Result<(ProofDb, Vec<Vec<u8>>, bool)> {
+        if self.dirty {
+            self.commit()?;
+        }
+        let mut ordered = Vec::new();
+        let (proof, exists) = self.proof_of(&to_bytes(key)?, None, Some(&mut ordered))?;
+        Ok((proof, ordered, exists))
+    }
+
+    /// A separate proof for each of `keys`, as a list of encoded nodes ordered 
+    /// from the root down, and whether the key exists. Pending changes are 
+    /// committed once. Unlike a multiproof, nodes shared by several keys are 
+    /// repeated, so each proof can be forwarded and verified on its own.
+    pub fn get_proofs(&mut self, keys: &[K]) -> Result<Vec<(K, Vec<Vec<u8>>, bool)>>
+    where
+        K: Clone
+    {
+        if self.dirty {
+            self.commit()?;
+        }
+        keys.iter().map(|key| {
+            let mut ordered = Vec::new();
+            let exists = match &self.root {
+                Some(root) => {
+                    let ikey = bytes_to_nibbles(&to_bytes(key)?);
+                    node_proof(root, &|dbkey| load_node(&self.db, dbkey), &ikey, &mut |_, rlp| {
+                        ordered.push(rlp);
+                        Ok(())
+                    })?
+                },
+                None => false
+            };
+            Ok((key.clone(), ordered, exists))
+        }).collect()
+    }
+
+    fn proof_of<ProofDb: Database<N>>(
+        &self, key: &[u8], metrics: Option<&mut ProofMetrics>, mut ordered: Option<&mut Vec<Vec<u8>>>
+    ) -> Result<(ProofDb, bool)> {
+        let mut proof = ProofDb::new();
+        let (mut nodes, mut bytes) = (0, 0);
+
+        let ikey = bytes_to_nibbles(key);
+
+        let exists = if let Some(root) = &self.root {
+            node_proof(root, &|dbkey| load_node(&self.db, dbkey), &ikey, &mut |hash, rlp| {
+                nodes += 1;
+                bytes += rlp.len();
+                if let Some(ordered) = ordered.as_mut() {
+                    ordered.push(rlp.clone());
+                }
+                proof.insert(&hash, rlp).map_err(db_error)
+            })?
+        } else {
+            false
+        };
+
+        if let Some(metrics) = metrics {
+            metrics.record(nodes, bytes);
+        }
+        Ok((proof, exists))
+    }
+
+    /// Summarize every node visited when looking up `key`, from the root to 
+    /// the node where the lookup terminates. This is mainly a debugging aid.
+    pub fn get_path(&self, key: &K) -> Result<Vec<NodeSummary<N>>> {
+        let rlp_key = to_bytes(key)?;
+        let ikey = bytes_to_nibbles(&rlp_key);
+
+        let mut path = Vec::new();
+        if let Some(root) = &self.root {
+            // the hash of an uncommitted root is not known yet
+            let hash = if self.dirty { None } else { self.root_hash };
+            node_path(root, hash, &self.db, &ikey, &mut path)?;
+        }
+        Ok(path)
+    }
+
+    /// The smallest key in the trie. Keys are ordered by their nibble paths,
+    /// which is the lexicographic order of the stored (RLP encoded) key bytes.
+    pub fn first_key(&self) -> Result<Option<Vec<u8>>> {
+        self.extreme_key(false)
+    }
+
+    /// The largest key in the trie, see [`Trie::first_key`].
+    pub fn last_key(&self) -> Result<Option<Vec<u8>>> {
+        self.extreme_key(true)
+    }
+
+    fn extreme_key(&self, last: bool) -> Result<Option<Vec<u8>>> {
+        Ok(if let Some(root) = &self.root {
+            let mut path = Vec::new();
+            node_extreme(root, &self.db, last, &mut path)?;
+            Some(nibbles_to_bytes(&path)?)
+        } else {
+            None
+        })
+    }
+
+    /// The greatest key less than or equal to `key`, in the order of 
+    /// [`Trie::first_key`].
+    pub fn floor_key(&self, key: &K) -> Result<Option<Vec<u8>>> {
+        self.bound_key(&to_bytes(key)?, true)
+    }
+
+    /// The least key greater than or equal to `key`, see [`Trie::floor_key`].
+    pub fn ceil_key(&self, key: &K) -> Result<Option<Vec<u8>>> {
+        self.bound_key(&to_bytes(key)?, false)
+    }
+
+    fn bound_key(&self, key: &[u8], floor: bool) -> Result<Option<Vec<u8>>> {
+        let ikey = bytes_to_nibbles(key);
+        if let Some(root) = &self.root {
+            let mut path = Vec::new();
+            if node_bound(root, &self.db, &ikey, floor, &mut path)? {
+                return Ok(Some(nibbles_to_bytes(&path)?))
+            }
+        }
+        Ok(None)
+    }
+
+    /// Sum of the byte lengths of all stored values, without decoding them.
+    /// For the typed API these are the RLP encoded values rather than the 
+    /// in-memory size of `V`.
+    pub fn total_value_bytes(&self) -> Result<u64> {
+        let mut total = 0;
+        if let Some(root) = &self.root {
+            node_walk(root, &self.db, &mut |node| {
+                let len = match node {
+                    MptNode::Leaf(LeafNode { value, .. }) => value.len(),
+                    MptNode::Branch(BranchNode { value, .. }) => value.len(),
+                    MptNode::Extension(_) => 0
+                };
+                total += len as u64;
+                Ok(())
+            })?;
+        }
+        Ok(total)
+    }
+
+    /// Count the keys starting with the raw key bytes `prefix` without 
+    /// reconstructing them. 
+    /// Note that typed keys are RLP encoded, and the encoding of a key is not 
+    /// a prefix of the encoding of a longer one, so `prefix` is matched against
+    /// the stored key bytes, as with [`Trie::insert_raw`].
+    pub fn count_prefix(&self, prefix: &[u8]) -> Result<usize> {
+        let mut count = 0;
+        if let Some(node) = self.find_prefix(prefix)? {
+            node_walk(&node, &self.db, &mut |node| {
+                match node {
+                    MptNode::Leaf(_) => count += 1,
+                    MptNode::Branch(BranchNode { value, .. }) if !value.is_empty() => count += 1,
+                    _ => ()
+                };
+                Ok(())
+            })?;
+        }
+        Ok(count)
+    }
+
+    /// Whether any key starts with the raw key bytes `prefix`, 
+    /// see [`Trie::count_prefix`].
+    pub fn contains_prefix(&self, prefix: &[u8]) -> Result<bool> {
+        // nodes are never empty, so finding one is enough
+        Ok(self.find_prefix(prefix)?.is_some())
+    }
+
+    /// Hash of the node rooting the keys below the raw key bytes `prefix`, 
+    /// the root hash of a trie holding the rest of those keys. `None` if no 
+    /// node starts exactly at `prefix`, e.g. when it ends inside the path 
+    /// of a leaf or an extension. Prefixes are raw bytes as in [`Trie::count_prefix`].
+    /// The trie must be committed, otherwise `TrieError::Uncommitted` is returned.
+    pub fn subtree_root(&self, prefix: &[u8]) -> Result<Option<KecHash<N>>> {
+        if self.dirty {
+            return Err(Error::TrieError(TrieError::Uncommitted))
+        }
+        Ok(match &self.root {
+            Some(root) => node_subtree_proof(
+                root, &|dbkey| load_node(&self.db, dbkey), &bytes_to_nibbles(prefix), &mut |_, _| Ok(())
+            )?,
+            None => None
+        })
+    }
+
+    /// The hash of [`Trie::subtree_root`] and a proof of it from the root, 
+    /// committing pending changes first. Proofs of the keys below `prefix` 
+    /// can then be checked against the subtree hash with the rest of the 
+    /// keys, see [`verify_subtree_proof`](crate::proof::verify_subtree_proof).
+    /// Fails with `TrieError::SubtreeNotFound` if there is no such subtree.
+    pub fn prove_subtree<ProofDb: Database<N>>(&mut self, prefix: &[u8]) -> Result<(KecHash<N>, ProofDb)> {
+        if self.dirty {
+            self.commit()?;
+        }
+        let mut proof = ProofDb::new();
+        let hash = match &self.root {
+            Some(root) => node_subtree_proof(
+                root, &|dbkey| load_node(&self.db, dbkey), &bytes_to_nibbles(prefix), 
+                &mut |hash, rlp| proof.insert(&hash, rlp).map_err(db_error)
+            )?,
+            None => None
+        };
+        match hash {
+            Some(hash) => Ok((hash, proof)),
+            None => Err(Error::TrieError(TrieError::SubtreeNotFound))
+        }
+    }
+
+    fn find_prefix(&self, prefix: &[u8]) -> Result<Option<Cow<'_, MptNode<N>>>> {
+        let iprefix = bytes_to_nibbles(prefix);
+        Ok(if let Some(root) = &self.root {
+            node_find_prefix(root, &self.db, &iprefix)?
+        } else {
+            None
+        })
+    }
+
+    /// Iterate over every node reachable from the root together with its hash,
+    /// loading hashed children from the database lazily. Nodes embedded in 
+    /// their parents are yielded as well, with the hash of their encoding.
+    /// The trie must be committed, otherwise `TrieError::Uncommitted` is returned.
+    pub fn iter_nodes(&self) -> Result<impl Iterator<Item = Result<(KecHash<N>, TrieNode<N>)>> + '_> {
+        if self.dirty {
+            return Err(Error::TrieError(TrieError::Uncommitted))
+        }
+        Ok(NodeIter {
+            db: &self.db,
+            stack: self.root.iter().cloned().map(Subtree::from).collect()
+        })
+    }
+
+    /// Like [`Trie::iter_nodes`], but in breadth-first order, yielding each node 
+    /// with its depth below the root, which is at depth 0.
+    /// The trie must be committed, otherwise `TrieError::Uncommitted` is returned.
+    pub fn iter_nodes_bfs(&self) -> Result<impl Iterator<Item = Result<(usize, KecHash<N>, TrieNode<N>)>> + '_> {
+        if self.dirty {
+            return Err(Error::TrieError(TrieError::Uncommitted))
+        }
+        Ok(NodeBfsIter {
+            db: &self.db,
+            queue: self.root.iter().cloned().map(|root| (0, Subtree::from(root))).collect()
+        })
+    }
+
+    /// Iterate lazily over all `(key bytes, value bytes)` pairs in the order 
+    /// of [`Trie::entries_raw`], loading every node from the database from 
+    /// the committed root hash on, so in-memory nodes are never touched.
+    /// Keys are rebuilt from their nibble paths.
+    /// The trie must be committed, otherwise `TrieError::Uncommitted` is returned.
+    pub fn iter_committed(&self) -> Result<impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + '_> {
+        if self.dirty {
+            return Err(Error::TrieError(TrieError::Uncommitted))
+        }
+        Ok(EntryIter {
+            db: &self.db,
+            stack: self.root_hash.iter().map(|hash| (Vec::new(), Subtree::NodeKey(*hash))).collect()
+        })
+    }
+
+    /// All `(key bytes, value bytes)` pairs in key order, as stored in the trie.
+    /// 
+    /// Key order is the lexicographic order of the key bytes: each byte is 
+    /// split high nibble first, so nibble order and byte order agree, and a 
+    /// key comes before every longer key it is a prefix of. Such a key ends at 
+    /// a branch node, whose value is yielded before any of its children.
+    /// 
+    /// Keys recorded with [`TrieBuilder::store_full_keys`] are returned as 
+    /// recorded, the others are rebuilt from their nibble paths.
+    pub fn entries_raw(&self) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut entries = Vec::new();
+        if let Some(root) = &self.root {
+            node_entries(root, &self.db, &mut Vec::new(), &mut |ikey, value| {
+                entries.push((self.entry_key(ikey)?, value.to_vec()));
+                Ok(())
+            })?;
+        }
+        Ok(entries)
+    }
+
+    /// the key bytes of an entry, as recorded if full keys are stored
+    fn entry_key(&self, ikey: &[u8]) -> Result<Vec<u8>> {
+        match self.full_keys.as_ref().and_then(|keys| keys.get(ikey)) {
+            Some(key) => Ok(key.clone()),
+            None => nibbles_to_bytes(ikey)
+        }
+    }
+
+    /// Like [`Trie::entries_raw`], but only the pairs whose raw value bytes 
+    /// satisfy `pred`. Values are not decoded.
+    pub fn values_matching<F: Fn(&[u8]) -> bool>(&self, pred: F) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut entries = Vec::new();
+        if let Some(root) = &self.root {
+            node_entries(root, &self.db, &mut Vec::new(), &mut |ikey, value| {
+                if pred(value) {
+                    entries.push((nibbles_to_bytes(ikey)?, value.to_vec()));
+                }
+                Ok(())
+            })?;
+        }
+        Ok(entries)
+    }
+
+    /// Serialize the committed state into a single portable blob.
+    /// 
+    /// The blob starts with [`SNAPSHOT_VERSION`] and a flag telling whether the
+    /// trie is empty, followed by the root hash and every reachable node, each
+    /// prefixed with its length as a big-endian u32. 
+    /// The trie must be committed, otherwise `TrieError::Uncommitted` is returned.
+    pub fn dump(&self) -> Result<Vec<u8>> {
+        if self.dirty {
+            return Err(Error::TrieError(TrieError::Uncommitted))
+        }
+
+        let mut blob = vec![SNAPSHOT_VERSION];
+        if let Some(root) = &self.root {
+            let (root_hash, rlp) = root.encode()?;
+            blob.push(1);
+            blob.extend_from_slice(&root_hash);
+            dump_record(&mut blob, &rlp);
+
+            let mut visited = HashSet::new();
+            visited.insert(root_hash);
+            node_dump(root, &self.db, &mut blob, &mut visited)?;
+        } else {
+            blob.push(0);
+        }
+        Ok(blob)
+    }
+
+    /// Total byte length of the encodings of all nodes reachable from the root,
+    /// an estimate of the storage footprint of the committed state.
+    /// Embedded nodes are counted as part of their parents, and nodes shared
+    /// by identical subtrees are counted once.
+    /// The trie must be committed, otherwise `TrieError::Uncommitted` is returned.
+    pub fn db_size_bytes(&self) -> Result<u64> {
+        if self.dirty {
+            return Err(Error::TrieError(TrieError::Uncommitted))
+        }
+        let root = match &self.root {
+            Some(root) => root,
+            None => return Ok(0)
+        };
+
+        let (root_hash, rlp) = root.encode()?;
+        let mut visited = HashSet::new();
+        visited.insert(root_hash);
+        Ok(rlp.len() as u64 + node_size(root, &self.db, &mut visited)?)
+    }
+
+    /// Number of nodes on the longest path from the root to a leaf, embedded 
+    /// nodes included, 0 for an empty trie. This bounds the nodes loaded by 
+    /// a lookup.
+    pub fn height(&self) -> Result<usize> {
+        match &self.root {
+            Some(root) => node_height(root, &self.db),
+            None => Ok(0)
+        }
+    }
+
+    /// Check that `other` holds every hashed node reachable from the root with 
+    /// the same bytes as this trie's database, e.g. after copying the state to 
+    /// another backend. Returns false at the first node missing or differing.
+    /// The trie must be committed, otherwise `TrieError::Uncommitted` is returned.
+    pub fn validate_against<Other: Database<N>>(&self, other: &Other) -> Result<bool> {
+        if self.dirty {
+            return Err(Error::TrieError(TrieError::Uncommitted))
+        }
+        let root = match &self.root {
+            Some(root) => root,
+            None => return Ok(true)
+        };
+
+        let (root_hash, rlp) = root.encode()?;
+        match other.get_ref(&root_hash).map_err(db_error)? {
+            Some(copy) if *copy == *rlp => (),
+            _ => return Ok(false)
+        }
+        let mut visited = HashSet::new();
+        visited.insert(root_hash);
+        node_matches(root, &self.db, other, &mut visited)
+    }
+
+    /// Rebuild a trie from a blob produced by [`Trie::dump`], writing all
+    /// nodes into `db`. The trie gets the default options, use 
+    /// [`TrieBuilder::restore`] for a trie dumped with other ones.
+    pub fn restore(db: Db, blob: &[u8]) -> Result<Self> {
+        TrieBuilder::new(db).restore(blob)
+    }
+
+    /// Write every entry as a `hex(key)\thex(value)\n` line, in key order.
+    /// Keys and values are the raw stored bytes, see [`Trie::entries_raw`].
+    pub fn export_kv<W: Write>(&self, w: &mut W) -> Result<()> {
+        for (key, value) in self.entries_raw()? {
+            writeln!(w, "{}\t{}", hex::encode(key), hex::encode(value))
+                .map_err(|err| Error::EncodingError(err.to_string()))?;
+        }
+        Ok(())
+    }
+
+    /// Build a trie in `db` from lines produced by [`Trie::export_kv`], and commit it.
+    /// The trie gets the default options, see [`TrieBuilder::import_kv`].
+    pub fn import_kv<R: BufRead>(db: Db, r: R) -> Result<Self> {
+        TrieBuilder::new(db).import_kv(r)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<Base, K, V> Trie<OverlayDb<Base>, K, V>
+where
+    Base: Database,
+    K: Serialize,
+    V: Serialize + DeserializeOwned
+{
+    /// Commit the trie and write every buffered node into the base database,
+    /// returning the trie over the base.
+    pub fn commit_overlay(mut self) -> Result<Trie<Base, K, V>> {
+        self.commit()?;
+        self.db.commit_overlay()?;
+        Ok(self.replace_db(OverlayDb::into_base))
+    }
+
+    /// Drop every change buffered since [`Trie::checkpoint_db`] and give back 
+    /// the untouched base database, to be reopened with [`Trie::revert`].
+    pub fn discard_overlay(self) -> Base {
+        self.db.into_base()
+    }
+}
+
+/// An entry of the index stored under [`ROOT_INDEX_KEY`]
+#[cfg(feature = "std")]
+#[derive(Serialize, Deserialize)]
+struct VersionedRoot<const N: usize> {
+    version: u64,
+    #[serde(with = "byte_array")]
+    root: KecHash<N>,
+}
+
+#[cfg(feature = "std")]
+fn load_root_index<Db: Database<N>, const N: usize>(db: &Db) -> Result<BTreeMap<u64, KecHash<N>>> {
+    Ok(match db.get_ref(&root_index_key()).map_err(db_error)? {
+        Some(rlp) => from_bytes::<Vec<VersionedRoot<N>>>(&rlp)?
+            .into_iter()
+            .map(|entry| (entry.version, entry.root))
+            .collect(),
+        None => BTreeMap::new()
+    })
+}
+
+/// Nodes collected by [`Trie::prepare_commit`], waiting to be written by 
+/// [`Trie::apply_commit`].
+#[cfg(feature = "std")]
+#[derive(Debug, Clone)]
+pub struct PendingCommit<const N: usize = KEY_LEN> {
+    writes: Vec<(KecHash<N>, Vec<u8>)>,
+    root: Option<MptNode<N>>,
+    root_hash: Option<KecHash<N>>,
+}
+
+#[cfg(feature = "std")]
+impl<const N: usize> PendingCommit<N> {
+    /// the root hash the trie will have once applied
+    pub fn root_hash(&self) -> Option<KecHash<N>> {
+        self.root_hash
+    }
+
+    /// number of nodes to write
+    pub fn len(&self) -> usize {
+        self.writes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+}
+
+/// depth-first iterator over the nodes of a committed trie, see [`Trie::iter_nodes`]
+#[cfg(feature = "std")]
+struct NodeIter<'a, Db, const N: usize> {
+    db: &'a Db,
+    stack: Vec<Subtree<N>>,
+}
+
+#[cfg(feature = "std")]
+impl<'a, Db, const N: usize> NodeIter<'a, Db, N>
+where
+    Db: Database<N>
+{
+    fn visit(&mut self, subtree: Subtree<N>) -> Result<Option<(KecHash<N>, TrieNode<N>)>> {
+        let node = match subtree {
+            Subtree::Empty => return Ok(None),
+            Subtree::Node(node) => unshare(node),
+            Subtree::NodeKey(dbkey) => load_node(self.db, &dbkey)?
+        };
+
+        match &node {
+            MptNode::Leaf(_) => (),
+            MptNode::Extension(ExtensionNode { subtree, .. }) => self.stack.push(subtree.clone()),
+            MptNode::Branch(BranchNode { branchs, .. }) => {
+                // reversed, so that children are visited in nibble order
+                self.stack.extend(branchs.iter().rev().cloned())
+            }
+        }
+        let (hash, _) = node.encode()?;
+        Ok(Some((hash, TrieNode(node))))
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a, Db, const N: usize> Iterator for NodeIter<'a, Db, N>
+where
+    Db: Database<N>
+{
+    type Item = Result<(KecHash<N>, TrieNode<N>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(subtree) = self.stack.pop() {
+            match self.visit(subtree) {
+                Ok(None) => continue,
+                Ok(Some(item)) => return Some(Ok(item)),
+                Err(err) => {
+                    // the traversal cannot continue without the missing node
+                    self.stack.clear();
+                    return Some(Err(err))
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(feature = "std")]
+struct EntryIter<'a, Db, const N: usize> {
+    db: &'a Db,
+    /// subtrees left to visit, with the nibble path leading to them
+    stack: Vec<(Vec<u8>, Subtree<N>)>,
+}
+
+#[cfg(feature = "std")]
+impl<'a, Db, const N: usize> EntryIter<'a, Db, N>
+where
+    Db: Database<N>
+{
+    fn visit(&mut self, mut path: Vec<u8>, subtree: Subtree<N>) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let node = match subtree {
+            Subtree::Empty => return Ok(None),
+            Subtree::Node(node) => unshare(node),
+            Subtree::NodeKey(dbkey) => load_node(self.db, &dbkey)?
+        };
+
+        match node {
+            MptNode::Leaf(LeafNode { remained, value }) => {
+                path.extend_from_slice(&remained);
+                Ok(Some((nibbles_to_bytes(&path)?, value)))
+            },
+            MptNode::Extension(ExtensionNode { shared, subtree }) => {
+                path.extend_from_slice(&shared);
+                self.stack.push((path, subtree));
+                Ok(None)
+            },
+            MptNode::Branch(BranchNode { branchs, value }) => {
+                // reversed, so that children are visited in nibble order
+                for (idx, branch) in branchs.iter().enumerate().rev() {
+                    if !matches!(branch, Subtree::Empty) {
+                        let mut child = path.clone();
+                        child.push(idx as u8);
+                        self.stack.push((child, branch.clone()));
+                    }
+                }
+                // the branch value comes before the keys of its children
+                Ok(if value.is_empty() { None } else { Some((nibbles_to_bytes(&path)?, value)) })
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a, Db, const N: usize> Iterator for EntryIter<'a, Db, N>
+where
+    Db: Database<N>
+{
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((path, subtree)) = self.stack.pop() {
+            match self.visit(path, subtree) {
+                Ok(None) => continue,
+                Ok(Some(item)) => return Some(Ok(item)),
+                Err(err) => {
+                    // the traversal cannot continue without the missing node
+                    self.stack.clear();
+                    return Some(Err(err))
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(feature = "std")]
+struct NodeBfsIter<'a, Db, const N: usize> {
+    db: &'a Db,
+    queue: VecDeque<(usize, Subtree<N>)>,
+}
+
+#[cfg(feature = "std")]
+impl<'a, Db, const N: usize> NodeBfsIter<'a, Db, N>
+where
+    Db: Database<N>
+{
+    fn visit(&mut self, depth: usize, subtree: Subtree<N>) -> Result<Option<(usize, KecHash<N>, TrieNode<N>)>> {
+        let node = match subtree {
+            Subtree::Empty => return Ok(None),
+            Subtree::Node(node) => unshare(node),
+            Subtree::NodeKey(dbkey) => load_node(self.db, &dbkey)?
+        };
+
+        match &node {
+            MptNode::Leaf(_) => (),
+            MptNode::Extension(ExtensionNode { subtree, .. }) => {
+                self.queue.push_back((depth + 1, subtree.clone()))
+            },
+            MptNode::Branch(BranchNode { branchs, .. }) => {
+                self.queue.extend(branchs.iter().cloned().map(|child| (depth + 1, child)))
+            }
+        }
+        let (hash, _) = node.encode()?;
+        Ok(Some((depth, hash, TrieNode(node))))
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a, Db, const N: usize> Iterator for NodeBfsIter<'a, Db, N>
+where
+    Db: Database<N>
+{
+    type Item = Result<(usize, KecHash<N>, TrieNode<N>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((depth, subtree)) = self.queue.pop_front() {
+            match self.visit(depth, subtree) {
+                Ok(None) => continue,
+                Ok(Some(item)) => return Some(Ok(item)),
+                Err(err) => {
+                    self.queue.clear();
+                    return Some(Err(err))
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(feature = "std")]
+impl<Db, K, V, const N: usize> Trie<Db, K, V, N>
+where
+    Db: Database<N>,
+    K: Serialize + DeserializeOwned,
+    V: Serialize + DeserializeOwned
+{
+    /// All entries in key order, with both keys and values decoded.
+    /// Keys are rebuilt from their paths and decoded from RLP, so this fails
+    /// for keys that do not round trip through RLP, e.g. ones inserted with 
+    /// [`Trie::insert_raw`] that are not canonical encodings.
+    pub fn entries_typed(&self) -> Result<Vec<(K, V)>> {
+        self.entries_raw()?
+            .into_iter()
+            .map(|(key, value)| Ok((from_bytes(&key)?, decode_value(&key, &value)?)))
+            .collect()
+    }
+}
+
+#[cfg(feature = "std")]
+fn dump_record(blob: &mut Vec<u8>, rlp: &[u8]) {
+    blob.extend_from_slice(&(rlp.len() as u32).to_be_bytes());
+    blob.extend_from_slice(rlp);
+}
+
+/// append every hashed node reachable from root to the snapshot blob
+#[cfg(feature = "std")]
+fn node_dump<Db, const N: usize>(
+    root: &MptNode<N>, db: &Db, blob: &mut Vec<u8>, visited: &mut HashSet<KecHash<N>>
+) -> Result<()>
+where
+    Db: Database<N>
+{
+    match root {
+        MptNode::Leaf(_) => Ok(()),
+        MptNode::Extension(ExtensionNode { subtree, .. }) => {
+            subtree_dump(subtree, db, blob, visited)
+        },
+        MptNode::Branch(BranchNode { branchs, .. }) => {
+            for branch in branchs {
+                subtree_dump(branch, db, blob, visited)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+fn subtree_dump<Db, const N: usize>(
+    subtree: &Subtree<N>, db: &Db, blob: &mut Vec<u8>, visited: &mut HashSet<KecHash<N>>
+) -> Result<()>
+where
+    Db: Database<N>
+{
+    match subtree {
+        Subtree::Empty => Ok(()),
+        Subtree::Node(node) => node_dump(node, db, blob, visited),
+        Subtree::NodeKey(dbkey) => {
+            // identical subtrees share the same database entry
+            if !visited.insert(*dbkey) {
+                return Ok(())
+            }
+            let rlp = db.get_ref(dbkey).map_err(db_error)?
+                .ok_or(Error::TrieError(TrieError::SubtreeNotFound))?;
+            dump_record(blob, &rlp);
+            let root = MptNode::from_rlp(&rlp)?;
+            node_dump(&root, db, blob, visited)
+        }
+    }
+}
+
+/// byte length of every hashed node reachable from root, see [`Trie::db_size_bytes`]
+#[cfg(feature = "std")]
+fn node_size<Db, const N: usize>(root: &MptNode<N>, db: &Db, visited: &mut HashSet<KecHash<N>>) -> Result<u64>
+where
+    Db: Database<N>
+{
+    match root {
+        MptNode::Leaf(_) => Ok(0),
+        MptNode::Extension(ExtensionNode { subtree, .. }) => {
+            subtree_size(subtree, db, visited)
+        },
+        MptNode::Branch(BranchNode { branchs, .. }) => {
+            let mut size = 0;
+            for branch in branchs {
+                size += subtree_size(branch, db, visited)?;
+            }
+            Ok(size)
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+fn subtree_size<Db, const N: usize>(subtree: &Subtree<N>, db: &Db, visited: &mut HashSet<KecHash<N>>) -> Result<u64>
+where
+    Db: Database<N>
+{
+    match subtree {
+        Subtree::Empty => Ok(0),
+        Subtree::Node(node) => node_size(node, db, visited),
+        Subtree::NodeKey(dbkey) => {
+            if !visited.insert(*dbkey) {
+                return Ok(0)
+            }
+            let rlp = db.get_ref(dbkey).map_err(db_error)?
+                .ok_or(Error::TrieError(TrieError::SubtreeNotFound))?;
+            let root = MptNode::from_rlp(&rlp)?;
+            Ok(rlp.len() as u64 + node_size(&root, db, visited)?)
+        }
+    }
+}
+
+/// number of nodes on the longest path from root to a leaf, see [`Trie::height`]
+fn node_height<Db, const N: usize>(root: &MptNode<N>, db: &Db) -> Result<usize>
+where
+    Db: Database<N>
+{
+    let below = match root {
+        MptNode::Leaf(_) => 0,
+        MptNode::Extension(ExtensionNode { subtree, .. }) => subtree_height(subtree, db)?,
+        MptNode::Branch(BranchNode { branchs, .. }) => {
+            let mut max = 0;
+            for branch in branchs {
+                max = max.max(subtree_height(branch, db)?);
+            }
+            max
+        }
+    };
+    Ok(below + 1)
+}
+
+fn subtree_height<Db, const N: usize>(subtree: &Subtree<N>, db: &Db) -> Result<usize>
+where
+    Db: Database<N>
+{
+    match subtree {
+        Subtree::Empty => Ok(0),
+        Subtree::Node(node) => node_height(node, db),
+        Subtree::NodeKey(dbkey) => node_height(&load_node(db, dbkey)?, db)
+    }
+}
+
+/// whether every hashed node reachable from root is stored in other 
+/// with the same bytes as in db, see [`Trie::validate_against`]
+#[cfg(feature = "std")]
+fn node_matches<Db, Other, const N: usize>(
+    root: &MptNode<N>, db: &Db, other: &Other, visited: &mut HashSet<KecHash<N>>
+) -> Result<bool>
+where
+    Db: Database<N>,
+    Other: Database<N>
+{
+    match root {
+        MptNode::Leaf(_) => Ok(true),
+        MptNode::Extension(ExtensionNode { subtree, .. }) => {
+            subtree_matches(subtree, db, other, visited)
+        },
+        MptNode::Branch(BranchNode { branchs, .. }) => {
+            for branch in branchs {
+                if !subtree_matches(branch, db, other, visited)? {
+                    return Ok(false)
+                }
+            }
+            Ok(true)
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+fn subtree_matches<Db, Other, const N: usize>(
+    subtree: &Subtree<N>, db: &Db, other: &Other, visited: &mut HashSet<KecHash<N>>
+) -> Result<bool>
+where
+    Db: Database<N>,
+    Other: Database<N>
+{
+    match subtree {
+        Subtree::Empty => Ok(true),
+        Subtree::Node(node) => node_matches(node, db, other, visited),
+        Subtree::NodeKey(dbkey) => {
+            if !visited.insert(*dbkey) {
+                return Ok(true)
+            }
+            let rlp = db.get_ref(dbkey).map_err(db_error)?
+                .ok_or(Error::TrieError(TrieError::SubtreeNotFound))?;
+            match other.get_ref(dbkey).map_err(db_error)? {
+                Some(copy) if *copy == *rlp => (),
+                _ => return Ok(false)
+            }
+            let root = MptNode::from_rlp(&rlp)?;
+            node_matches(&root, db, other, visited)
+        }
+    }
+}
+
+#[cfg(test)]
+thread_local! {
+    /// number of nodes encoded by [`node_collapse`] and [`root_collapse`] on this thread
+    static COLLAPSE_ENCODES: std::cell::Cell<usize> = std::cell::Cell::new(0);
+}
+
+/// collapse a node, children are collapsed first so that every node is 
+/// encoded only once.
+/// returns the collapsed node itself if its encoding is shorter than threshold, 
+/// otherwise it is fed to sink and its key is returned
+pub(crate) fn node_collapse<F, const N: usize>(root: &MptNode<N>, threshold: usize, sink: &mut F) -> Result<Subtree<N>>
+where
+    F: FnMut(KecHash<N>, Vec<u8>) -> Result<()>
+{
+    let node_collapsed = collapse_children(root, threshold, sink)?;
+    let rlp = to_bytes(&node_collapsed)?;
+    #[cfg(test)]
+    COLLAPSE_ENCODES.with(|count| count.set(count.get() + 1));
+
+    // this node is small enough to be embedded in its parent
+    if rlp.len() < threshold {
+        return Ok(node_collapsed.into())
+    }
+
+    let dbkey = node_hash(&rlp);
+    sink(dbkey, rlp)?;
+    Ok(Subtree::NodeKey(dbkey))
+}
+
+/// collapse a root node like [`node_collapse`], but feed it to sink however 
+/// small it is, since a root is always referenced by its hash. 
+/// returns the hash and the collapsed root
+pub(crate) fn root_collapse<F, const N: usize>(root: &MptNode<N>, threshold: usize, sink: &mut F) -> Result<(KecHash<N>, MptNode<N>)>
+where
+    F: FnMut(KecHash<N>, Vec<u8>) -> Result<()>
+{
+    let root_collapsed = collapse_children(root, threshold, sink)?;
+    let rlp = to_bytes(&root_collapsed)?;
+    #[cfg(test)]
+    COLLAPSE_ENCODES.with(|count| count.set(count.get() + 1));
+
+    let dbkey = node_hash(&rlp);
+    sink(dbkey, rlp)?;
+    Ok((dbkey, root_collapsed))
+}
+
+/// a copy of the node with all its children collapsed
+fn collapse_children<F, const N: usize>(root: &MptNode<N>, threshold: usize, sink: &mut F) -> Result<MptNode<N>>
+where
+    F: FnMut(KecHash<N>, Vec<u8>) -> Result<()>
+{
+    Ok(match root {
+        MptNode::Leaf(leaf) => leaf.clone().into(),
+        MptNode::Branch(BranchNode { branchs, value }) => {
+            let mut collapsed_node = BranchNode::new();
+            for (idx, branch) in branchs.iter().enumerate() {
+                collapsed_node.branch(idx, subtree_collapse(branch, threshold, sink)?);
+            }
+            collapsed_node.value = value.clone();
+            collapsed_node.into()
+        },
+        MptNode::Extension(ExtensionNode { shared, subtree }) => {
+            ExtensionNode {
+                shared: shared.clone(),
+                subtree: subtree_collapse(subtree, threshold, sink)?
+            }.into()
+        }
+    })
+}
+
+pub(crate) fn subtree_collapse<F, const N: usize>(subtree: &Subtree<N>, threshold: usize, sink: &mut F) -> Result<Subtree<N>>
+where 
+    F: FnMut(KecHash<N>, Vec<u8>) -> Result<()>
+{
+    match subtree {
+        Subtree::Node(root) => node_collapse(root, threshold, sink),
+        _ => Ok(subtree.clone())
+    }
+}
+
+/// collect the nodes on the path of a key, feeding each (hash, rlp) pair to sink.
+/// load resolves nodes referenced by hash
+pub(crate) fn node_proof<L, F, const N: usize>(
+    root: &MptNode<N>, load: &L, ikey: &[u8], sink: &mut F
+) -> Result<bool>
+where
+    L: Fn(&KecHash<N>) -> Result<MptNode<N>>,
+    F: FnMut(KecHash<N>, Vec<u8>) -> Result<()>
+{
+    let (hash, rlp) = root.encode()?;
+    sink(hash, rlp)?;
+    match root {
+        MptNode::Leaf(leaf) => Ok(leaf.remained == ikey),
+        MptNode::Extension(ExtensionNode { shared, subtree }) => {
+            match common_prefix(&shared, ikey) {
+                (_, [], key_remained) => {
+                    subtree_proof(subtree, load, key_remained, sink)
+                },
+                _ => Ok(false)
+            }
+        },
+        MptNode::Branch(branch) => {
+            if ikey.is_empty() {
+                Ok(!branch.value.is_empty())
+            } else {
+                let (prefix, key_remained) = ikey.split_at(1);
+                let idx = branch_index(prefix[0])?;
+                let subtree = &branch.branchs[idx];
+                subtree_proof(subtree, load, key_remained, sink)
+            }
+        },
+    }
+}
+
+/// descend along iprefix like [`node_proof`], feeding the nodes on the way to sink,
+/// and return the hash of the node starting exactly at iprefix, if any
+pub(crate) fn node_subtree_proof<L, F, const N: usize>(
+    root: &MptNode<N>, load: &L, iprefix: &[u8], sink: &mut F
+) -> Result<Option<KecHash<N>>>
+where
+    L: Fn(&KecHash<N>) -> Result<MptNode<N>>,
+    F: FnMut(KecHash<N>, Vec<u8>) -> Result<()>
+{
+    let (hash, rlp) = root.encode()?;
+    sink(hash, rlp)?;
+    if iprefix.is_empty() {
+        return Ok(Some(hash))
+    }
+    let (subtree, rest) = match root {
+        MptNode::Leaf(_) => return Ok(None),
+        MptNode::Extension(ExtensionNode { shared, subtree }) => {
+            match iprefix.strip_prefix(shared.as_slice()) {
+                Some(rest) => (subtree, rest),
+                None => return Ok(None)
+            }
+        },
+        MptNode::Branch(branch) => {
+            let idx = branch_index(iprefix[0])?;
+            (&branch.branchs[idx], &iprefix[1..])
+        }
+    };
+    match subtree {
+        Subtree::Empty => Ok(None),
+        Subtree::Node(node) => node_subtree_proof(node, load, rest, sink),
+        Subtree::NodeKey(dbkey) => node_subtree_proof(&load(dbkey)?, load, rest, sink)
+    }
+}
+
+fn subtree_proof<L, F, const N: usize>(subtree: &Subtree<N>, load: &L, ikey: &[u8], sink: &mut F) -> Result<bool>
+where
+    L: Fn(&KecHash<N>) -> Result<MptNode<N>>,
+    F: FnMut(KecHash<N>, Vec<u8>) -> Result<()>
+{
+    match subtree {
+        Subtree::Empty => Ok(false),
+        Subtree::Node(node) => node_proof(node, load, ikey, sink),
+        Subtree::NodeKey(dbkey) => {
+            let root = load(dbkey)?;
+            node_proof(&root, load, ikey, sink)
+        }
+    }
+}
+
+fn node_path<Db, const N: usize>(
+    root: &MptNode<N>, hash: Option<KecHash<N>>, db: &Db, ikey: &[u8], path: &mut Vec<NodeSummary<N>>
+) -> Result<()>
+where
+    Db: Database<N>
+{
+    let summary = |consumed: &[u8]| NodeSummary {
+        kind: root.kind(),
+        consumed: Nibbles(consumed.to_vec()),
+        hash
+    };
+
+    match root {
+        MptNode::Leaf(LeafNode { remained, .. }) => {
+            let (consumed, _, _) = common_prefix(&remained, ikey);
+            path.push(summary(consumed));
+            Ok(())
+        },
+        MptNode::Extension(ExtensionNode { shared, subtree }) => {
+            match common_prefix(&shared, ikey) {
+                (_, [], key_remained) => {
+                    path.push(summary(shared));
+                    subtree_path(subtree, db, key_remained, path)
+                },
+                (consumed, _, _) => {
+                    path.push(summary(consumed));
+                    Ok(())
+                }
+            }
+        },
+        MptNode::Branch(BranchNode { branchs, .. }) => {
+            if ikey.is_empty() {
+                path.push(summary(&[]));
+                Ok(())
+            } else {
+                let (prefix, key_remained) = ikey.split_at(1);
+                path.push(summary(prefix));
+                let idx = branch_index(prefix[0])?;
+                subtree_path(&branchs[idx], db, key_remained, path)
+            }
+        },
+    }
+}
+
+fn subtree_path<Db, const N: usize>(
+    subtree: &Subtree<N>, db: &Db, ikey: &[u8], path: &mut Vec<NodeSummary<N>>
+) -> Result<()>
+where
+    Db: Database<N>
+{
+    match subtree {
+        Subtree::Empty => Ok(()),
+        Subtree::Node(node) => node_path(node, None, db, ikey, path),
+        Subtree::NodeKey(dbkey) => {
+            let root = load_node(db, dbkey)?;
+            node_path(&root, Some(*dbkey), db, ikey, path)
+        }
+    }
+}
+
+/// visit every node reachable from root, loading hashed subtrees from database
+pub(crate) fn node_walk<Db, F, const N: usize>(root: &MptNode<N>, db: &Db, f: &mut F) -> Result<()>
+where
+    Db: Database<N>,
+    F: FnMut(&MptNode<N>) -> Result<()>
+{
+    f(root)?;
+    match root {
+        MptNode::Leaf(_) => Ok(()),
+        MptNode::Extension(ExtensionNode { subtree, .. }) => subtree_walk(subtree, db, f),
+        MptNode::Branch(BranchNode { branchs, .. }) => {
+            for branch in branchs {
+                subtree_walk(branch, db, f)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+fn subtree_walk<Db, F, const N: usize>(subtree: &Subtree<N>, db: &Db, f: &mut F) -> Result<()>
+where
+    Db: Database<N>,
+    F: FnMut(&MptNode<N>) -> Result<()>
+{
+    match subtree {
+        Subtree::Empty => Ok(()),
+        Subtree::Node(node) => node_walk(node, db, f),
+        Subtree::NodeKey(dbkey) => {
+            let root = load_node(db, dbkey)?;
+            node_walk(&root, db, f)
+        }
+    }
+}
+
+/// find the topmost node whose keys all start with the nibbles in prefix
+fn node_find_prefix<'a, Db, const N: usize>(
+    root: &'a MptNode<N>, db: &Db, prefix: &[u8]
+) -> Result<Option<Cow<'a, MptNode<N>>>>
+where
+    Db: Database<N>
+{
+    if prefix.is_empty() {
+        return Ok(Some(Cow::Borrowed(root)))
+    }
+
+    match root {
+        MptNode::Leaf(LeafNode { remained, .. }) => {
+            Ok(if remained.starts_with(prefix) { Some(Cow::Borrowed(root)) } else { None })
+        },
+        MptNode::Extension(ExtensionNode { shared, subtree }) => {
+            match common_prefix(&shared, prefix) {
+                (_, [], prefix_remained) => subtree_find_prefix(subtree, db, prefix_remained),
+                // prefix ends in the middle of shared nibbles, every key below matches
+                (_, _, []) => Ok(Some(Cow::Borrowed(root))),
+                _ => Ok(None)
+            }
+        },
+        MptNode::Branch(BranchNode { branchs, .. }) => {
+            let (idx, prefix_remained) = prefix.split_at(1);
+            subtree_find_prefix(&branchs[branch_index(idx[0])?], db, prefix_remained)
+        }
+    }
+}
+
+fn subtree_find_prefix<'a, Db, const N: usize>(
+    subtree: &'a Subtree<N>, db: &Db, prefix: &[u8]
+) -> Result<Option<Cow<'a, MptNode<N>>>>
+where
+    Db: Database<N>
+{
+    match subtree {
+        Subtree::Empty => Ok(None),
+        Subtree::Node(node) => node_find_prefix(node, db, prefix),
+        Subtree::NodeKey(dbkey) => {
+            let root = load_node(db, dbkey)?;
+            Ok(node_find_prefix(&root, db, prefix)?.map(|node| Cow::Owned(node.into_owned())))
+        }
+    }
+}
+
+/// feed every (key nibbles, value) pair below root to f, in key order.
+/// path holds the nibbles consumed before reaching root.
+fn node_entries<Db, F, const N: usize>(root: &MptNode<N>, db: &Db, path: &mut Vec<u8>, f: &mut F) -> Result<()>
+where
+    Db: Database<N>,
+    F: FnMut(&[u8], &[u8]) -> Result<()>
+{
+    let depth = path.len();
+    match root {
+        MptNode::Leaf(LeafNode { remained, value }) => {
+            path.extend_from_slice(remained);
+            f(path, value)?;
+        },
+        MptNode::Extension(ExtensionNode { shared, subtree }) => {
+            path.extend_from_slice(shared);
+            subtree_entries(subtree, db, path, f)?;
+        },
+        MptNode::Branch(BranchNode { branchs, value }) => {
+            // the branch value belongs to the key ending here, a strict prefix 
+            // of all keys in its children, so it sorts before them
+            if !value.is_empty() {
+                f(path, value)?;
+            }
+            for (idx, branch) in branchs.iter().enumerate() {
+                path.push(idx as u8);
+                subtree_entries(branch, db, path, f)?;
+                path.pop();
+            }
         }
-
-        let mut proof = ProofDb::new();
-
-        let rlp_key = to_bytes(key)?;
-        let ikey = bytes_to_nibbles(&rlp_key);
-
-        let exists = if let Some(root) = &self.root {
-            node_proof(root, &self.db, &mut proof, &ikey)?
-        } else {
-            false
-        };
-
-        Ok((proof, exists))
     }
+    path.truncate(depth);
+    Ok(())
 }
 
-/// collapse a node
-/// returns (collapsed node, collapsed node length)
-fn node_collapse<Db>(root: MptNode, db: &mut Db) -> Result<Subtree>
+fn subtree_entries<Db, F, const N: usize>(subtree: &Subtree<N>, db: &Db, path: &mut Vec<u8>, f: &mut F) -> Result<()>
 where
-    Db: Database
+    Db: Database<N>,
+    F: FnMut(&[u8], &[u8]) -> Result<()>
 {
-    let rlp = to_bytes(&root)?;
-
-    // this node do not need to be collapsed
-    if rlp.len() < 32 {
-        return Ok(Subtree::Node(Box::new(root)))
+    match subtree {
+        Subtree::Empty => Ok(()),
+        Subtree::Node(node) => node_entries(node, db, path, f),
+        Subtree::NodeKey(dbkey) => {
+            let root = load_node(db, dbkey)?;
+            node_entries(&root, db, path, f)
+        }
     }
+}
 
-    let node_collapsed = match root {
-        MptNode::Leaf(_) => root,
-        MptNode::Branch(BranchNode { branchs, value }) => {
-            let mut collapsed_node = BranchNode::new();
-            for (idx, branch) in branchs.into_iter().enumerate() {
-                collapsed_node.branch(idx, subtree_collapse(branch, db)?);
+/// feed the (key nibbles, value) pairs below root with keys not less than ikey
+/// to f in key order, following the descent of node_bound for the least key 
+/// and continuing in order from there. a missing ikey lets every key through.
+/// f returns false to stop the walk, in which case false is returned as well.
+fn node_entries_from<Db, F, const N: usize>(
+    root: &MptNode<N>, db: &Db, ikey: Option<&[u8]>, path: &mut Vec<u8>, f: &mut F
+) -> Result<bool>
+where
+    Db: Database<N>,
+    F: FnMut(&[u8], &[u8]) -> Result<bool>
+{
+    let depth = path.len();
+    let more = match root {
+        MptNode::Leaf(LeafNode { remained, value }) => {
+            if ikey.map_or(true, |ikey| remained.as_slice() >= ikey) {
+                path.extend_from_slice(remained);
+                f(path, value)?
+            } else {
+                true
             }
-            collapsed_node.value = value;
-            collapsed_node.into()
         },
-        MptNode::Extension(ExtensionNode { shared, subtree }) => {
-            ExtensionNode {
-                shared: shared,
-                subtree: subtree_collapse(subtree, db)?
-            }.into()
+        MptNode::Extension(ExtensionNode { shared, subtree }) => match ikey {
+            Some(ikey) if ikey.starts_with(shared) => {
+                path.extend_from_slice(shared);
+                subtree_entries_from(subtree, db, Some(&ikey[shared.len()..]), path, f)?
+            },
+            // every key below is smaller
+            Some(ikey) if shared.as_slice() < ikey => true,
+            _ => {
+                path.extend_from_slice(shared);
+                subtree_entries_from(subtree, db, None, path, f)?
+            }
+        },
+        MptNode::Branch(BranchNode { branchs, value }) => {
+            // the key of the branch value is smaller than ikey unless they are equal
+            let (first, below) = match ikey {
+                Some([nibble, below @ ..]) => (branch_index(*nibble)?, Some(below)),
+                _ => {
+                    if !value.is_empty() && !f(path, value)? {
+                        return Ok(false)
+                    }
+                    (0, None)
+                }
+            };
+            let mut more = true;
+            for (idx, branch) in branchs.iter().enumerate().skip(first) {
+                path.push(idx as u8);
+                let bound = if idx == first { below } else { None };
+                more = subtree_entries_from(branch, db, bound, path, f)?;
+                path.pop();
+                if !more {
+                    break
+                }
+            }
+            more
         }
     };
+    path.truncate(depth);
+    Ok(more)
+}
 
-    let (dbkey, rlp) = node_collapsed.encode()?;
-    // after collapsing, a node either keeps unchanged, or part of it is committed to database,
-    // in the later case, the node must contains a database key, whose length is 32
-    // so the rlp length of collapsed node must exceeds the 32 byte limit
-    assert!(rlp.len() >= 32);
-    db.insert(&dbkey, rlp)?;
-    Ok(Subtree::NodeKey(dbkey))
+fn subtree_entries_from<Db, F, const N: usize>(
+    subtree: &Subtree<N>, db: &Db, ikey: Option<&[u8]>, path: &mut Vec<u8>, f: &mut F
+) -> Result<bool>
+where
+    Db: Database<N>,
+    F: FnMut(&[u8], &[u8]) -> Result<bool>
+{
+    match subtree {
+        Subtree::Empty => Ok(true),
+        Subtree::Node(node) => node_entries_from(node, db, ikey, path, f),
+        Subtree::NodeKey(dbkey) => {
+            let root = load_node(db, dbkey)?;
+            node_entries_from(&root, db, ikey, path, f)
+        }
+    }
 }
 
-fn subtree_collapse<Db>(subtree: Subtree, db: &mut Db) -> Result<Subtree>
-where 
-    Db: Database
+/// walk down to the smallest (or largest if `last` is set) key below root,
+/// appending its nibbles to path
+fn node_extreme<Db, const N: usize>(root: &MptNode<N>, db: &Db, last: bool, path: &mut Vec<u8>) -> Result<()>
+where
+    Db: Database<N>
+{
+    match root {
+        MptNode::Leaf(LeafNode { remained, .. }) => {
+            path.extend_from_slice(remained);
+            Ok(())
+        },
+        MptNode::Extension(ExtensionNode { shared, subtree }) => {
+            path.extend_from_slice(shared);
+            subtree_extreme(subtree, db, last, path)
+        },
+        MptNode::Branch(BranchNode { branchs, value }) => {
+            // the key of the branch value is a prefix of all keys in its children,
+            // so it sorts first
+            if !last && !value.is_empty() {
+                return Ok(())
+            }
+            let mut children = branchs.iter()
+                .enumerate()
+                .filter(|(_, branch)| !matches!(branch, Subtree::Empty));
+            let child = if last { children.last() } else { children.next() };
+            if let Some((idx, subtree)) = child {
+                path.push(idx as u8);
+                subtree_extreme(subtree, db, last, path)
+            } else {
+                Ok(())
+            }
+        },
+    }
+}
+
+fn subtree_extreme<Db, const N: usize>(subtree: &Subtree<N>, db: &Db, last: bool, path: &mut Vec<u8>) -> Result<()>
+where
+    Db: Database<N>
 {
     match subtree {
-        Subtree::Node(root) => node_collapse(*root, db),
-        _ => Ok(subtree)
+        Subtree::Empty => Ok(()),
+        Subtree::Node(node) => node_extreme(node, db, last, path),
+        Subtree::NodeKey(dbkey) => {
+            let root = load_node(db, dbkey)?;
+            node_extreme(&root, db, last, path)
+        }
     }
 }
 
-fn node_proof<Db, ProofDb>(
-    root: &MptNode, db: &Db, proof: &mut ProofDb, ikey: &[u8]
+/// find the greatest key below root not greater than ikey (or the least key 
+/// not less than ikey if floor is unset), appending its nibbles to path.
+/// returns false with path untouched if there is no such key
+fn node_bound<Db, const N: usize>(
+    root: &MptNode<N>, db: &Db, ikey: &[u8], floor: bool, path: &mut Vec<u8>
 ) -> Result<bool>
 where
-    Db: Database,
-    ProofDb: Database
+    Db: Database<N>
 {
-    let (hash, rlp) = root.encode()?;
-    proof.insert(&hash, rlp)?;
+    let depth = path.len();
+    // whether every key below a subtree with the given path is on the wanted side
+    let beyond = |prefix: &[u8]| if floor { prefix < ikey } else { prefix > ikey };
+
     match root {
-        MptNode::Leaf(leaf) => Ok(leaf.remained == ikey),
+        MptNode::Leaf(LeafNode { remained, .. }) => {
+            if remained.as_slice() == ikey || beyond(remained) {
+                path.extend_from_slice(remained);
+                return Ok(true)
+            }
+        },
         MptNode::Extension(ExtensionNode { shared, subtree }) => {
-            match common_prefix(&shared, ikey) {
-                (_, [], key_remained) => {
-                    subtree_proof(subtree, db, proof, key_remained)
-                },
-                _ => Ok(false)
+            if ikey.starts_with(shared) {
+                path.extend_from_slice(shared);
+                if subtree_bound(subtree, db, &ikey[shared.len()..], floor, path)? {
+                    return Ok(true)
+                }
+            } else if beyond(shared) {
+                path.extend_from_slice(shared);
+                subtree_extreme(subtree, db, floor, path)?;
+                return Ok(true)
             }
         },
-        MptNode::Branch(branch) => {
+        MptNode::Branch(BranchNode { branchs, value }) => {
             if ikey.is_empty() {
-                Ok(false)
+                // the branch value equals ikey, all other keys are greater
+                if !value.is_empty() {
+                    return Ok(true)
+                }
+                if !floor {
+                    node_extreme(root, db, false, path)?;
+                    return Ok(true)
+                }
             } else {
-                let (prefix, key_remained) = ikey.split_at(1);
-                let idx = prefix[0] as usize;
-                let subtree = &branch.branchs[idx];
-                subtree_proof(subtree, db, proof, key_remained)
+                let idx = branch_index(ikey[0])?;
+                path.push(ikey[0]);
+                if subtree_bound(&branchs[idx], db, &ikey[1..], floor, path)? {
+                    return Ok(true)
+                }
+                path.truncate(depth);
+
+                let siblings: Vec<usize> = if floor {
+                    (0..idx).rev().collect()
+                } else {
+                    (idx + 1..branchs.len()).collect()
+                };
+                let sibling = siblings.into_iter()
+                    .find(|idx| !matches!(branchs[*idx], Subtree::Empty));
+                if let Some(idx) = sibling {
+                    path.push(idx as u8);
+                    subtree_extreme(&branchs[idx], db, floor, path)?;
+                    return Ok(true)
+                }
+                // the branch value is a prefix of ikey, so it is smaller
+                if floor && !value.is_empty() {
+                    return Ok(true)
+                }
             }
         },
     }
+    path.truncate(depth);
+    Ok(false)
 }
 
-fn subtree_proof<Db, ProofDb>(subtree: &Subtree, db: &Db, proof: &mut ProofDb, ikey: &[u8]) -> Result<bool>
+fn subtree_bound<Db, const N: usize>(
+    subtree: &Subtree<N>, db: &Db, ikey: &[u8], floor: bool, path: &mut Vec<u8>
+) -> Result<bool>
 where
-    Db: Database,
-    ProofDb: Database
+    Db: Database<N>
 {
     match subtree {
         Subtree::Empty => Ok(false),
-        Subtree::Node(node) => node_proof(node, db, proof, ikey),
+        Subtree::Node(node) => node_bound(node, db, ikey, floor, path),
         Subtree::NodeKey(dbkey) => {
-            let rlp = db.get(&dbkey)?
-                .ok_or(Error::TrieError(TrieError::SubtreeNotFound))?;
-            let root = MptNode::from_rlp(&rlp)?;
-            node_proof(&root, db, proof, ikey)
+            let root = load_node(db, dbkey)?;
+            node_bound(&root, db, ikey, floor, path)
         }
     }
 }
 
 /// get value with a key from the trie
-pub(crate) fn node_get<Db>(
-    root: &MptNode, db: &Db, ikey: &[u8]
+pub(crate) fn node_get<Db, const N: usize>(
+    root: &MptNode<N>, db: &Db, ikey: &[u8]
 ) -> Result<Option<Vec<u8>>>
 where
-    Db: Database
+    Db: Database<N>
 {
     match root {
         MptNode::Leaf(LeafNode { remained, value: leaf_value }) => {
@@ -296,10 +2634,11 @@ where
         },
         MptNode::Branch(BranchNode { branchs, value }) => {
             if ikey.is_empty() {
-                Ok(Some(value.clone()))
+                // an empty value means no key ends at this branch
+                Ok(if value.is_empty() { None } else { Some(value.clone()) })
             } else {
                 let (prefix, key_remained) = ikey.split_at(1);
-                let idx = prefix[0] as usize;
+                let idx = branch_index(prefix[0])?;
                 let subtree = &branchs[idx];
                 subtree_get(subtree, db, key_remained)
             }
@@ -307,98 +2646,272 @@ where
     }
 }
 
-fn subtree_get<Db>(
-    subtree: &Subtree, db: &Db, key: &[u8]
+fn subtree_get<Db, const N: usize>(
+    subtree: &Subtree<N>, db: &Db, key: &[u8]
 ) -> Result<Option<Vec<u8>>>
 where
-    Db: Database
+    Db: Database<N>
 {
     match subtree {
         Subtree::Empty => Ok(None),
         Subtree::Node(node) => node_get(node, db, key),
         Subtree::NodeKey(dbkey) => {
-            let rlp = db.get(&dbkey)?
-                .ok_or(Error::TrieError(TrieError::SubtreeNotFound))?;
-            let root = MptNode::from_rlp(&rlp)?;
+            let root = load_node(db, dbkey)?;
             node_get(&root, db, key)
         }
     }
 }
 
+/// remove a key from the trie, returns None if the node becomes empty.
+/// Nodes are merged so that the result has the same shape as a trie built 
+/// without the key.
+fn node_remove<Db, const N: usize>(root: MptNode<N>, db: &mut Db, ikey: &[u8]) -> Result<Option<MptNode<N>>>
+where
+    Db: Database<N>
+{
+    Ok(match root {
+        MptNode::Leaf(leaf) => {
+            if leaf.remained == ikey { None } else { Some(leaf.into()) }
+        },
+        MptNode::Extension(ExtensionNode { shared, subtree }) => {
+            if !ikey.starts_with(&shared) {
+                return Ok(Some(ExtensionNode { shared, subtree }.into()))
+            }
+            subtree_remove(subtree, db, &ikey[shared.len()..])?
+                .map(|node| prepend_path(&shared, node))
+        },
+        MptNode::Branch(BranchNode { mut branchs, mut value }) => {
+            if ikey.is_empty() {
+                value = Vec::new();
+            } else {
+                let idx = branch_index(ikey[0])?;
+                let subtree = mem::replace(&mut branchs[idx], Subtree::Empty);
+                branchs[idx] = match subtree_remove(subtree, db, &ikey[1..])? {
+                    Some(node) => node.into(),
+                    None => Subtree::Empty
+                };
+            }
+            branch_merge(branchs, value, db)?
+        }
+    })
+}
+
+/// remove several keys from the trie in one descent, returns None if the node 
+/// becomes empty. ikeys must be sorted. Each node is merged once, after all 
+/// the keys below it are removed, giving the same shape as [`node_remove`] 
+/// applied key by key.
+fn node_remove_batch<Db, const N: usize>(root: MptNode<N>, db: &mut Db, ikeys: &[&[u8]]) -> Result<Option<MptNode<N>>>
+where
+    Db: Database<N>
+{
+    if ikeys.is_empty() {
+        return Ok(Some(root))
+    }
+    Ok(match root {
+        MptNode::Leaf(leaf) => {
+            if ikeys.contains(&leaf.remained.as_slice()) { None } else { Some(leaf.into()) }
+        },
+        MptNode::Extension(ExtensionNode { shared, subtree }) => {
+            let below: Vec<&[u8]> = ikeys.iter()
+                .filter_map(|ikey| ikey.strip_prefix(shared.as_slice()))
+                .collect();
+            if below.is_empty() {
+                return Ok(Some(ExtensionNode { shared, subtree }.into()))
+            }
+            subtree_remove_batch(subtree, db, &below)?
+                .map(|node| prepend_path(&shared, node))
+        },
+        MptNode::Branch(BranchNode { mut branchs, mut value }) => {
+            // sorted, so a key ending here comes first
+            let children = match ikeys.split_first() {
+                Some((first, rest)) if first.is_empty() => {
+                    value = Vec::new();
+                    rest
+                },
+                _ => ikeys
+            };
+            let mut grouped: [Vec<&[u8]>; 16] = Default::default();
+            for ikey in children {
+                grouped[branch_index(ikey[0])?].push(&ikey[1..]);
+            }
+            for (branch, below) in branchs.iter_mut().zip(grouped) {
+                if below.is_empty() {
+                    continue
+                }
+                let subtree = mem::replace(branch, Subtree::Empty);
+                *branch = match subtree_remove_batch(subtree, db, &below)? {
+                    Some(node) => node.into(),
+                    None => Subtree::Empty
+                };
+            }
+            branch_merge(branchs, value, db)?
+        }
+    })
+}
+
+fn subtree_remove_batch<Db, const N: usize>(subtree: Subtree<N>, db: &mut Db, ikeys: &[&[u8]]) -> Result<Option<MptNode<N>>>
+where
+    Db: Database<N>
+{
+    match subtree {
+        Subtree::Empty => Ok(None),
+        Subtree::Node(node) => node_remove_batch(unshare(node), db, ikeys),
+        Subtree::NodeKey(dbkey) => {
+            let root = load_node(db, &dbkey)?;
+            node_remove_batch(root, db, ikeys)
+        }
+    }
+}
+
+/// rebuild a branch after removals below it, merging it with its only child 
+/// or turning it into a leaf when it no longer branches
+fn branch_merge<Db, const N: usize>(mut branchs: [Subtree<N>; 16], value: Vec<u8>, db: &Db) -> Result<Option<MptNode<N>>>
+where
+    Db: Database<N>
+{
+    let mut children = branchs.iter()
+        .enumerate()
+        .filter(|(_, branch)| !matches!(branch, Subtree::Empty))
+        .map(|(idx, _)| idx);
+    Ok(match (children.next(), children.next()) {
+        // still branching
+        (Some(_), Some(_)) => Some(BranchNode { branchs, value }.into()),
+        (Some(_), None) if !value.is_empty() => Some(BranchNode { branchs, value }.into()),
+        // a single child left, merge it into this node
+        (Some(idx), None) => {
+            let child = match mem::replace(&mut branchs[idx], Subtree::Empty) {
+                Subtree::Node(node) => unshare(node),
+                Subtree::NodeKey(dbkey) => load_node(db, &dbkey)?,
+                Subtree::Empty => unreachable!()
+            };
+            Some(prepend_path(&[idx as u8], child))
+        },
+        (None, _) if !value.is_empty() => Some(LeafNode { remained: Vec::new(), value }.into()),
+        (None, _) => None
+    })
+}
+
+fn subtree_remove<Db, const N: usize>(subtree: Subtree<N>, db: &mut Db, ikey: &[u8]) -> Result<Option<MptNode<N>>>
+where
+    Db: Database<N>
+{
+    match subtree {
+        Subtree::Empty => Ok(None),
+        Subtree::Node(node) => node_remove(unshare(node), db, ikey),
+        Subtree::NodeKey(dbkey) => {
+            let root = load_node(db, &dbkey)?;
+            node_remove(root, db, ikey)
+        }
+    }
+}
+
 /// insert a key-value pair into trie.
 /// Value is a owned Vec<u8> here intentionally to reduce heap allocation.
-fn node_insert<Db>(
-    root: MptNode, db: &mut Db, ikey: &[u8], ivalue: Vec<u8>
-) -> Result<MptNode>
+pub(crate) fn node_insert<Db, const N: usize>(
+    root: MptNode<N>, db: &mut Db, ikey: &[u8], ivalue: Vec<u8>
+) -> Result<MptNode<N>>
+where
+    Db: Database<N>
+{
+    Ok(node_upsert(root, db, ikey, |_| Ok(Some(ivalue)))?.0)
+}
+
+/// descend to the position of a key and call update with the value stored 
+/// there, if any. The returned value is written in place. 
+/// Returns the node and whether it changed: when update returns None the node 
+/// comes back untouched, subtrees loaded on the way still being referenced 
+/// by their hashes.
+fn node_upsert<Db, F, const N: usize>(
+    root: MptNode<N>, db: &mut Db, ikey: &[u8], update: F
+) -> Result<(MptNode<N>, bool)>
 where
-    Db: Database
+    Db: Database<N>,
+    F: FnOnce(Option<&[u8]>) -> Result<Option<Vec<u8>>>
 {
     Ok(match root {
         // branch node, we choose the corresponding branch and visit it
-        MptNode::Branch(BranchNode { mut branchs, value }) => {
+        MptNode::Branch(BranchNode { mut branchs, mut value }) => {
             // we finally hit this branch
-            if ikey.is_empty() {
-                BranchNode {
-                    branchs,
-                    value: ivalue
+            let changed = if ikey.is_empty() {
+                let old = if value.is_empty() { None } else { Some(value.as_slice()) };
+                match update(old)? {
+                    Some(ivalue) => {
+                        value = ivalue;
+                        true
+                    },
+                    None => false
                 }
             } else {
                 // now the first one nibble is comsumpted
                 let (prefix, key) = ikey.split_at(1);
-                let idx = prefix[0] as usize;
+                let idx = branch_index(prefix[0])?;
                 let subtree = Subtree::Empty;
                 // swap out the original subtree
                 let subtree = mem::replace(&mut branchs[idx], subtree);
-                branchs[idx] = subtree_insert(subtree, db, key, ivalue)?;
-                BranchNode { branchs, value }
-            }.into()
+                let (subtree, changed) = subtree_upsert(subtree, db, key, update)?;
+                branchs[idx] = subtree;
+                changed
+            };
+            (BranchNode { branchs, value }.into(), changed)
         },
         MptNode::Leaf(LeafNode { remained, value: leaf_value }) => {
             // match max common prefix 
             match common_prefix(ikey, &remained) {
                 // full matched, replace the value
-                (_, [], []) => {
-                    LeafNode {
-                        remained,
-                        value: ivalue
-                    }.into()
+                (_, [], []) => match update(Some(&leaf_value))? {
+                    Some(value) => (LeafNode { remained, value }.into(), true),
+                    None => (LeafNode { remained, value: leaf_value }.into(), false)
                 },
                 // not fully matched 
                 (shared, key_remained, leaf_remained) => {
+                    let ivalue = match update(None)? {
+                        Some(ivalue) => ivalue,
+                        None => return Ok((LeafNode { remained, value: leaf_value }.into(), false))
+                    };
                     let branch = BranchNode::new().into();
                     let branch = node_insert(branch, db, key_remained, ivalue)?;
                     let branch = node_insert(branch, db, leaf_remained, leaf_value)?;
 
                     // has no common prefix
-                    if shared.is_empty() {
+                    let node = if shared.is_empty() {
                         branch
                     } else {
                         ExtensionNode {
                             shared: shared.to_vec(),
                             subtree: MptNode::from(branch).into()
                         }.into()
-                    }
+                    };
+                    (node, true)
                 },
             }
         },
         MptNode::Extension(ExtensionNode { shared, subtree }) => {
-            assert!(shared.len() > 0);
+            if shared.is_empty() {
+                return Err(Error::TrieError(TrieError::InvalidNodeShape(
+                    "Extension node with empty shared nibbles.".into()
+                )))
+            }
             // match max common prefix 
             match common_prefix(ikey, &shared) {
-                // shared fully matched, track to next node
+                // shared fully matched, track to next node. If the key ends 
+                // here too, key_remained is empty and the value goes to the 
+                // branch the extension points to
                 (_, key_remained, []) => {
-                    ExtensionNode {
-                        shared,
-                        subtree: subtree_insert(subtree, db, key_remained, ivalue)?
-                    }.into()
+                    let (subtree, changed) = subtree_upsert(subtree, db, key_remained, update)?;
+                    (ExtensionNode { shared, subtree }.into(), changed)
                 },
                 // here shared is not empty, so we build a extension first
-                // leaf_remained is not empty
-                (shared, key_remained, shared_remained) => {
+                // leaf_remained is not empty. key_remained is empty when the 
+                // key ends inside shared, then the value is held by the new branch
+                (common, key_remained, shared_remained) => {
+                    let ivalue = match update(None)? {
+                        Some(ivalue) => ivalue,
+                        None => return Ok((ExtensionNode { shared, subtree }.into(), false))
+                    };
                     let mut branch = BranchNode::new();
                     // length of shared must not less than 1
                     let (prefix, shared_remained) = shared_remained.split_at(1);
-                    let idx = prefix[0] as usize;
+                    let idx = branch_index(prefix[0])?;
                     if shared_remained.is_empty() {
                         branch.branch(idx, subtree);
                     } else {
@@ -411,42 +2924,417 @@ where
                     let node = node_insert(
                         branch.into(), db, key_remained, ivalue
                     )?;
-                    if shared.is_empty() {
+                    let node = if common.is_empty() {
                         node
                     } else {
                         ExtensionNode {
-                            shared: shared.to_vec(),
+                            shared: common.to_vec(),
                             subtree: MptNode::from(node).into()
                         }.into()
-                    }
+                    };
+                    (node, true)
                 }
             }
         }
     })
 }
 
-fn subtree_insert<Db>(
-    subtree: Subtree, db: &mut Db, key: &[u8], value: Vec<u8>
-) -> Result<Subtree>
+fn subtree_upsert<Db, F, const N: usize>(
+    subtree: Subtree<N>, db: &mut Db, key: &[u8], update: F
+) -> Result<(Subtree<N>, bool)>
 where 
-    Db: Database
+    Db: Database<N>,
+    F: FnOnce(Option<&[u8]>) -> Result<Option<Vec<u8>>>
 {
-    Ok(Subtree::Node(Box::new(match subtree {
+    Ok(match subtree {
         // subtress is empty, we 
         Subtree::Empty => {
-            LeafNode {
-                remained: key.to_vec(),
-                value: value.to_vec()
-            }.into()
+            match update(None)? {
+                Some(value) => (MptNode::from(LeafNode {
+                    remained: key.to_vec(),
+                    value
+                }).into(), true),
+                None => (Subtree::Empty, false)
+            }
         },
         Subtree::Node(root) => {
-            node_insert(*root, db, key, value)?
+            let (node, changed) = node_upsert(unshare(root), db, key, update)?;
+            (node.into(), changed)
         },
         Subtree::NodeKey(dbkey) => {
-            let rlp = db.get(&dbkey)?
-                .ok_or(Error::TrieError(TrieError::SubtreeNotFound))?;
-            let root = MptNode::from_rlp(&rlp)?;
-            node_insert(root, db, key, value)?
+            let root = load_node(db, &dbkey)?;
+            let (node, changed) = node_upsert(root, db, key, update)?;
+            // nothing loaded is kept in memory unless it is modified
+            (if changed { node.into() } else { Subtree::NodeKey(dbkey) }, changed)
+        }
+    })
+}
+
+#[cfg(test)]
+mod test_mpt {
+    use crate::{db::MemoryDb, hex_prefix::bytes_to_nibbles};
+    use std::sync::Arc;
+
+    use super::{
+        Trie, Database, LeafNode, BranchNode, MptNode, Subtree, node_insert, node_get, node_proof, 
+        node_path, node_find_prefix, node_bound, node_remove, node_remove_batch, KecHash, COLLAPSE_ENCODES
+    };
+    use crate::error::{Error, TrieError};
+
+    type RawTrie = Trie<MemoryDb, Vec<u8>, Vec<u8>>;
+
+    /// Typed keys are RLP encoded, which is prefix-free, so a key can never end
+    /// in the middle of another key's path through the public API. 
+    /// Build the trie from raw key bytes instead to exercise those paths.
+    fn raw_trie(kvs: &[(&str, &str)]) -> RawTrie {
+        let mut trie = Trie::new(MemoryDb::new());
+        for (key, value) in kvs {
+            let ikey = bytes_to_nibbles(key.as_bytes());
+            let value = value.as_bytes().to_vec();
+            trie.root = Some(match trie.root.take() {
+                Some(root) => node_insert(root, &mut trie.db, &ikey, value).unwrap(),
+                None => LeafNode { remained: ikey, value }.into()
+            });
+            trie.dirty = true;
+        }
+        trie
+    }
+
+    fn raw_get(trie: &RawTrie, key: &str) -> Option<Vec<u8>> {
+        let ikey = bytes_to_nibbles(key.as_bytes());
+        node_get(trie.root.as_ref().unwrap(), &trie.db, &ikey).unwrap()
+    }
+
+    #[test]
+    fn test_remove_batch_matches_sequential() {
+        let kvs = [
+            ("do", "verb"), ("dog", "puppy"), ("dogs", "pack"), ("doge", "coin"),
+            ("horse", "stallion"), ("horses", "herd"), ("hoof", "foot"), ("cat", "kitten")
+        ];
+        let batches: [&[&str]; 4] = [
+            &["dog", "horse"],
+            &["do", "dogs", "doge"],
+            &["cat", "dog", "dogs", "doge", "horse", "horses", "hoof"],
+            &["do", "dog", "dogs", "doge", "horse", "horses", "hoof", "cat"],
+        ];
+        for batch in batches {
+            let mut sequential = raw_trie(&kvs);
+            sequential.commit().unwrap();
+            for key in batch {
+                sequential = sequential.remove_raw(key.as_bytes()).unwrap().0;
+            }
+
+            // removed from the committed trie, so children are loaded from the database
+            let mut batched = raw_trie(&kvs);
+            batched.commit().unwrap();
+            let mut ikeys: Vec<Vec<u8>> = batch.iter().map(|key| bytes_to_nibbles(key.as_bytes())).collect();
+            ikeys.sort();
+            let targets: Vec<&[u8]> = ikeys.iter().map(|ikey| ikey.as_slice()).collect();
+            let root = batched.root.take().unwrap();
+            batched.root = node_remove_batch(root, &mut batched.db, &targets).unwrap();
+            batched.dirty = true;
+
+            assert_eq!(batched.commit().unwrap(), sequential.commit().unwrap());
+            for (key, value) in kvs.iter().filter(|(key, _)| !batch.contains(key)) {
+                assert_eq!(raw_get(&batched, key), Some(value.as_bytes().to_vec()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_extreme_keys_with_branch_value() {
+        let mut trie = raw_trie(&[
+            ("dog", "puppy"), ("horse", "stallion"), ("do", "verb"), ("doge", "coin")
+        ]);
+        assert_eq!(trie.first_key().unwrap(), Some(b"do".to_vec()));
+        assert_eq!(trie.last_key().unwrap(), Some(b"horse".to_vec()));
+
+        trie.commit().unwrap();
+        assert_eq!(trie.first_key().unwrap(), Some(b"do".to_vec()));
+        assert_eq!(trie.last_key().unwrap(), Some(b"horse".to_vec()));
+    }
+
+    #[test]
+    fn test_key_ends_inside_extension() {
+        // "abcd" and "abce" create an extension whose shared nibbles cover "ab"
+        let kvs = [("abcd", "long"), ("abce", "other"), ("ab", "short")];
+        let mut trie = raw_trie(&kvs);
+        trie.commit().unwrap();
+        for (key, value) in kvs {
+            assert_eq!(raw_get(&trie, key), Some(value.as_bytes().to_vec()));
+        }
+
+        // the same with a single leaf being split
+        let kvs = [("abcd", "long"), ("ab", "short")];
+        let mut trie = raw_trie(&kvs);
+        trie.commit().unwrap();
+        for (key, value) in kvs {
+            assert_eq!(raw_get(&trie, key), Some(value.as_bytes().to_vec()));
+        }
+        assert_eq!(raw_get(&trie, "abc"), None);
+    }
+
+    #[test]
+    fn test_out_of_range_nibble() {
+        let mut trie = raw_trie(&[("dog", "puppy"), ("horse", "stallion")]);
+        let root = trie.root.take().unwrap();
+        // the root is an extension over "6", followed by a branch
+        let bad_key = [6, 16, 6, 15];
+        fn is_invalid<T>(res: Result<T, Error>) -> bool {
+            matches!(res, Err(Error::TrieError(TrieError::InvalidNodeShape(_))))
+        }
+
+        assert!(is_invalid(node_get(&root, &trie.db, &bad_key)));
+        let load = |_: &KecHash| -> Result<MptNode, Error> {
+            Err(Error::TrieError(TrieError::SubtreeNotFound))
+        };
+        assert!(is_invalid(node_proof(&root, &load, &bad_key, &mut |_, _| Ok(()))));
+        assert!(is_invalid(node_path(&root, None, &trie.db, &bad_key, &mut Vec::new())));
+        assert!(is_invalid(node_find_prefix(&root, &trie.db, &bad_key)));
+        assert!(is_invalid(node_bound(&root, &trie.db, &bad_key, true, &mut Vec::new())));
+        assert!(is_invalid(node_remove(root.clone(), &mut trie.db, &bad_key)));
+        assert!(is_invalid(node_remove_batch(root.clone(), &mut trie.db, &[&bad_key[..]])));
+        assert!(is_invalid(node_insert(root, &mut trie.db, &bad_key, b"bad".to_vec())));
+    }
+
+    #[test]
+    fn test_rebuild_hash() {
+        let mut trie = raw_trie(&[
+            ("do", "verb"), ("dog", "puppy"), ("doge", "coin"), ("horse", "stallion")
+        ]);
+        assert_eq!(trie.root_hash(), None);
+
+        let hash = trie.rebuild_hash().unwrap();
+        assert!(hash.is_some());
+        assert_eq!(trie.root_hash(), hash);
+        assert!(trie.is_dirty());
+        assert!(trie.db.is_empty());
+
+        assert_eq!(trie.commit().unwrap(), hash);
+    }
+
+    #[test]
+    fn test_committed_consistency() {
+        let mut trie = raw_trie(&[
+            ("do", "verb"), ("dog", "puppy"), ("doge", "coin"), ("horse", "stallion")
+        ]);
+        // not checked while dirty
+        assert!(trie.assert_committed_consistency().is_ok());
+        let hash = trie.commit().unwrap().unwrap();
+        assert!(trie.assert_committed_consistency().is_ok());
+
+        // desync the recorded hash from the tree
+        trie.root_hash = Some([0; 32]);
+        match trie.assert_committed_consistency() {
+            Err(Error::TrieError(TrieError::HashMismatch(recorded))) => assert_eq!(recorded, [0; 32]),
+            other => panic!("unexpected result {:?}", other)
+        }
+        trie.root_hash = None;
+        match trie.assert_committed_consistency() {
+            Err(Error::TrieError(TrieError::HashMismatch(computed))) => assert_eq!(computed, hash),
+            other => panic!("unexpected result {:?}", other)
+        }
+
+        trie.root_hash = Some(hash);
+        trie = trie.revert(hash).unwrap();
+        assert!(trie.assert_committed_consistency().is_ok());
+    }
+
+    #[test]
+    fn test_branch_value_iterated_first() {
+        let mut trie = raw_trie(&[("dogs", "pack"), ("do", "verb"), ("dog", "puppy")]);
+
+        // "do" ends at the branch below the extension over its nibbles
+        match trie.root.as_ref().unwrap() {
+            MptNode::Extension(ext) => {
+                assert_eq!(ext.shared, bytes_to_nibbles(b"do"));
+                match &ext.subtree {
+                    Subtree::Node(node) => match node.as_ref() {
+                        MptNode::Branch(branch) => assert_eq!(branch.value, b"verb"),
+                        other => panic!("unexpected node {:?}", other)
+                    },
+                    other => panic!("unexpected subtree {:?}", other)
+                }
+            },
+            other => panic!("unexpected root {:?}", other)
+        }
+
+        let expected = vec![
+            (b"do".to_vec(), b"verb".to_vec()),
+            (b"dog".to_vec(), b"puppy".to_vec()),
+            (b"dogs".to_vec(), b"pack".to_vec())
+        ];
+        assert_eq!(trie.entries_raw().unwrap(), expected);
+        trie.commit().unwrap();
+        assert_eq!(trie.entries_raw().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_count_prefix() {
+        let mut trie = raw_trie(&[
+            ("do", "verb"), ("dog", "puppy"), ("doge", "coin"), ("dot", "point"),
+            ("horse", "stallion"), ("abcd", "long"), ("abce", "other")
+        ]);
+
+        for _ in 0..2 {
+            assert_eq!(trie.count_prefix(b"").unwrap(), 7);
+            // prefix landing on a branch holding a value
+            assert_eq!(trie.count_prefix(b"do").unwrap(), 4);
+            assert_eq!(trie.count_prefix(b"dog").unwrap(), 2);
+            // prefix ending inside the shared nibbles of an extension
+            assert_eq!(trie.count_prefix(b"a").unwrap(), 2);
+            assert_eq!(trie.count_prefix(b"abc").unwrap(), 2);
+            // prefix ending inside a leaf
+            assert_eq!(trie.count_prefix(b"hor").unwrap(), 1);
+            // no match
+            assert_eq!(trie.count_prefix(b"ax").unwrap(), 0);
+            assert_eq!(trie.count_prefix(b"doges").unwrap(), 0);
+            assert_eq!(trie.count_prefix(b"z").unwrap(), 0);
+
+            trie.commit().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_contains_prefix() {
+        let mut trie = raw_trie(&[
+            ("user:alice", "1"), ("user:bob", "2"), ("userdata", "3"), ("session", "4")
+        ]);
+
+        for _ in 0..2 {
+            assert!(trie.contains_prefix(b"user:").unwrap());
+            // ends inside the shared nibbles of the extension over "user"
+            assert!(trie.contains_prefix(b"us").unwrap());
+            assert!(trie.contains_prefix(b"user:b").unwrap());
+            assert!(!trie.contains_prefix(b"admin:").unwrap());
+            assert!(!trie.contains_prefix(b"user:carol").unwrap());
+
+            trie.commit().unwrap();
         }
-    })))
+        assert!(!RawTrie::new(MemoryDb::new()).contains_prefix(b"").unwrap());
+    }
+
+    #[test]
+    fn test_collapse_encodes_once() {
+        let mut trie: Trie<MemoryDb, u64, String> = Trie::new(MemoryDb::new());
+        for i in 0..200u64 {
+            trie = trie.insert(&i, &"v".repeat(40)).unwrap();
+        }
+
+        COLLAPSE_ENCODES.with(|count| count.set(0));
+        let (_, writes) = trie.commit_collecting_writes().unwrap();
+        // all nodes are large enough to be stored, each of them is encoded once. 
+        // Identical leaves are written under the same hash, so the writes are 
+        // counted rather than the stored nodes
+        assert_eq!(COLLAPSE_ENCODES.with(|count| count.get()), writes.len());
+        assert!(trie.db.len() < writes.len());
+
+        // a root below the inline threshold is stored without encoding it again
+        let mut small: Trie<MemoryDb, u64, String> = Trie::new(MemoryDb::new());
+        small = small.insert(&1, &"v".to_string()).unwrap();
+        COLLAPSE_ENCODES.with(|count| count.set(0));
+        let root_hash = small.commit().unwrap().unwrap();
+        assert_eq!(COLLAPSE_ENCODES.with(|count| count.get()), 1);
+        assert!(small.db.exists(&root_hash).unwrap());
+    }
+
+    #[test]
+    fn test_incremental_root() {
+        let mut trie: Trie<MemoryDb, u64, String> = Trie::new(MemoryDb::new());
+        for i in 0..200u64 {
+            trie = trie.insert(&i, &"v".repeat(40)).unwrap();
+        }
+        let root_hash = trie.commit().unwrap().unwrap();
+        let mut trie = trie.revert(root_hash).unwrap();
+
+        trie = trie.insert(&7, &"w".repeat(40)).unwrap();
+        let path_len = trie.get_path(&7).unwrap().len();
+        COLLAPSE_ENCODES.with(|count| count.set(0));
+        let incremental = trie.incremental_root().unwrap();
+        // root, the branch under nibble 0 and the leaf
+        assert_eq!(path_len, 3);
+        assert_eq!(COLLAPSE_ENCODES.with(|count| count.get()), path_len);
+
+        // the same state built from scratch hashes every node
+        let mut full: Trie<MemoryDb, u64, String> = Trie::new(MemoryDb::new());
+        for i in 0..200u64 {
+            let value = if i == 7 { "w" } else { "v" };
+            full = full.insert(&i, &value.repeat(40)).unwrap();
+        }
+        assert_eq!(full.compute_root().unwrap(), incremental);
+        assert_eq!(trie.compute_root().unwrap(), incremental);
+        assert_eq!(trie.commit().unwrap(), incremental);
+    }
+
+    #[test]
+    fn test_identical_insert_keeps_hashed_subtrees() {
+        let mut trie: Trie<MemoryDb, u64, String> = Trie::new(MemoryDb::new());
+        for i in 0..64u64 {
+            trie = trie.insert(&i, &"v".repeat(40)).unwrap();
+        }
+        let root_hash = trie.commit().unwrap().unwrap();
+        let trie = trie.revert(root_hash).unwrap();
+
+        // the path of the key is loaded to compare the values, but not kept
+        let trie = trie.insert(&7, &"v".repeat(40)).unwrap();
+        assert!(!trie.is_dirty());
+        match &trie.root {
+            Some(MptNode::Branch(BranchNode { branchs, .. })) => {
+                assert!(branchs.iter().all(|branch| !matches!(branch, Subtree::Node(_))));
+            },
+            other => panic!("unexpected root {:?}", other)
+        }
+    }
+
+    #[test]
+    fn test_floor_ceil_with_branch_value() {
+        let mut trie = raw_trie(&[
+            ("do", "verb"), ("dog", "puppy"), ("doge", "coin"), ("horse", "stallion")
+        ]);
+        let bound = |trie: &RawTrie, key: &str, floor| {
+            trie.bound_key(key.as_bytes(), floor).unwrap()
+                .map(|key| String::from_utf8(key).unwrap())
+        };
+
+        for _ in 0..2 {
+            assert_eq!(bound(&trie, "dof", true).as_deref(), Some("do"));
+            assert_eq!(bound(&trie, "dof", false).as_deref(), Some("dog"));
+            assert_eq!(bound(&trie, "dog", true).as_deref(), Some("dog"));
+            assert_eq!(bound(&trie, "dogf", true).as_deref(), Some("doge"));
+            assert_eq!(bound(&trie, "dogf", false).as_deref(), Some("horse"));
+            assert_eq!(bound(&trie, "d", true), None);
+            assert_eq!(bound(&trie, "d", false).as_deref(), Some("do"));
+            assert_eq!(bound(&trie, "z", true).as_deref(), Some("horse"));
+            assert_eq!(bound(&trie, "z", false), None);
+
+            trie.commit().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_clone_shares_subtrees() {
+        let mut trie: Trie<MemoryDb, u64, String> = Trie::new(MemoryDb::new());
+        for i in 0..256u64 {
+            trie = trie.insert(&i, &"v".repeat(40)).unwrap();
+        }
+
+        // rlp(200) is 0x81c8, it lives below the branch at nibble 8 of the root
+        let modified = trie.clone().insert(&200, &"changed".to_string()).unwrap();
+        assert_eq!(trie.get(&200).unwrap(), Some("v".repeat(40)));
+
+        let branchs = |trie: &Trie<MemoryDb, u64, String>| match &trie.root {
+            Some(MptNode::Branch(BranchNode { branchs, .. })) => branchs.clone(),
+            _ => panic!("the root should be a branch")
+        };
+        let (before, after) = (branchs(&trie), branchs(&modified));
+        for (idx, (before, after)) in before.iter().zip(after.iter()).enumerate() {
+            match (before, after) {
+                (Subtree::Node(before), Subtree::Node(after)) => {
+                    assert_eq!(Arc::ptr_eq(before, after), idx != 8, "branch {}", idx);
+                },
+                (Subtree::Empty, Subtree::Empty) => (),
+                _ => panic!("branch {} changed its kind", idx)
+            }
+        }
+    }
 }
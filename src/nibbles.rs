@@ -0,0 +1,110 @@
+//! Nibble paths, the unit in which keys are consumed by trie nodes.
+
+use core::fmt::Display;
+
+use alloc::{vec::Vec, string::String, format};
+
+use serde::Serialize;
+
+use crate::{hex_prefix::{bytes_to_nibbles, nibbles_to_bytes}, error::{Error, Result}};
+
+/// A path of nibbles, each in `0..16`, as returned by 
+/// [`Trie::get_path`](crate::mpt::Trie::get_path) and 
+/// [`Trie::dirty_keys`](crate::mpt::Trie::dirty_keys).
+/// Serializes as the list of nibbles.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Default, Serialize)]
+pub struct Nibbles(pub(crate) Vec<u8>);
+
+impl Nibbles {
+    /// Split every byte of a key into its high and low nibble.
+    pub fn from_key(key: &[u8]) -> Self {
+        Self(bytes_to_nibbles(key))
+    }
+
+    /// Copy a path given one nibble per byte, failing if any byte is not a nibble.
+    pub fn try_from_bytes(nibbles: &[u8]) -> Result<Self> {
+        Self::from_nibbles(nibbles.to_vec())
+    }
+
+    /// Wrap a path, failing if any element is not a nibble.
+    pub fn from_nibbles(nibbles: Vec<u8>) -> Result<Self> {
+        if let Some(invalid) = nibbles.iter().find(|nibble| **nibble >= 16) {
+            return Err(Error::EncodingError(format!("{:#x} is not a nibble.", invalid)))
+        }
+        Ok(Self(nibbles))
+    }
+
+    /// Join the nibbles back into bytes, failing if the length is odd.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        nibbles_to_bytes(&self.0)
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn into_vec(self) -> Vec<u8> {
+        self.0
+    }
+}
+
+impl AsRef<[u8]> for Nibbles {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl TryFrom<Vec<u8>> for Nibbles {
+    type Error = Error;
+
+    fn try_from(nibbles: Vec<u8>) -> Result<Self> {
+        Self::from_nibbles(nibbles)
+    }
+}
+
+/// Formats as hex digits separated by dots, e.g. `6.4.6.f`.
+impl Display for Nibbles {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let digits: Vec<String> = self.0.iter().map(|nibble| format!("{:x}", nibble)).collect();
+        write!(f, "{}", digits.join("."))
+    }
+}
+
+#[cfg(test)]
+mod test_nibbles {
+    use super::Nibbles;
+
+    #[test]
+    fn test_construction() {
+        let nibbles = Nibbles::from_key(b"do");
+        assert_eq!(nibbles.as_slice(), &[6, 4, 6, 15]);
+        assert_eq!(nibbles.len(), 4);
+        assert_eq!(nibbles.to_bytes().unwrap(), b"do".to_vec());
+
+        assert_eq!(Nibbles::from_nibbles(vec![6, 4, 6, 15]).unwrap(), nibbles);
+        assert_eq!(Nibbles::try_from_bytes(&[6, 4, 6, 15]).unwrap(), nibbles);
+        assert!(Nibbles::from_nibbles(vec![6, 16]).is_err());
+        assert!(Nibbles::try_from_bytes(b"do").is_err());
+        assert!(Nibbles::try_from(vec![0xff]).is_err());
+
+        // an odd path is valid, but does not make whole bytes
+        let odd = Nibbles::from_nibbles(vec![1, 2, 3]).unwrap();
+        assert!(odd.to_bytes().is_err());
+        assert!(Nibbles::default().is_empty());
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(Nibbles::from_key(b"do").to_string(), "6.4.6.f");
+        assert_eq!(Nibbles::from_nibbles(vec![10]).unwrap().to_string(), "a");
+        assert_eq!(Nibbles::default().to_string(), "");
+    }
+}
@@ -12,6 +12,8 @@
 //! determine node type from RLP encoded byte array. So we created some proxy types as 
 //! a middle layer during encoding and decoding.
 
+use alloc::{vec::Vec, sync::Arc, format};
+
 use serde::{Serialize, Deserialize, Serializer, ser::SerializeSeq};
 use serde_bytes::{ByteBuf, Bytes};
 use serlp::{
@@ -23,8 +25,9 @@ use array_init::{try_array_init, array_init} ;
 
 use crate::{hex_prefix::{
     Nibbles,
-    FLAG_MASK
-}, mpt::{KecHash, keccak256, KEY_LEN}, error::{Error, Result}};
+    FLAG_MASK,
+    HpKind
+}, mpt::{KecHash, node_hash, KEY_LEN, INLINE_THRESHOLD, Database, load_node}, error::{Error, Result, TrieError}, nibbles};
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub(crate) struct LeafNode {
@@ -35,119 +38,154 @@ pub(crate) struct LeafNode {
 }
 
 mod hex_prefix_leaf {
+    use alloc::vec::Vec;
     use serde::{Deserializer, Serializer};
 
-    use crate::hex_prefix::{hex_prefix_encode, hex_prefix_decode};
+    use crate::hex_prefix::HpKind;
 
-    /// This just specializes [`serde_bytes::serialize`] to `<T = [u8]>`.
     pub(super) fn serialize<S>(nibbles: &Vec<u8>, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
-        let encoded = hex_prefix_encode(&nibbles, true);
-        serde_bytes::serialize(&encoded, serializer)
+        super::hex_prefix_path::serialize(nibbles, HpKind::Leaf, serializer)
     }
 
-    /// This takes the result of [`serde_bytes::deserialize`] from `[u8]` to `[u8; N]`.
     pub(super) fn deserialize<'de, D>(deserializer: D) -> Result<Vec<u8>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        super::hex_prefix_path::deserialize(HpKind::Leaf, deserializer)
+    }
+}
+
+/// Hex-prefix encoding of the path stored in leaf and extension nodes,
+/// the node kind is kept in the flag bit.
+mod hex_prefix_path {
+    use alloc::{vec::Vec, format};
+    use serde::{Deserializer, Serializer, de::Error};
+
+    use crate::hex_prefix::{hex_prefix_encode, hex_prefix_decode, HpKind};
+
+    /// This just specializes [`serde_bytes::serialize`] to `<T = [u8]>`.
+    pub(super) fn serialize<S>(nibbles: &[u8], kind: HpKind, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let encoded = hex_prefix_encode(nibbles, kind.to_flag());
+        serde_bytes::serialize(&encoded, serializer)
+    }
+
+    /// This takes the result of [`serde_bytes::deserialize`] and decodes the path,
+    /// failing if the flag does not match the expected node kind.
+    pub(super) fn deserialize<'de, D>(kind: HpKind, deserializer: D) -> Result<Vec<u8>, D::Error>
     where
         D: Deserializer<'de>,
     {
         let slice: &[u8] = serde_bytes::deserialize(deserializer)?;
-        let (decoded, flag) = hex_prefix_decode(slice);
-        if flag != true { panic!("Wrong node type met when decoding.") }
+        let (decoded, flag) = hex_prefix_decode(slice).map_err(D::Error::custom)?;
+        let found = HpKind::from_flag(flag);
+        if found != kind {
+            return Err(D::Error::custom(format!(
+                "Wrong node type met when decoding, expected {:?}, found {:?}.", kind, found
+            )))
+        }
         Ok(decoded)
     }
 }
 
 #[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
 #[serde(try_from = "RlpProxy")]
-pub(crate) enum Subtree {
+pub(crate) enum Subtree<const N: usize = KEY_LEN> {
     /// this field will be encoded into 0x80 with RLP encoding
     Empty,
-    Node(Box<MptNode>),
+    /// shared between clones of a trie, copied when modified
+    Node(Arc<MptNode<N>>),
     #[serde(with = "byte_array")]
-    NodeKey(KecHash)
+    NodeKey(KecHash<N>)
 }
 
-impl From<MptNode> for Subtree {
-    fn from(node: MptNode) -> Self {
-        Self::Node(Box::new(node))
+/// take a node out of a subtree, copying it if it is shared with another trie
+pub(crate) fn unshare<const N: usize>(node: Arc<MptNode<N>>) -> MptNode<N> {
+    Arc::try_unwrap(node).unwrap_or_else(|node| (*node).clone())
+}
+
+impl<const N: usize> From<MptNode<N>> for Subtree<N> {
+    fn from(node: MptNode<N>) -> Self {
+        Self::Node(Arc::new(node))
     }
 }
 
-impl TryFrom<RlpProxy> for Subtree {
+impl<const N: usize> TryFrom<RlpProxy> for Subtree<N> {
     type Error = Error;
 
-    fn try_from(node: RlpProxy) -> std::result::Result<Self, Self::Error> {
+    fn try_from(node: RlpProxy) -> core::result::Result<Self, Self::Error> {
         let buf = node.raw();
         
         Ok(match buf.len() {
             // empty 
             1 if buf[0] == 0x80 => Subtree::Empty,
-            1..=31 => Subtree::Node(Box::new(from_bytes(&buf)?)),
-            32.. => {
+            len @ 1.. if len < INLINE_THRESHOLD => Subtree::Node(Arc::new(from_bytes(&buf)?)),
+            1.. => {
                 let key_buf: ByteBuf = from_bytes(buf)?;
-                let mut key = [0; KEY_LEN];
-                key.copy_from_slice(&key_buf);
+                let key = KecHash::<N>::try_from(key_buf.as_slice())
+                    .map_err(|_| Error::EncodingError(format!(
+                        "Subtree key must be {} bytes, found {}.", N, key_buf.len()
+                    )))?;
                 Subtree::NodeKey(key)
             },
-            _ => panic!("Error subtree encoding.")
+            _ => return Err(Error::TrieError(TrieError::InvalidNodeShape(
+                "Empty subtree encoding.".into()
+            )))
         })
     }
 }
 
 #[derive(Deserialize, Serialize, Clone, PartialEq, Eq, Debug)]
-pub(crate) struct ExtensionNode {
+pub(crate) struct ExtensionNode<const N: usize = KEY_LEN> {
     #[serde(with = "hex_prefix_extension")]
     pub(crate) shared: Nibbles,
-    pub(crate) subtree: Subtree
+    pub(crate) subtree: Subtree<N>
 }
 
 mod hex_prefix_extension {
+    use alloc::vec::Vec;
     use serde::{Deserializer, Serializer};
 
-    use crate::hex_prefix::{hex_prefix_encode, hex_prefix_decode};
+    use crate::hex_prefix::HpKind;
 
-    /// This just specializes [`serde_bytes::serialize`] to `<T = [u8]>`.
     pub(super) fn serialize<S>(nibbles: &Vec<u8>, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
-        let encoded = hex_prefix_encode(&nibbles, false);
-        serde_bytes::serialize(&encoded, serializer)
+        super::hex_prefix_path::serialize(nibbles, HpKind::Extension, serializer)
     }
 
-    /// This takes the result of [`serde_bytes::deserialize`] from `[u8]` to `[u8; N]`.
     pub(super) fn deserialize<'de, D>(deserializer: D) -> Result<Vec<u8>, D::Error>
     where
         D: Deserializer<'de>,
     {
-        let slice: &[u8] = serde_bytes::deserialize(deserializer)?;
-        let (decoded, flag) = hex_prefix_decode(slice);
-        if flag != false { panic!("Wrong node type met when decoding.") }
-        Ok(decoded)
+        super::hex_prefix_path::deserialize(HpKind::Extension, deserializer)
     }
 }
 
 #[derive(Deserialize, Clone, PartialEq, Eq, Debug)]
 #[serde(try_from = "RlpProxy")]
-pub(crate) struct BranchNode {
-    pub branchs: [Subtree; 16],
+pub(crate) struct BranchNode<const N: usize = KEY_LEN> {
+    pub branchs: [Subtree<N>; 16],
     /// vec is empty when this node is not leaf
     #[serde(with = "serde_bytes")]
     pub value: Vec<u8>
 }
 
-impl TryFrom<RlpProxy> for BranchNode {
+impl<const N: usize> TryFrom<RlpProxy> for BranchNode<N> {
     type Error = Error;
 
-    fn try_from(proxy: RlpProxy) -> std::result::Result<Self, Error> {
+    fn try_from(proxy: RlpProxy) -> core::result::Result<Self, Error> {
         let mut tree = proxy.rlp_tree();
         let root = tree.root_mut();
         if let RlpNodeValue::Compound(compound) = &mut root.value {
             let branchs = try_array_init::<Error, _, _, 16>(|_| {
-                Ok(from_bytes::<Subtree>(compound.pop_front()
+                Ok(from_bytes::<Subtree<N>>(compound.pop_front()
                     .ok_or(
                         Error::EncodingError("Error decoding branchs".into())
                     )?.span
@@ -163,13 +201,15 @@ impl TryFrom<RlpProxy> for BranchNode {
                 value: value.into_vec()
             })
         } else {
-            panic!("Malformed Branch Node.")
+            Err(Error::TrieError(TrieError::InvalidNodeShape(
+                "Branch node is not a list.".into()
+            )))
         }
     }
 }
 
-impl Serialize for BranchNode {
-    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+impl<const N: usize> Serialize for BranchNode<N> {
+    fn serialize<S>(&self, serializer: S) -> core::result::Result<S::Ok, S::Error>
     where
         S: Serializer 
     {
@@ -182,7 +222,7 @@ impl Serialize for BranchNode {
     }
 }
 
-impl BranchNode {
+impl<const N: usize> BranchNode<N> {
     pub fn new() -> Self {
         Self {
             branchs: array_init(|_| Subtree::Empty),
@@ -190,34 +230,140 @@ impl BranchNode {
         }
     }
 
-    pub fn branch(&mut self, idx: usize, value: Subtree) {
+    pub fn branch(&mut self, idx: usize, value: Subtree<N>) {
         self.branchs[idx] = value
     }
 }
 
+/// The type of a trie node, as reported by the inspection APIs.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize)]
+pub enum NodeKind {
+    Leaf,
+    Extension,
+    Branch,
+}
+
 #[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
 #[serde(try_from = "RlpProxy")]
-pub(crate) enum MptNode {
+pub(crate) enum MptNode<const N: usize = KEY_LEN> {
     Leaf(LeafNode),
-    Extension(ExtensionNode),
-    Branch(BranchNode),
+    Extension(ExtensionNode<N>),
+    Branch(BranchNode<N>),
 }
 
-impl MptNode {
-    pub fn encode(&self) -> Result<(KecHash, Vec<u8>)> {
+impl<const N: usize> MptNode<N> {
+    pub fn encode(&self) -> Result<(KecHash<N>, Vec<u8>)> {
         let encoded = to_bytes(self)?;
-        Ok((keccak256(&encoded), encoded))
+        Ok((node_hash(&encoded), encoded))
     }
 
+    /// Decode a node, rejecting encodings that are not canonical 
+    /// (e.g. padded lengths), as go-ethereum does. 
     pub fn from_rlp(rlp: &[u8]) -> Result<Self> {
-        Ok(from_bytes(rlp)?)
+        let node: Self = from_bytes(rlp)?;
+        if to_bytes(&node)? != rlp {
+            return Err(Error::EncodingError("Non-canonical node encoding.".into()))
+        }
+        Ok(node)
+    }
+
+    pub fn kind(&self) -> NodeKind {
+        match self {
+            MptNode::Leaf(_) => NodeKind::Leaf,
+            MptNode::Extension(_) => NodeKind::Extension,
+            MptNode::Branch(_) => NodeKind::Branch,
+        }
+    }
+
+    /// see [`TrieNode::flatten_path`]
+    pub(crate) fn flatten_path<Db: Database<N>>(&self, db: &Db) -> Result<(nibbles::Nibbles, MptNode<N>)> {
+        let mut path = Vec::new();
+        let mut node = self.clone();
+        loop {
+            let next = match &node {
+                MptNode::Leaf(_) => break,
+                MptNode::Extension(ExtensionNode { shared, subtree }) => {
+                    path.extend_from_slice(shared);
+                    subtree
+                },
+                MptNode::Branch(BranchNode { branchs, value }) => {
+                    let mut children = branchs.iter().enumerate()
+                        .filter(|(_, child)| !matches!(child, Subtree::Empty));
+                    match (children.next(), children.next()) {
+                        (Some((idx, child)), None) if value.is_empty() => {
+                            path.push(idx as u8);
+                            child
+                        },
+                        _ => break
+                    }
+                }
+            };
+            node = match next {
+                Subtree::Empty => return Err(Error::TrieError(TrieError::InvalidNodeShape(
+                    "Extension node without subtree.".into()
+                ))),
+                Subtree::Node(child) => child.as_ref().clone(),
+                Subtree::NodeKey(dbkey) => load_node(db, dbkey)?
+            };
+        }
+        Ok((nibbles::Nibbles(path), node))
+    }
+
+    /// see [`TrieNode::unflatten_path`]
+    pub(crate) fn unflatten_path(path: &nibbles::Nibbles, terminal: MptNode<N>) -> MptNode<N> {
+        if path.is_empty() {
+            terminal
+        } else {
+            prepend_path(path.as_slice(), terminal)
+        }
     }
 }
 
-impl TryFrom<RlpProxy> for MptNode {
+/// A trie node as stored in the database, see 
+/// [`Trie::iter_nodes`](crate::mpt::Trie::iter_nodes).
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct TrieNode<const N: usize = KEY_LEN>(pub(crate) MptNode<N>);
+
+impl<const N: usize> TrieNode<N> {
+    pub fn from_rlp(rlp: &[u8]) -> Result<Self> {
+        Ok(Self(MptNode::from_rlp(rlp)?))
+    }
+
+    /// RLP encoding of the node
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        Ok(to_bytes(&self.0)?)
+    }
+
+    /// [`node_hash`] of the RLP encoding, i.e. the key of the node in database
+    pub fn hash(&self) -> Result<KecHash<N>> {
+        Ok(self.0.encode()?.0)
+    }
+
+    pub fn kind(&self) -> NodeKind {
+        self.0.kind()
+    }
+
+    /// Follow the chain of extensions and single-child branches without value
+    /// starting at this node, returning the nibbles consumed along the chain 
+    /// and the node it ends at, which is a leaf or a branch that forks.
+    /// This is the logical key path hidden behind the node types.
+    /// Hashed children are loaded from `db`.
+    pub fn flatten_path<Db: Database<N>>(&self, db: &Db) -> Result<(nibbles::Nibbles, TrieNode<N>)> {
+        let (path, terminal) = self.0.flatten_path(db)?;
+        Ok((path, TrieNode(terminal)))
+    }
+
+    /// The inverse of [`TrieNode::flatten_path`], in canonical form: 
+    /// the path is merged into `terminal` or put in an extension over it.
+    pub fn unflatten_path(path: &nibbles::Nibbles, terminal: TrieNode<N>) -> TrieNode<N> {
+        TrieNode(MptNode::unflatten_path(path, terminal.0))
+    }
+}
+
+impl<const N: usize> TryFrom<RlpProxy> for MptNode<N> {
     type Error = Error;
 
-    fn try_from(proxy: RlpProxy) ->  std::result::Result<Self, Error> {
+    fn try_from(proxy: RlpProxy) ->  core::result::Result<Self, Error> {
         let mut tree = proxy.rlp_tree();
         let root = tree.root();
         let buf = proxy.raw();
@@ -227,39 +373,62 @@ impl TryFrom<RlpProxy> for MptNode {
                 2 => {
                     let nibbles = tree.next()
                         .ok_or(Error::EncodingError("Error Decoding Node.".into()))?;
-                    let flag = nibbles[0] & FLAG_MASK;
-                    if flag == 0 {
-                        MptNode::Extension(from_bytes(buf)?)
-                    } else {
-                        MptNode::Leaf(from_bytes(buf)?)
+                    let flag = nibbles.first()
+                        .ok_or(Error::EncodingError("Empty node path.".into()))?;
+                    match HpKind::from_flag(flag & FLAG_MASK != 0) {
+                        HpKind::Extension => MptNode::Extension(from_bytes(buf)?),
+                        HpKind::Leaf => MptNode::Leaf(from_bytes(buf)?)
                     }
                 },
                 17 => MptNode::Branch(from_bytes(buf)?),
-                _ => panic!("Unexpected node type.")
+                len => return Err(Error::TrieError(TrieError::InvalidNodeShape(
+                    format!("Node with {} items.", len)
+                )))
             }
         } else {
-            panic!("Unexpected node type.")
+            return Err(Error::TrieError(TrieError::InvalidNodeShape(
+                "Node is not a list.".into()
+            )))
         })
     }
 }
 
-impl From<LeafNode> for MptNode {
+impl<const N: usize> From<LeafNode> for MptNode<N> {
     fn from(node: LeafNode) -> Self { Self::Leaf(node) }
 }
 
-impl From<ExtensionNode> for MptNode {
-    fn from(node: ExtensionNode) -> Self { Self::Extension(node) }
+impl<const N: usize> From<ExtensionNode<N>> for MptNode<N> {
+    fn from(node: ExtensionNode<N>) -> Self { Self::Extension(node) }
 }
 
-impl From<BranchNode> for MptNode {
-    fn from(node: BranchNode) -> Self { Self::Branch(node) }
+impl<const N: usize> From<BranchNode<N>> for MptNode<N> {
+    fn from(node: BranchNode<N>) -> Self { Self::Branch(node) }
+}
+
+/// put path in front of node, merging it into leaves and extensions
+pub(crate) fn prepend_path<const N: usize>(path: &[u8], node: MptNode<N>) -> MptNode<N> {
+    let join = |rest: &[u8]| [path, rest].concat();
+    match node {
+        MptNode::Leaf(LeafNode { remained, value }) => {
+            LeafNode { remained: join(&remained), value }.into()
+        },
+        MptNode::Extension(ExtensionNode { shared, subtree }) => {
+            ExtensionNode { shared: join(&shared), subtree }.into()
+        },
+        MptNode::Branch(_) => {
+            ExtensionNode { shared: path.to_vec(), subtree: node.into() }.into()
+        }
+    }
 }
 
 #[cfg(test)]
 mod test_nodes {
     use serlp::rlp::RlpTree;
 
-    use super::{LeafNode, BranchNode, ExtensionNode, MptNode, Subtree};
+    use serlp::rlp::from_bytes;
+    use serde_bytes::Bytes;
+
+    use super::{LeafNode, BranchNode, ExtensionNode, MptNode, TrieNode, Subtree, Error, to_bytes, KEY_LEN};
 
     #[test]
     fn test_extension_node() {
@@ -268,14 +437,14 @@ mod test_nodes {
             value: b"coin".to_vec()
         };
 
-        let mut branch = BranchNode::new();
+        let mut branch: BranchNode = BranchNode::new();
 
-        branch.branch(0, Subtree::Node(Box::new(MptNode::Leaf(leaf.clone()))));
+        branch.branch(0, MptNode::Leaf(leaf.clone()).into());
         branch.value = b"verb".to_vec();
 
         let extension = ExtensionNode {
             shared: vec![0, 1, 0, 2, 0, 3, 0, 4],
-            subtree: Subtree::Node(Box::new(branch.into()))
+            subtree: MptNode::from(branch).into()
         };
 
         let node = MptNode::Extension(extension.clone());
@@ -295,4 +464,76 @@ mod test_nodes {
         let decoded = MptNode::from_rlp(&encoded).unwrap();
         assert_eq!(decoded, node);
     }
+
+    #[test]
+    fn test_subtree_key_length() {
+        // a 40 byte string is long enough to be taken as a hash reference
+        let encoded = to_bytes(&Bytes::new(&[0xab; 40])).unwrap();
+        assert!(from_bytes::<Subtree>(&encoded).is_err());
+
+        let encoded = to_bytes(&Bytes::new(&[0xab; 32])).unwrap();
+        assert_eq!(from_bytes::<Subtree>(&encoded).unwrap(), Subtree::NodeKey([0xab; 32]));
+    }
+
+    #[test]
+    fn test_node_kind_mismatch() {
+        let leaf: MptNode = MptNode::Leaf(LeafNode {
+            remained: vec![1, 2, 3],
+            value: b"coin".to_vec()
+        });
+        let (_, encoded) = leaf.encode().unwrap();
+
+        let err = from_bytes::<ExtensionNode>(&encoded).map_err(Error::from).unwrap_err();
+        assert!(matches!(err, Error::EncodingError(_)));
+        // the flag is still honoured when the node type is not known in advance
+        assert_eq!(MptNode::from_rlp(&encoded).unwrap(), leaf);
+    }
+
+    #[test]
+    fn test_non_canonical_node() {
+        // [hex_prefix([1, 2, 3], leaf), 0x05]
+        let canonical = hex::decode("c482312305").unwrap();
+        assert_eq!(MptNode::<KEY_LEN>::from_rlp(&canonical).unwrap(), MptNode::Leaf(LeafNode {
+            remained: vec![1, 2, 3],
+            value: vec![5]
+        }));
+
+        // a short list with its length in the long form
+        let padded_list = hex::decode("f80482312305").unwrap();
+        assert!(MptNode::<KEY_LEN>::from_rlp(&padded_list).is_err());
+        // a single byte below 0x80 wrapped in a string header
+        let padded_value = hex::decode("c58231238105").unwrap();
+        assert!(MptNode::<KEY_LEN>::from_rlp(&padded_value).is_err());
+    }
+
+    #[test]
+    fn test_flatten_path() {
+        use crate::db::MemoryDb;
+
+        let leaf = |remained: Vec<u8>| MptNode::from(LeafNode { remained, value: b"v".to_vec() });
+        let mut fork: BranchNode = BranchNode::new();
+        fork.branch(1, leaf(vec![2, 3]).into());
+        fork.branch(9, leaf(vec![8, 7]).into());
+        let fork = MptNode::from(fork);
+
+        let mut single: BranchNode = BranchNode::new();
+        single.branch(5, fork.clone().into());
+        let chain = MptNode::from(ExtensionNode {
+            shared: vec![0xa, 0xb],
+            subtree: MptNode::from(single).into()
+        });
+
+        let (path, terminal) = TrieNode(chain).flatten_path(&MemoryDb::default()).unwrap();
+        assert_eq!(path.as_slice(), &[0xa, 0xb, 5]);
+        assert_eq!(terminal, TrieNode(fork.clone()));
+
+        // rebuilt in canonical form, with the branch folded into the extension
+        let rebuilt = TrieNode::unflatten_path(&path, terminal);
+        assert_eq!(rebuilt, TrieNode(ExtensionNode { shared: path.into_vec(), subtree: fork.into() }.into()));
+
+        // a leaf is its own terminal
+        let (path, terminal) = TrieNode(leaf(vec![4])).flatten_path(&MemoryDb::default()).unwrap();
+        assert!(path.is_empty());
+        assert_eq!(terminal, TrieNode(leaf(vec![4])));
+    }
 }
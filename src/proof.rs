@@ -1,9 +1,56 @@
-use serde::Serialize;
-use serlp::rlp::to_bytes;
+use core::mem;
 
-use crate::{node::MptNode, mpt::{Database, node_get, KecHash}, hex_prefix::bytes_to_nibbles};
-use crate::error::Result;
+use alloc::{vec::Vec, collections::BTreeMap, sync::Arc, format};
 
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+use serde_bytes::ByteBuf;
+use serlp::rlp::{to_bytes, from_bytes};
+
+use crate::{
+    node::{MptNode, LeafNode, ExtensionNode, BranchNode, Subtree, unshare}, 
+    mpt::{
+        Database, node_get, node_proof, node_subtree_proof, node_insert, root_collapse, 
+        KecHash, keccak256, load_node, db_error, decode_value, branch_index, INLINE_THRESHOLD
+    },
+    hex_prefix::bytes_to_nibbles, db
+};
+use crate::error::{Error, Result, TrieError};
+
+/// Running statistics over generated proofs, 
+/// see [`Trie::get_proof_with_metrics`](crate::mpt::Trie::get_proof_with_metrics).
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ProofMetrics {
+    /// number of proofs recorded
+    pub count: usize,
+    /// number of nodes in all recorded proofs
+    pub total_nodes: usize,
+    /// byte size of all recorded proofs
+    pub total_bytes: usize,
+    /// byte size of the smallest proof
+    pub min_bytes: usize,
+    /// byte size of the largest proof
+    pub max_bytes: usize,
+}
+
+impl ProofMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn record(&mut self, nodes: usize, bytes: usize) {
+        self.min_bytes = if self.count == 0 { bytes } else { self.min_bytes.min(bytes) };
+        self.max_bytes = self.max_bytes.max(bytes);
+        self.count += 1;
+        self.total_nodes += nodes;
+        self.total_bytes += bytes;
+    }
+}
+
+/// Check that `proof` shows `key` to exist under `root_hash`.
+/// 
+/// The whole nibble path of `key` is followed from the root and must be 
+/// consumed exactly at the node holding the value, so a proof of another key 
+/// never verifies, even if it leads to a value.
 pub fn verify_proof<ProofDb, K>(
     root_hash: &KecHash, proof: &ProofDb, key: &K
 ) -> Result<bool>
@@ -11,12 +58,511 @@ where
     K: Serialize,
     ProofDb: Database
 {
-    let rlp_key = to_bytes(key)?;
-    let ikey = bytes_to_nibbles(&rlp_key);
-    Ok(if let Some(rlp) = proof.get(&root_hash)? {
+    verify_proof_raw(root_hash, proof, &to_bytes(key)?)
+}
+
+/// Same as [`verify_proof`], for raw key bytes.
+pub fn verify_proof_raw<ProofDb>(
+    root_hash: &KecHash, proof: &ProofDb, key: &[u8]
+) -> Result<bool>
+where
+    ProofDb: Database
+{
+    Ok(verify_proof_value_raw(root_hash, proof, key)?.is_some())
+}
+
+/// Same as [`verify_proof`], also returning the proven value of `key`, 
+/// or `None` if `proof` does not show `key` to exist. 
+/// Keys that are a prefix of others have their value held by a branch node,
+/// which is returned the same way.
+pub fn verify_proof_value<ProofDb, K, V>(
+    root_hash: &KecHash, proof: &ProofDb, key: &K
+) -> Result<Option<V>>
+where
+    K: Serialize,
+    V: DeserializeOwned,
+    ProofDb: Database
+{
+    let key = to_bytes(key)?;
+    verify_proof_value_raw(root_hash, proof, &key)?
+        .map(|value| decode_value(&key, &value))
+        .transpose()
+}
+
+/// Same as [`verify_proof_value`], for raw key bytes and values.
+pub fn verify_proof_value_raw<ProofDb>(
+    root_hash: &KecHash, proof: &ProofDb, key: &[u8]
+) -> Result<Option<Vec<u8>>>
+where
+    ProofDb: Database
+{
+    let ikey = bytes_to_nibbles(key);
+    Ok(if let Some(rlp) = proof.get(&root_hash).map_err(db_error)? {
         let root = MptNode::from_rlp(&rlp)?;
-        node_get(&root, proof, &ikey)?.is_some()
+        node_get(&root, proof, &ikey)?
     } else {
-        false
+        None
     })
-}
\ No newline at end of file
+}
+
+/// Same as [`verify_proof`], but checks that every node of the proof hashes 
+/// to the reference pointing to it, instead of trusting the keys of `proof`.
+/// A node stored under the wrong hash fails with `TrieError::HashMismatch`.
+pub fn verify_proof_strict<ProofDb, K>(
+    root_hash: &KecHash, proof: &ProofDb, key: &K
+) -> Result<bool>
+where
+    K: Serialize,
+    ProofDb: Database
+{
+    let load = |dbkey: &KecHash| load_checked(proof, dbkey);
+    let root = match load(root_hash) {
+        Ok(root) => root,
+        Err(Error::TrieError(TrieError::SubtreeNotFound)) => return Ok(false),
+        Err(err) => return Err(err)
+    };
+    let ikey = bytes_to_nibbles(&to_bytes(key)?);
+    node_proof(&root, &load, &ikey, &mut |_, _| Ok(()))
+}
+
+/// The nodes of a multiproof stored once, and for each key the indices of 
+/// the nodes on its path, the first one being the root.
+#[derive(Serialize, Deserialize)]
+struct Multiproof {
+    nodes: Vec<ByteBuf>,
+    paths: Vec<MultiproofPath>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct MultiproofPath {
+    #[serde(with = "serde_bytes")]
+    key: Vec<u8>,
+    nodes: Vec<u64>,
+}
+
+/// Encode the proofs of `keys`, the i-th key under `roots[i]`, into a single 
+/// RLP blob. `proof` holds the nodes of all the proofs, e.g. merged from 
+/// [`Trie::get_proof`](crate::mpt::Trie::get_proof). Every node is stored 
+/// once however many paths go through it, with a list of node indices per 
+/// key, so the blob is smaller than the concatenated proofs as soon as 
+/// paths overlap. Embedded nodes are left in their parents.
+/// Fails with `TrieError::SubtreeNotFound` if a node on a path is missing.
+pub fn encode_multiproof<K: Serialize>(
+    proof: &db::ProofDb, roots: &[KecHash], keys: &[K]
+) -> Result<Vec<u8>> {
+    if roots.len() != keys.len() {
+        return Err(Error::EncodingError(format!(
+            "Expected a root per key, found {} roots for {} keys.", roots.len(), keys.len()
+        )))
+    }
+
+    let mut multiproof = Multiproof { nodes: Vec::new(), paths: Vec::new() };
+    let mut indices: BTreeMap<KecHash, u64> = BTreeMap::new();
+    for (root_hash, key) in roots.iter().zip(keys) {
+        let key = to_bytes(key)?;
+        let mut path = Vec::new();
+        let root = load_node(proof, root_hash)?;
+        node_proof(&root, &|dbkey| load_node(proof, dbkey), &bytes_to_nibbles(&key), &mut |hash, rlp| {
+            if hash != *root_hash && rlp.len() < INLINE_THRESHOLD {
+                return Ok(())
+            }
+            let index = *indices.entry(hash).or_insert(multiproof.nodes.len() as u64);
+            if index == multiproof.nodes.len() as u64 {
+                multiproof.nodes.push(ByteBuf::from(rlp));
+            }
+            path.push(index);
+            Ok(())
+        })?;
+        multiproof.paths.push(MultiproofPath { key, nodes: path });
+    }
+    Ok(to_bytes(&multiproof)?)
+}
+
+/// Decode a blob produced by [`encode_multiproof`] into the proof nodes and 
+/// the `(root hash, raw key bytes)` pairs, the keys being RLP encoded. 
+/// Each key can then be checked with [`verify_proof_raw`].
+pub fn decode_multiproof(blob: &[u8]) -> Result<(db::ProofDb, Vec<(KecHash, Vec<u8>)>)> {
+    let multiproof: Multiproof = from_bytes(blob)?;
+    let hashes: Vec<KecHash> = multiproof.nodes.iter().map(|node| keccak256(node)).collect();
+
+    let mut keys = Vec::with_capacity(multiproof.paths.len());
+    for MultiproofPath { key, nodes } in multiproof.paths {
+        let root = nodes.first().and_then(|index| hashes.get(*index as usize));
+        match root {
+            Some(root) if nodes.iter().all(|index| (*index as usize) < hashes.len()) => {
+                keys.push((*root, key))
+            },
+            _ => return Err(Error::EncodingError("Malformed multiproof.".into()))
+        }
+    }
+
+    let mut proof = db::ProofDb::new();
+    for (hash, node) in hashes.iter().zip(multiproof.nodes) {
+        proof.insert(hash, node.into_vec())?;
+    }
+    Ok((proof, keys))
+}
+
+/// Check that `proof` shows the raw key bytes `key` to be absent under 
+/// `root_hash`: the path of `key` must be followed from the root until it 
+/// leaves the trie. Nodes are checked against their hashes as in 
+/// [`verify_proof_strict`], and a proof missing a node on the path proves 
+/// nothing, so false is returned.
+pub fn verify_absence_raw<ProofDb>(
+    root_hash: &KecHash, proof: &ProofDb, key: &[u8]
+) -> Result<bool>
+where
+    ProofDb: Database
+{
+    let load = |dbkey: &KecHash| load_checked(proof, dbkey);
+    let ikey = bytes_to_nibbles(key);
+    match load(root_hash).and_then(|root| node_proof(&root, &load, &ikey, &mut |_, _| Ok(()))) {
+        Ok(exists) => Ok(!exists),
+        Err(Error::TrieError(TrieError::SubtreeNotFound)) => Ok(false),
+        Err(err) => Err(err)
+    }
+}
+
+/// Check that `proof`, from [`Trie::prove_subtree`], shows the node starting at 
+/// the raw key bytes `prefix` under `root_hash` to hash to `subtree_hash`.
+/// Nodes are checked against their hashes as in [`verify_proof_strict`].
+/// A key below `prefix` can then be verified against `subtree_hash` with 
+/// the rest of its bytes.
+/// 
+/// [`Trie::prove_subtree`]: crate::mpt::Trie::prove_subtree
+pub fn verify_subtree_proof<ProofDb>(
+    root_hash: &KecHash, proof: &ProofDb, prefix: &[u8], subtree_hash: &KecHash
+) -> Result<bool>
+where
+    ProofDb: Database
+{
+    let load = |dbkey: &KecHash| load_checked(proof, dbkey);
+    let iprefix = bytes_to_nibbles(prefix);
+    let found = load(root_hash)
+        .and_then(|root| node_subtree_proof(&root, &load, &iprefix, &mut |_, _| Ok(())));
+    match found {
+        Ok(hash) => Ok(hash.as_ref() == Some(subtree_hash)),
+        Err(Error::TrieError(TrieError::SubtreeNotFound)) => Ok(false),
+        Err(err) => Err(err)
+    }
+}
+
+/// load a node of a proof, checking that it hashes to the key it is stored under
+fn load_checked<ProofDb: Database>(proof: &ProofDb, dbkey: &KecHash) -> Result<MptNode> {
+    let rlp = proof.get_ref(dbkey).map_err(db_error)?
+        .ok_or(Error::TrieError(TrieError::SubtreeNotFound))?;
+    if keccak256(&rlp) != *dbkey {
+        return Err(Error::TrieError(TrieError::HashMismatch(dbkey.to_vec())))
+    }
+    MptNode::from_rlp(&rlp)
+}
+
+/// Verify an account proof produced by go-ethereum's `eth_getProof`.
+/// 
+/// `account_proof` holds the RLP encoded nodes on the path from the state root,
+/// which is a secure trie keyed by `keccak256(address)`.
+/// Returns the RLP encoded account if the proof shows it exists.
+pub fn verify_eth_proof(
+    root_hash: &KecHash, account_proof: &[Vec<u8>], address: &[u8; 20]
+) -> Result<Option<Vec<u8>>> {
+    let mut proof = db::ProofDb::new();
+    for node in account_proof {
+        proof.insert(&keccak256(node), node.clone())?;
+    }
+
+    if !proof.exists(root_hash)? {
+        return Ok(None)
+    }
+    let root = load_node(&proof, root_hash)?;
+    let ikey = bytes_to_nibbles(&keccak256(address));
+    node_get(&root, &proof, &ikey)
+}
+
+/// Verify many proofs at once, each given as (root hash, proof nodes, raw key bytes).
+/// 
+/// All proof nodes go into a single pool, so a node shared by several proofs 
+/// is only hashed once. As a consequence a proof may be completed by nodes 
+/// from the other proofs in the batch.
+/// Returns whether each key is proven to exist under its root.
+pub fn verify_proofs(items: &[(KecHash, Vec<Vec<u8>>, Vec<u8>)]) -> Result<Vec<bool>> {
+    let mut pool = db::ProofDb::new();
+    let mut hashed: BTreeMap<&[u8], KecHash> = BTreeMap::new();
+    for (_, nodes, _) in items {
+        for node in nodes {
+            if !hashed.contains_key(node.as_slice()) {
+                let hash = keccak256(node);
+                hashed.insert(node, hash);
+                pool.insert(&hash, node.clone())?;
+            }
+        }
+    }
+
+    items.iter().map(|(root_hash, _, key)| {
+        let ikey = bytes_to_nibbles(key);
+        let found = load_node(&pool, root_hash)
+            .and_then(|root| node_get(&root, &pool, &ikey));
+        match found {
+            Ok(value) => Ok(value.is_some()),
+            // an incomplete proof proves nothing
+            Err(Error::TrieError(TrieError::SubtreeNotFound)) => Ok(false),
+            Err(err) => Err(err)
+        }
+    }).collect()
+}
+
+/// Check a range of entries served with 
+/// [`Trie::serve_range`](crate::mpt::Trie::serve_range) against `root_hash`.
+/// 
+/// `entries` must be every entry of the trie with a key at or after `origin`
+/// up to the last one returned, in key order. Keys are raw key bytes.
+/// Returns `None` if this is not proven, otherwise whether the trie holds 
+/// more keys after the last entry.
+/// 
+/// Every entry inside the range is dropped from the trie rebuilt from the 
+/// boundary proofs, the entries are inserted back, and the root must come 
+/// out unchanged. So a missing, extra or modified entry is detected.
+pub fn verify_range_proof<ProofDb>(
+    root_hash: &KecHash, origin: &[u8], entries: &[(Vec<u8>, Vec<u8>)], proof: &ProofDb
+) -> Result<Option<bool>>
+where
+    ProofDb: Database
+{
+    let sorted = entries.windows(2).all(|pair| pair[0].0 < pair[1].0);
+    let after_origin = entries.first().map_or(true, |(key, _)| key.as_slice() >= origin);
+    // an empty value cannot be stored
+    if !sorted || !after_origin || entries.iter().any(|(_, value)| value.is_empty()) {
+        return Ok(None)
+    }
+
+    let left = bytes_to_nibbles(origin);
+    // the entries may run up to the end of the trie
+    if range_matches(root_hash, &left, None, entries, proof)? {
+        return Ok(Some(false))
+    }
+    if let Some((last, _)) = entries.last() {
+        let right = bytes_to_nibbles(last);
+        if range_matches(root_hash, &left, Some(&right), entries, proof)? {
+            return Ok(Some(true))
+        }
+    }
+    Ok(None)
+}
+
+/// whether the trie rebuilt from the proof, with every key between left and
+/// right (inclusive, unbounded if None) replaced by entries, has the root hash
+fn range_matches<ProofDb>(
+    root_hash: &KecHash, left: &[u8], right: Option<&[u8]>, 
+    entries: &[(Vec<u8>, Vec<u8>)], proof: &ProofDb
+) -> Result<bool>
+where
+    ProofDb: Database
+{
+    let rebuild = || -> Result<Option<KecHash>> {
+        let mut root = match range_unset(load_node(proof, root_hash)?, Some(left), right, proof)? {
+            Subtree::Node(node) => Some(unshare(node)),
+            _ => None
+        };
+        // nodes left in the range are removed, so nothing is loaded from here
+        let mut db = db::ProofDb::new();
+        for (key, value) in entries {
+            let ikey = bytes_to_nibbles(key);
+            root = Some(match root {
+                Some(root) => node_insert(root, &mut db, &ikey, value.clone())?,
+                None => LeafNode { remained: ikey, value: value.clone() }.into()
+            });
+        }
+        Ok(match root {
+            Some(root) => Some(root_collapse(&root, INLINE_THRESHOLD, &mut |_, _| Ok(()))?.0),
+            None => None
+        })
+    };
+
+    match rebuild() {
+        Ok(hash) => Ok(hash.as_ref() == Some(root_hash)),
+        // an incomplete proof proves nothing
+        Err(Error::TrieError(TrieError::SubtreeNotFound)) => Ok(false),
+        Err(err) => Err(err)
+    }
+}
+
+/// where the keys below a path lie relative to a bound of a range
+enum Side<'a> {
+    /// all of them are out of the range
+    Outside,
+    /// all of them are in the range, as far as this bound is concerned
+    Inside,
+    /// the bound runs below the path, with these nibbles remaining
+    Straddle(&'a [u8]),
+}
+
+impl<'a> Side<'a> {
+    fn of(path: &[u8], bound: Option<&'a [u8]>, is_left: bool) -> Self {
+        let bound = match bound {
+            Some(bound) => bound,
+            None => return Side::Inside
+        };
+        let common = path.iter().zip(bound).take_while(|(a, b)| a == b).count();
+        if common == path.len() {
+            // every key below the path is at least the left bound
+            if is_left && common == bound.len() {
+                return Side::Inside
+            }
+            return Side::Straddle(&bound[common..])
+        }
+        // the keys below are greater than the bound
+        let greater = common == bound.len() || path[common] > bound[common];
+        if greater == is_left { Side::Inside } else { Side::Outside }
+    }
+
+    fn bound(&self) -> Option<&'a [u8]> {
+        match self {
+            Side::Straddle(bound) => Some(*bound),
+            _ => None
+        }
+    }
+}
+
+/// drop every key between left and right (inclusive, unbounded if None) 
+/// from a node reached by both bounds, loading the nodes on the bounds' paths
+fn range_unset<ProofDb>(
+    root: MptNode, left: Option<&[u8]>, right: Option<&[u8]>, proof: &ProofDb
+) -> Result<Subtree>
+where
+    ProofDb: Database
+{
+    let node = match root {
+        MptNode::Leaf(leaf) => {
+            let after_left = left.map_or(true, |left| leaf.remained.as_slice() >= left);
+            let before_right = right.map_or(true, |right| leaf.remained.as_slice() <= right);
+            if after_left && before_right {
+                return Ok(Subtree::Empty)
+            }
+            leaf.into()
+        },
+        MptNode::Extension(ExtensionNode { shared, subtree }) => {
+            let (l, r) = (Side::of(&shared, left, true), Side::of(&shared, right, false));
+            match subtree_range_unset(subtree, l, r, proof)? {
+                // the whole extension is in the range
+                Subtree::Empty => return Ok(Subtree::Empty),
+                subtree => ExtensionNode { shared, subtree }.into()
+            }
+        },
+        MptNode::Branch(BranchNode { branchs, mut value }) => {
+            // the key ending here is a prefix of the right bound, so only the left one matters
+            if left.map_or(true, |left| left.is_empty()) {
+                value = Vec::new();
+            }
+            let mut node: BranchNode = BranchNode::new();
+            node.value = value;
+            for (idx, subtree) in IntoIterator::into_iter(branchs).enumerate() {
+                let path = [idx as u8];
+                let (l, r) = (Side::of(&path, left, true), Side::of(&path, right, false));
+                node.branch(idx, subtree_range_unset(subtree, l, r, proof)?);
+            }
+            node.into()
+        }
+    };
+    Ok(Subtree::Node(Arc::new(node)))
+}
+
+fn subtree_range_unset<ProofDb>(
+    subtree: Subtree, left: Side, right: Side, proof: &ProofDb
+) -> Result<Subtree>
+where
+    ProofDb: Database
+{
+    match (&left, &right) {
+        (Side::Outside, _) | (_, Side::Outside) => Ok(subtree),
+        (Side::Inside, Side::Inside) => Ok(Subtree::Empty),
+        _ => match subtree {
+            Subtree::Empty => Ok(Subtree::Empty),
+            Subtree::Node(node) => range_unset(unshare(node), left.bound(), right.bound(), proof),
+            Subtree::NodeKey(dbkey) => {
+                range_unset(load_node(proof, &dbkey)?, left.bound(), right.bound(), proof)
+            }
+        }
+    }
+}
+
+/// The outcome of feeding a node to a [`ProofVerifier`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Step {
+    /// the node with this hash is needed next
+    NeedMore(KecHash),
+    /// the key exists with this value
+    Found(Vec<u8>),
+    /// the key is proven not to exist
+    Absent,
+}
+
+/// Verifies a proof one node at a time, in root-to-leaf order, 
+/// so that the whole proof never needs to be held in memory.
+/// 
+/// Each node fed must hash to the reference reached so far, 
+/// otherwise `TrieError::HashMismatch` is returned.
+#[derive(Debug, Clone)]
+pub struct ProofVerifier {
+    /// `None` once the verification reached a result
+    expected: Option<KecHash>,
+    ikey: Vec<u8>,
+    /// number of nibbles of `ikey` consumed so far
+    pos: usize,
+}
+
+impl ProofVerifier {
+    pub fn new<K: Serialize>(root_hash: KecHash, key: &K) -> Result<Self> {
+        Ok(Self::new_raw(root_hash, &to_bytes(key)?))
+    }
+
+    /// Same as [`ProofVerifier::new`], for raw key bytes.
+    pub fn new_raw(root_hash: KecHash, key: &[u8]) -> Self {
+        Self { expected: Some(root_hash), ikey: bytes_to_nibbles(key), pos: 0 }
+    }
+
+    /// Check the next node of the proof and follow the key through it.
+    pub fn feed(&mut self, node: &[u8]) -> Result<Step> {
+        let expected = self.expected.take().ok_or_else(|| Error::EncodingError(
+            "The proof is already verified.".into()
+        ))?;
+        if keccak256(node) != expected {
+            return Err(Error::TrieError(TrieError::HashMismatch(expected.to_vec())))
+        }
+
+        let mut node: MptNode = MptNode::from_rlp(node)?;
+        loop {
+            let remained = &self.ikey[self.pos..];
+            let subtree = match node {
+                MptNode::Leaf(LeafNode { remained: path, value }) => {
+                    return Ok(if path == remained { Step::Found(value) } else { Step::Absent })
+                },
+                MptNode::Extension(ExtensionNode { shared, subtree }) => {
+                    if !remained.starts_with(&shared) {
+                        return Ok(Step::Absent)
+                    }
+                    self.pos += shared.len();
+                    subtree
+                },
+                MptNode::Branch(BranchNode { mut branchs, value }) => {
+                    match remained.first() {
+                        None if value.is_empty() => return Ok(Step::Absent),
+                        None => return Ok(Step::Found(value)),
+                        Some(&nibble) => {
+                            self.pos += 1;
+                            mem::replace(&mut branchs[branch_index(nibble)?], Subtree::Empty)
+                        }
+                    }
+                }
+            };
+
+            node = match subtree {
+                Subtree::Empty => return Ok(Step::Absent),
+                // embedded nodes are part of the bytes already checked
+                Subtree::Node(child) => unshare(child),
+                Subtree::NodeKey(hash) => {
+                    self.expected = Some(hash);
+                    return Ok(Step::NeedMore(hash))
+                }
+            }
+        }
+    }
+}
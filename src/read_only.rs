@@ -0,0 +1,66 @@
+//! A read-only view over a committed trie.
+
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::{mpt::{Trie, Database, KecHash, KEY_LEN}, error::Result};
+
+/// A committed [`Trie`] that only exposes reads, obtained with 
+/// [`Trie::into_read_only`]. Taking it in a function signature makes it 
+/// explicit that the trie is never written to.
+/// 
+/// ```compile_fail
+/// use mpt_rs::{mpt::{Trie, Database}, db::MemoryDb};
+/// 
+/// let trie: Trie<MemoryDb, u64, u64> = Trie::new(MemoryDb::new());
+/// let trie = trie.into_read_only().unwrap();
+/// trie.insert(&1, &1);
+/// ```
+pub struct ReadOnlyTrie<Db, K, V, const N: usize = KEY_LEN>
+where
+    Db: Database<N>,
+    K: Serialize,
+    V: Serialize + DeserializeOwned
+{
+    inner: Trie<Db, K, V, N>
+}
+
+impl<Db, K, V, const N: usize> ReadOnlyTrie<Db, K, V, N>
+where
+    Db: Database<N>,
+    K: Serialize,
+    V: Serialize + DeserializeOwned
+{
+    pub(crate) fn new(inner: Trie<Db, K, V, N>) -> Self {
+        Self { inner }
+    }
+
+    pub fn root_hash(&self) -> Option<KecHash<N>> {
+        self.inner.root_hash()
+    }
+
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        self.inner.get(key)
+    }
+
+    pub fn get_raw(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.inner.get_raw(key)
+    }
+
+    pub fn contains_key(&self, key: &K) -> Result<bool> {
+        self.inner.contains_key(key)
+    }
+
+    pub fn get_proof<ProofDb: Database<N>>(&self, key: &K) -> Result<(ProofDb, bool)> {
+        self.inner.get_proof_no_commit(key)
+    }
+
+    /// see [`Trie::entries_raw`]
+    pub fn entries_raw(&self) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.inner.entries_raw()
+    }
+
+    /// Give back the underlying trie, e.g. to start writing to it again.
+    pub fn into_inner(self) -> Trie<Db, K, V, N> {
+        self.inner
+    }
+}
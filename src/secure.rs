@@ -0,0 +1,112 @@
+//! A secure trie stores every value under the keccak256 hash of its key,
+//! as Ethereum does for the state and storage tries.
+
+use std::marker::PhantomData;
+
+use serde::{Serialize, de::DeserializeOwned};
+use serlp::rlp::to_bytes;
+
+use crate::{
+    mpt::{Trie, Database, KecHash, keccak256, db_error, decode_value},
+    proof::verify_absence_raw,
+    error::Result
+};
+
+/// A [`Trie`] keyed by `keccak256(key)`, where keys are raw bytes such as 
+/// addresses or storage slots.
+/// 
+/// The hashed keys cannot be turned back into the original ones, so the 
+/// trie can optionally record the preimages of inserted keys in a separate 
+/// database, like go-ethereum's preimage store. This is off by default 
+/// since it doubles the storage needed for keys.
+pub struct SecureTrie<Db, K, V>
+where
+    Db: Database,
+    K: AsRef<[u8]>,
+    V: Serialize + DeserializeOwned
+{
+    trie: Trie<Db, KecHash, V>,
+    preimages: Option<Db>,
+    _k: PhantomData<K>
+}
+
+impl<Db, K, V> SecureTrie<Db, K, V>
+where
+    Db: Database,
+    K: AsRef<[u8]>,
+    V: Serialize + DeserializeOwned
+{
+    pub fn new(db: Db) -> Self {
+        Self {
+            trie: Trie::new(db),
+            preimages: None,
+            _k: PhantomData::default()
+        }
+    }
+
+    /// Create a secure trie that records key preimages into `preimages`.
+    pub fn with_preimages(db: Db, preimages: Db) -> Self {
+        Self {
+            preimages: Some(preimages),
+            ..Self::new(db)
+        }
+    }
+
+    pub fn insert(mut self, key: &K, value: &V) -> Result<Self> {
+        let hashed = keccak256(key.as_ref());
+        if let Some(preimages) = &mut self.preimages {
+            preimages.insert(&hashed, key.as_ref().to_vec()).map_err(db_error)?;
+        }
+        self.trie = self.trie.insert_raw(&hashed, to_bytes(value)?)?;
+        Ok(self)
+    }
+
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        let hashed = keccak256(key.as_ref());
+        Ok(if let Some(value) = self.trie.get_raw(&hashed)? {
+            Some(decode_value(key.as_ref(), &value)?)
+        } else {
+            None
+        })
+    }
+
+    /// A proof for the hashed `key`, committing pending changes first, and 
+    /// whether the key exists. Check it with [`verify_absence`] or with 
+    /// [`verify_proof_raw`](crate::proof::verify_proof_raw) on `keccak256(key)`.
+    pub fn get_proof<ProofDb: Database>(&mut self, key: &K) -> Result<(ProofDb, bool)> {
+        self.trie.get_proof_raw(&keccak256(key.as_ref()))
+    }
+
+    /// The original key of a hashed key, if preimages are recorded and the 
+    /// key was inserted through this trie.
+    pub fn preimage(&self, hashed: &KecHash) -> Result<Option<Vec<u8>>> {
+        Ok(match &self.preimages {
+            Some(preimages) => preimages.get(hashed).map_err(db_error)?,
+            None => None
+        })
+    }
+
+    pub fn root_hash(&self) -> Option<KecHash> {
+        self.trie.root_hash()
+    }
+
+    pub fn commit(&mut self) -> Result<Option<KecHash>> {
+        self.trie.commit()
+    }
+
+    /// The underlying trie, keyed by the hashed keys.
+    pub fn trie(&self) -> &Trie<Db, KecHash, V> {
+        &self.trie
+    }
+}
+
+/// Check that `proof` shows `key` to be absent from the secure trie with 
+/// root `root_hash`, e.g. that an account does not exist. 
+/// See [`verify_absence_raw`], applied to `keccak256(key)`.
+pub fn verify_absence<ProofDb, K>(root_hash: &KecHash, proof: &ProofDb, key: &K) -> Result<bool>
+where
+    ProofDb: Database,
+    K: AsRef<[u8]>
+{
+    verify_absence_raw(root_hash, proof, &keccak256(key.as_ref()))
+}
@@ -0,0 +1,112 @@
+//! Build a trie from keys in ascending order in a single pass, 
+//! like go-ethereum's `StackTrie`.
+
+use std::sync::Arc;
+
+use crate::{
+    mpt::{
+        Database, KecHash, INLINE_THRESHOLD, node_insert, root_collapse, subtree_collapse, db_error, branch_index
+    },
+    node::{MptNode, LeafNode, ExtensionNode, BranchNode, Subtree},
+    hex_prefix::bytes_to_nibbles,
+    error::{Error, Result, TrieError}
+};
+
+/// Computes the root of a trie from `(key, value)` pairs pushed in strictly 
+/// ascending key order.
+/// 
+/// Once a key is pushed, every subtree left of its path is complete, so it 
+/// is written to the database right away. Only the path of the last key is 
+/// kept in memory. The root hash equals the one of a [`Trie`](crate::mpt::Trie)
+/// holding the same entries.
+pub struct StackTrie<Db>
+where
+    Db: Database
+{
+    root: Option<MptNode>,
+    db: Db,
+    last: Option<Vec<u8>>,
+}
+
+impl<Db> StackTrie<Db>
+where
+    Db: Database
+{
+    pub fn new(db: Db) -> Self {
+        Self { root: None, db, last: None }
+    }
+
+    /// Push raw key and value bytes, the key must be greater than all 
+    /// previously pushed ones, otherwise `TrieError::UnorderedKey` is returned.
+    pub fn push(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        if let Some(last) = &self.last {
+            if key <= last.as_slice() {
+                return Err(Error::TrieError(TrieError::UnorderedKey))
+            }
+        }
+
+        let ikey = bytes_to_nibbles(key);
+        let root = match self.root.take() {
+            Some(root) => node_insert(root, &mut self.db, &ikey, value)?,
+            None => LeafNode { remained: ikey.clone(), value }.into()
+        };
+        self.root = Some(root);
+        if let Some(root) = &mut self.root {
+            node_seal(root, &mut self.db, &ikey)?;
+        }
+        self.last = Some(key.to_vec());
+        Ok(())
+    }
+
+    /// Write the remaining nodes and return the root hash, 
+    /// together with the database holding all nodes.
+    pub fn finalize(mut self) -> Result<(Option<KecHash>, Db)> {
+        let root_hash = match self.root {
+            // the root is always stored, however small it is
+            Some(root) => Some(root_collapse(&root, INLINE_THRESHOLD, &mut |dbkey, rlp| {
+                self.db.insert(&dbkey, rlp).map_err(db_error)
+            })?.0),
+            None => None
+        };
+        Ok((root_hash, self.db))
+    }
+}
+
+/// collapse the subtrees left of the path of ikey, no more keys can go there
+fn node_seal<Db>(root: &mut MptNode, db: &mut Db, ikey: &[u8]) -> Result<()>
+where
+    Db: Database
+{
+    match root {
+        MptNode::Leaf(_) => Ok(()),
+        MptNode::Extension(ExtensionNode { shared, subtree }) => {
+            let ikey = ikey.get(shared.len()..).unwrap_or_default();
+            subtree_seal(subtree, db, ikey)
+        },
+        MptNode::Branch(BranchNode { branchs, .. }) => {
+            // a key ending at this branch is smaller than all of its children
+            if ikey.is_empty() {
+                return Ok(())
+            }
+            let idx = branch_index(ikey[0])?;
+            // subtrees before the previous sibling were sealed by earlier pushes
+            if let Some(left) = (0..idx).rev().find(|i| !matches!(branchs[*i], Subtree::Empty)) {
+                branchs[left] = subtree_collapse(&branchs[left], INLINE_THRESHOLD, &mut |dbkey, rlp| {
+                    db.insert(&dbkey, rlp).map_err(db_error)
+                })?;
+            }
+            subtree_seal(&mut branchs[idx], db, &ikey[1..])
+        }
+    }
+}
+
+fn subtree_seal<Db>(subtree: &mut Subtree, db: &mut Db, ikey: &[u8]) -> Result<()>
+where
+    Db: Database
+{
+    match subtree {
+        Subtree::Node(node) => node_seal(Arc::make_mut(node), db, ikey),
+        // inserting loads every node on the path of the key into memory
+        Subtree::NodeKey(_) | Subtree::Empty => Ok(())
+    }
+}
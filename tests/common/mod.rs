@@ -0,0 +1,21 @@
+//! Fixtures shared by the integration tests.
+#![allow(dead_code)]
+
+use mpt_rs::mpt::{Database, Trie};
+
+/// Insert the keys `0..n`, all with the same 40 byte value, 
+/// which is long enough for every leaf to be stored under its hash.
+pub fn fill<Db: Database>(mut trie: Trie<Db, u64, String>, n: u64) -> Trie<Db, u64, String> {
+    for i in 0..n {
+        trie = trie.insert(&i, &"v".repeat(40)).unwrap();
+    }
+    trie
+}
+
+/// Same as [`fill`], but every value tells which key it belongs to.
+pub fn fill_numbered<Db: Database>(mut trie: Trie<Db, u64, String>, n: u64) -> Trie<Db, u64, String> {
+    for i in 0..n {
+        trie = trie.insert(&i, &format!("value-{:040}", i)).unwrap();
+    }
+    trie
+}
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::{cell::Cell, collections::HashMap};
 
 use mpt_rs::{mpt::{Trie, Database, KecHash}, proof::verify_proof};
 use serde::{Deserialize, Serialize};
@@ -8,6 +8,8 @@ use serlp::types::{biguint, byte_array};
 use hex;
 use mpt_rs::error::Result;
 
+mod common;
+
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
 struct LegacyTx {
     nonce: u64,
@@ -124,6 +126,22 @@ fn test_revert() {
     assert_eq!(a, trie.get(&"aaaa").unwrap().unwrap());
 }
 
+#[test]
+fn test_revert_missing_state() {
+    use mpt_rs::error::Error;
+
+    let mut trie: Trie<MapDb, _, _> = Trie::new(MapDb::new());
+    trie = trie.insert(&"aaaa", &"a".repeat(40)).unwrap();
+    trie.commit().unwrap();
+
+    let missing = [0x5a; 32];
+    match trie.revert(missing) {
+        Err(Error::StateNotFound(hash)) => assert_eq!(hash, missing),
+        Err(err) => panic!("unexpected error {:?}", err),
+        Ok(_) => panic!("reverted to a missing state")
+    }
+}
+
 #[test]
 fn test_proof() {
     let a = "a".to_string().repeat(1);
@@ -160,6 +178,8 @@ fn test_proof() {
 struct MapDb(HashMap<KecHash, Vec<u8>>);
 
 impl Database for MapDb {
+    type Error = mpt_rs::error::Error;
+
     fn new() -> Self {
         Self(HashMap::new())
     }
@@ -177,3 +197,887 @@ impl Database for MapDb {
         Ok(self.0.get(key).cloned())
     }
 }
+
+#[test]
+fn test_eth_proof() {
+    use sha3::{Keccak256, Digest};
+    use mpt_rs::proof::verify_eth_proof;
+
+    let keccak = |data: &[u8]| -> KecHash { Keccak256::digest(data).into() };
+
+    let address: [u8; 20] = hex::decode("b47e3cd837ddf8e4c57f05d70ab865de6e193bbb")
+        .unwrap().try_into().unwrap();
+    // [nonce = 1, balance = 1 ether, storage root = empty, code hash = keccak256("")]
+    let account = hex::decode(concat!(
+        "f84c01880de0b6b3a7640000",
+        "a056e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421",
+        "a0c5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470"
+    )).unwrap();
+
+    // a state trie holding a single account is a single leaf node:
+    // [hex_prefix(keccak256(address), leaf), account]
+    let mut leaf = hex::decode("f872a120").unwrap();
+    leaf.extend_from_slice(&keccak(&address));
+    leaf.extend_from_slice(&[0xb8, account.len() as u8]);
+    leaf.extend_from_slice(&account);
+    let state_root = keccak(&leaf);
+
+    let proof = vec![leaf];
+    assert_eq!(verify_eth_proof(&state_root, &proof, &address).unwrap(), Some(account));
+
+    // an address not in the trie
+    let mut other = address;
+    other[0] ^= 1;
+    assert_eq!(verify_eth_proof(&state_root, &proof, &other).unwrap(), None);
+
+    // a proof that does not start from the claimed root
+    let mut wrong_root = state_root;
+    wrong_root[0] ^= 1;
+    assert_eq!(verify_eth_proof(&wrong_root, &proof, &address).unwrap(), None);
+}
+
+#[test]
+fn test_eth_proof_goerli_genesis() {
+    use mpt_rs::proof::verify_eth_proof;
+
+    // the state of the Görli genesis block, as returned by `eth_getProof` at block 0
+    let state_root: KecHash = hex::decode("5d6cded585e73c4e322c30c2f782a336316f17dd85a4863b9d838d2d4b8b3008")
+        .unwrap().try_into().unwrap();
+    let decode = |nodes: Vec<&str>| -> Vec<Vec<u8>> {
+        nodes.into_iter().map(|node| hex::decode(node).unwrap()).collect()
+    };
+
+    // a pre-funded account: [nonce = 0, balance = 100000 ether, empty storage root, empty code hash]
+    let address: [u8; 20] = hex::decode("4c2ae482593505f0163cdefc073e81c63cda4107")
+        .unwrap().try_into().unwrap();
+    let account = hex::decode(concat!(
+        "f84e808a152d02c7e14af6800000",
+        "a056e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421",
+        "a0c5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470"
+    )).unwrap();
+    let account_proof = decode(vec![
+        concat!(
+            "f90211a0a2dcc6cbcf6a5d15a556cb0543eb6d4bc30b2623482aed8b1c1a4deae1f0723ba0464b07429a05039e229314",
+            "92d6c6251a860c018ea390045d596b1ac11b5c7aa7a0382fbb965c19798b116e1b32ad64d99bdf09f8f4ed4c83e1b388",
+            "ffad0ee8bc62a0b5f7c51c3b2d51d97f171d2b38a4df1a7c0acc5eb0de46beeff4d07f5ed20e19a0466f6adac3e982cc",
+            "e4b8ff7f5dfa30732d4af63c34f48c467abc5c33be2405eba08ae1268e7dac7541934b44cc2f91c1bae4870d2f49ab73",
+            "65810a4f7bb317c8c6a0cf7159e09fc6bf2cfdc89b2e534fb8ac079137d9f5f55b823f8d574cc1c7f0caa0c2f16143c4",
+            "d1db03276c433696dddb3e9f3b113bcd854b127962262e98f43147a0828820316cc02bfefd899aba41340659fd06df1e",
+            "0a0796287ec2a4110239f6d2a0be88e4724326382a8b56e2328eeef0ad51f18d5bae0e84296afe14c4028c4af9a0c14e",
+            "9060c6b3784e35b9e6ae2ad2984142a75910ccc89eb89dc1e2f44b6c58c2a06c16627ec10f5381bfa639a01e8dd03d8b",
+            "0392afb3aca6c524c525c17ccbc66da0b389a19ff0bc587c3224bc15851de5956e08339d95e161bd5cfb6168fbfb736a",
+            "a0899f71abb18c6c956118bf567fac629b75f7e9526873e429d3d8abb6dbb58021a00fd717235298742623c0b3cafb3e",
+            "4bd86c0b5ab1f71097b4dd19f3d6925d758da02c2dec9fc84b66cb56f42729a57ed97f204843794db7ba6713f5d1723c",
+            "6b219480",
+        ),
+        concat!(
+            "f90171a046a9f1217c365990825b7d161fc23cae5688cfb6b2307efe4b732c723e03795880a0c0e0b54cb105bad41b4b",
+            "925883507463ddfae71c619ba2e41d6d57da2a28effea0793c9db0e252f8f5c79a9d872efc5385ab632a9dc31217637b",
+            "3509fcf6f0b010a077c059a2b360e9c967686a1302a40994cd63a81aa80a841991d8f3d7379b68eb80a0386a1e942dbe",
+            "86342b17e2e8b28a259d6db65df8e05f944951a089bb9f3d989fa05739e60589ed3f36586d1f8806d55091356a7f4e28",
+            "c25f42251218a4d47ab7c7a066e01acc8a9826bc3d5f5286819fc5883dfa30943331f1e7ff2968bfc57ea2d0a00f7041",
+            "c0b666de2c820d816b27347738f0e8e2d4d7e1e94e2908b88bc3665a338080a012794aea34d39f220863a2977506ebe5",
+            "555c2b6488a9469fed918b744f67d6d9a0e5fdd2cd23dcc8b76c98c662674a47ab574fa93f23724caac875af223670d4",
+            "eca0965f0a51c00021b05153bd406f1f65a59ed6c6bb11cb1c30760f5471c2c5b83f8080",
+        ),
+        concat!(
+            "f8518080808080808080808080a0680feca724e6135ce3de08c6eb8ae3c9753db3f3c851ce53f478778822c24feca003",
+            "94f3bd9d9bc69d3b5431973f725bf7dd946743c6ee79a0df1c0ecbd0155c8480808080",
+        ),
+        concat!(
+            "f8729f3556dc1dd5668cd2b40bc897961b1198685102008091d1a8d2c994332ef5eeb850f84e808a152d02c7e14af680",
+            "0000a056e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421a0c5d2460186f7233c927e7db2",
+            "dcc703c0e500b653ca82273b7bfad8045d85a470",
+        ),
+    ]);
+    assert_eq!(verify_eth_proof(&state_root, &account_proof, &address).unwrap(), Some(account));
+
+    // 0x0000000000000000000000000000000000000100 was not funded, 
+    // its proof ends at the branch where its path leaves the trie
+    let mut absent = [0; 20];
+    absent[18] = 1;
+    let absent_proof = decode(vec![
+        concat!(
+            "f90211a0a2dcc6cbcf6a5d15a556cb0543eb6d4bc30b2623482aed8b1c1a4deae1f0723ba0464b07429a05039e229314",
+            "92d6c6251a860c018ea390045d596b1ac11b5c7aa7a0382fbb965c19798b116e1b32ad64d99bdf09f8f4ed4c83e1b388",
+            "ffad0ee8bc62a0b5f7c51c3b2d51d97f171d2b38a4df1a7c0acc5eb0de46beeff4d07f5ed20e19a0466f6adac3e982cc",
+            "e4b8ff7f5dfa30732d4af63c34f48c467abc5c33be2405eba08ae1268e7dac7541934b44cc2f91c1bae4870d2f49ab73",
+            "65810a4f7bb317c8c6a0cf7159e09fc6bf2cfdc89b2e534fb8ac079137d9f5f55b823f8d574cc1c7f0caa0c2f16143c4",
+            "d1db03276c433696dddb3e9f3b113bcd854b127962262e98f43147a0828820316cc02bfefd899aba41340659fd06df1e",
+            "0a0796287ec2a4110239f6d2a0be88e4724326382a8b56e2328eeef0ad51f18d5bae0e84296afe14c4028c4af9a0c14e",
+            "9060c6b3784e35b9e6ae2ad2984142a75910ccc89eb89dc1e2f44b6c58c2a06c16627ec10f5381bfa639a01e8dd03d8b",
+            "0392afb3aca6c524c525c17ccbc66da0b389a19ff0bc587c3224bc15851de5956e08339d95e161bd5cfb6168fbfb736a",
+            "a0899f71abb18c6c956118bf567fac629b75f7e9526873e429d3d8abb6dbb58021a00fd717235298742623c0b3cafb3e",
+            "4bd86c0b5ab1f71097b4dd19f3d6925d758da02c2dec9fc84b66cb56f42729a57ed97f204843794db7ba6713f5d1723c",
+            "6b219480",
+        ),
+        concat!(
+            "f90111a0620752d6bea8de401a8c510032e39222b65b041db368434fcc14a225eb1b85cba0d824ad267b926051a297bd",
+            "f654a1edbe5faa6d0df67a3dc0ab774b73fd07617c808080a084738c7684996d2818b5afb5c90a43b7c77fe79a7979f7",
+            "e99bbd0f0b5088d7cea081ccbba21d7cec421a6cf72d42653f93c5aa68dd7c6dec8ccdb31362dc616f5b8080a00b223f",
+            "60e135cec31dbd69a809d9b93c5cd1ac17600d9be3301b35c339cd250580a03cca1eb0ee4bbf953527c1da5f3bc3f76c",
+            "aceb445acd5bd73a1177a19e2d76fb80a0ee404b58868dd330132e9015883deb72d80aefa4814784a69d7295b9b1c60c",
+            "d580a08abe8247fce754b95926e9217ec2ee42030eb22c08a7808f452d386b14b055b180",
+        ),
+    ]);
+    assert_eq!(verify_eth_proof(&state_root, &absent_proof, &absent).unwrap(), None);
+}
+
+#[test]
+fn test_eth_proof_malformed_nodes() {
+    use sha3::{Keccak256, Digest};
+    use mpt_rs::proof::verify_eth_proof;
+
+    let address = [0x11; 20];
+    // a list of 3 items, a byte string, and a leaf with an empty path
+    for node in ["c3010203", "83010203", "c28001"] {
+        let node = hex::decode(node).unwrap();
+        let root: KecHash = Keccak256::digest(&node).into();
+        assert!(verify_eth_proof(&root, &[node], &address).is_err());
+    }
+}
+
+#[test]
+fn test_proof_metrics() {
+    use mpt_rs::{db::MemoryDb, proof::ProofMetrics};
+
+    let mut trie: Trie<MemoryDb, _, _> = Trie::new(MemoryDb::new());
+    for i in 0..100u64 {
+        trie = trie.insert(&i, &"v".repeat(i as usize)).unwrap();
+    }
+
+    let mut metrics = ProofMetrics::new();
+    let (mut nodes, mut bytes, mut min, mut max) = (0, 0, usize::MAX, 0);
+    for i in [0u64, 7, 42, 99, 1000] {
+        let (proof, _) = trie.get_proof_with_metrics::<MemoryDb>(&i, Some(&mut metrics)).unwrap();
+        let size: usize = proof.iter().map(|(_, node)| node.len()).sum();
+        nodes += proof.len();
+        bytes += size;
+        min = min.min(size);
+        max = max.max(size);
+    }
+
+    assert_eq!(metrics.count, 5);
+    assert_eq!(metrics.total_nodes, nodes);
+    assert_eq!(metrics.total_bytes, bytes);
+    assert_eq!(metrics.min_bytes, min);
+    assert_eq!(metrics.max_bytes, max);
+}
+
+#[test]
+fn test_estimate_proof_size() {
+    use mpt_rs::{db::MemoryDb, proof::ProofMetrics};
+
+    let mut trie: Trie<MemoryDb, _, _> = Trie::new(MemoryDb::new());
+    for i in 0..100u64 {
+        trie = trie.insert(&i, &"v".repeat(i as usize)).unwrap();
+    }
+    // estimated before the commit done by get_proof
+    let estimates: Vec<usize> = [0u64, 7, 42, 99, 1000].iter()
+        .map(|i| trie.estimate_proof_size(i).unwrap())
+        .collect();
+
+    for (i, estimate) in [0u64, 7, 42, 99, 1000].iter().zip(estimates) {
+        let mut metrics = ProofMetrics::new();
+        let (proof, _) = trie.get_proof_with_metrics::<MemoryDb>(i, Some(&mut metrics)).unwrap();
+        let size: usize = proof.iter().map(|(_, node)| node.len()).sum();
+        assert_eq!(estimate, size);
+        assert_eq!(estimate, metrics.total_bytes);
+        assert_eq!(trie.estimate_proof_size(i).unwrap(), size);
+    }
+
+    // a change on top of committed state only re-encodes the modified path
+    trie = trie.insert(&7, &"w".repeat(300)).unwrap();
+    let estimate = trie.estimate_proof_size(&7).unwrap();
+    let (proof, _) = trie.get_proof::<MemoryDb>(&7).unwrap();
+    assert_eq!(estimate, proof.iter().map(|(_, node)| node.len()).sum::<usize>());
+
+    let empty: Trie<MemoryDb, u64, String> = Trie::new(MemoryDb::new());
+    assert_eq!(empty.estimate_proof_size(&1).unwrap(), 0);
+}
+
+#[test]
+fn test_prove_concurrent() {
+    use std::thread;
+    use mpt_rs::{db::MemoryDb, error::{Error, TrieError}};
+
+    let mut trie: Trie<MemoryDb, u64, String> = common::fill_numbered(Trie::new(MemoryDb::new()), 200);
+    let root_hash = trie.commit().unwrap().unwrap();
+
+    let shared = &trie;
+    thread::scope(|scope| {
+        let handles: Vec<_> = (0..8u64).map(|t| scope.spawn(move || {
+            for i in (t..200).step_by(8).chain([1000 + t]) {
+                let (proof, exists) = shared.prove_concurrent::<MemoryDb>(&i).unwrap();
+                assert_eq!(exists, i < 200);
+                assert_eq!(verify_proof(&root_hash, &proof, &i).unwrap(), exists);
+            }
+        })).collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    });
+
+    trie = trie.insert(&1, &"changed".to_string()).unwrap();
+    assert!(matches!(
+        trie.prove_concurrent::<MemoryDb>(&1),
+        Err(Error::TrieError(TrieError::Uncommitted))
+    ));
+}
+
+#[test]
+fn test_verify_proofs() {
+    use mpt_rs::{db::MemoryDb, proof::verify_proofs};
+    use serlp::rlp::to_bytes;
+
+    let mut trie: Trie<MemoryDb, u64, String> = common::fill(Trie::new(MemoryDb::new()), 64);
+    let root_hash = trie.commit().unwrap().unwrap();
+
+    let mut other: Trie<MemoryDb, u64, String> = Trie::new(MemoryDb::new());
+    other = other.insert(&1000, &"w".repeat(40)).unwrap();
+    let other_root = other.commit().unwrap().unwrap();
+
+    let item = |trie: &mut Trie<MemoryDb, u64, String>, root: KecHash, key: u64| {
+        let (proof, exists) = trie.get_proof::<MemoryDb>(&key).unwrap();
+        assert!(exists);
+        let nodes = proof.iter().map(|(_, node)| node.to_vec()).collect::<Vec<_>>();
+        (root, nodes, to_bytes(&key).unwrap())
+    };
+
+    // the first two proofs share the nodes near the root
+    let mut items = vec![
+        item(&mut trie, root_hash, 3),
+        item(&mut trie, root_hash, 60),
+        item(&mut other, other_root, 1000),
+    ];
+    assert_eq!(verify_proofs(&items).unwrap(), vec![true, true, true]);
+
+    // corrupting the only node of the last proof breaks it alone
+    let node = &mut items[2].1[0];
+    let last = node.len() - 1;
+    node[last] ^= 1;
+    assert_eq!(verify_proofs(&items).unwrap(), vec![true, true, false]);
+}
+
+#[test]
+fn test_proof_of_branch_value() {
+    use mpt_rs::{db::MemoryDb, proof::verify_proof_raw};
+
+    let mut trie: Trie<MemoryDb, Vec<u8>, Vec<u8>> = Trie::new(MemoryDb::new());
+    // "a" is a prefix of "ab", so its value is held by a branch
+    trie = trie.insert_raw(b"a", b"short".to_vec()).unwrap();
+    trie = trie.insert_raw(b"ab", b"long".to_vec()).unwrap();
+    let root_hash = trie.commit().unwrap().unwrap();
+
+    let (proof, exists) = trie.get_proof_raw::<MemoryDb>(b"a").unwrap();
+    assert!(exists);
+    assert!(verify_proof_raw(&root_hash, &proof, b"a").unwrap());
+
+    let (proof, exists) = trie.get_proof_raw::<MemoryDb>(b"ab").unwrap();
+    assert!(exists);
+    assert!(verify_proof_raw(&root_hash, &proof, b"ab").unwrap());
+}
+
+#[test]
+fn test_proof_value_of_prefix_key() {
+    use mpt_rs::{db::MemoryDb, proof::{verify_proof_raw, verify_proof_value_raw}};
+
+    let mut trie: Trie<MemoryDb, Vec<u8>, Vec<u8>> = Trie::new(MemoryDb::new());
+    trie = trie.insert_raw(b"key", b"lock".to_vec()).unwrap();
+    trie = trie.insert_raw(b"keyboard", b"typing".to_vec()).unwrap();
+    let root_hash = trie.commit().unwrap().unwrap();
+
+    // "key" ends at the branch forking off "keyboard"
+    let (proof, exists) = trie.get_proof_raw::<MemoryDb>(b"key").unwrap();
+    assert!(exists);
+    assert!(verify_proof_raw(&root_hash, &proof, b"key").unwrap());
+    assert_eq!(verify_proof_value_raw(&root_hash, &proof, b"key").unwrap(), Some(b"lock".to_vec()));
+
+    let (proof, exists) = trie.get_proof_raw::<MemoryDb>(b"keyboard").unwrap();
+    assert!(exists);
+    assert_eq!(verify_proof_value_raw(&root_hash, &proof, b"keyboard").unwrap(), Some(b"typing".to_vec()));
+
+    let (proof, exists) = trie.get_proof_raw::<MemoryDb>(b"keys").unwrap();
+    assert!(!exists);
+    assert_eq!(verify_proof_value_raw(&root_hash, &proof, b"keys").unwrap(), None);
+}
+
+#[test]
+fn test_proof_value() {
+    use mpt_rs::{db::MemoryDb, proof::verify_proof_value};
+
+    let mut trie: Trie<MemoryDb, u32, String> = Trie::new(MemoryDb::new());
+    for i in 0..20 {
+        trie = trie.insert(&i, &format!("value {}", i)).unwrap();
+    }
+    let root_hash = trie.commit().unwrap().unwrap();
+    let (proof, _) = trie.get_proof::<MemoryDb>(&7).unwrap();
+    let value: Option<String> = verify_proof_value(&root_hash, &proof, &7u32).unwrap();
+    assert_eq!(value, Some("value 7".to_string()));
+    let (proof, exists) = trie.get_proof::<MemoryDb>(&100).unwrap();
+    assert!(!exists);
+    let value: Option<String> = verify_proof_value(&root_hash, &proof, &100u32).unwrap();
+    assert_eq!(value, None);
+}
+
+#[test]
+fn test_empty_extension_rejected() {
+    use mpt_rs::{keccak256, error::{Error, TrieError}};
+
+    let mut db = MapDb::new();
+    // [hex_prefix([1, 2, 3], leaf), 0x05]
+    let leaf = hex::decode("c482312305").unwrap();
+    let leaf_hash = keccak256(&leaf);
+    db.insert(&leaf_hash, leaf).unwrap();
+
+    // an extension whose shared path is empty: [hex_prefix([], extension), leaf hash]
+    let mut extension = hex::decode("e200a0").unwrap();
+    extension.extend_from_slice(&leaf_hash);
+    let extension_hash = keccak256(&extension);
+    db.insert(&extension_hash, extension).unwrap();
+
+    let trie: Trie<MapDb, Vec<u8>, Vec<u8>> = Trie::new(db).revert(extension_hash).unwrap();
+    match trie.insert_raw(&[0x12], b"value".to_vec()) {
+        Err(Error::TrieError(TrieError::InvalidNodeShape(_))) => (),
+        Err(err) => panic!("unexpected error {}", err),
+        Ok(_) => panic!("insert through a malformed node succeeded")
+    }
+}
+
+#[test]
+fn test_prove_in_memory() {
+    use mpt_rs::db::MemoryDb;
+
+    let mut trie: Trie<MemoryDb, u64, String> = common::fill(Trie::new(MemoryDb::new()), 64);
+    trie.commit().unwrap();
+    // modify the committed trie
+    trie = trie.insert(&7, &"changed".to_string()).unwrap();
+    trie = trie.insert(&100, &"new".to_string()).unwrap();
+    let nodes = trie.db.len();
+
+    let root_hash = trie.compute_root().unwrap().unwrap();
+    for key in [7u64, 8, 100] {
+        let (proof, exists) = trie.prove_in_memory::<MemoryDb>(&key).unwrap();
+        assert!(exists);
+        assert!(verify_proof(&root_hash, &proof, &key).unwrap());
+    }
+    let (proof, exists) = trie.prove_in_memory::<MemoryDb>(&1000).unwrap();
+    assert!(!exists);
+    assert!(!verify_proof(&root_hash, &proof, &1000u64).unwrap());
+
+    // nothing was written
+    assert!(trie.is_dirty());
+    assert_eq!(trie.db.len(), nodes);
+    assert_eq!(trie.commit().unwrap(), Some(root_hash));
+}
+
+#[test]
+fn test_verify_proof_strict() {
+    use mpt_rs::{proof::verify_proof_strict, error::{Error, TrieError}};
+
+    let mut trie: Trie<MapDb, u64, String> = common::fill_numbered(Trie::new(MapDb::new()), 64);
+    let root_hash = trie.commit().unwrap().unwrap();
+
+    let (mut proof, exists) = trie.get_proof::<MapDb>(&7).unwrap();
+    assert!(exists);
+    assert!(verify_proof_strict(&root_hash, &proof, &7u64).unwrap());
+
+    // swap the leaf for the one of another key, keeping the hash it is stored under
+    let (other, _) = trie.get_proof::<MapDb>(&8).unwrap();
+    let leaf_hash = *proof.0.keys()
+        .find(|hash| **hash != root_hash && !other.0.contains_key(*hash))
+        .unwrap();
+    let other_leaf = other.0.iter()
+        .find(|(hash, _)| !proof.0.contains_key(*hash))
+        .map(|(_, rlp)| rlp.clone())
+        .unwrap();
+    proof.0.insert(leaf_hash, other_leaf);
+
+    match verify_proof_strict(&root_hash, &proof, &7u64) {
+        Err(Error::TrieError(TrieError::HashMismatch(hash))) => assert_eq!(hash, leaf_hash),
+        other => panic!("unexpected result {:?}", other)
+    }
+}
+
+#[test]
+fn test_unicode_and_long_keys() {
+    use mpt_rs::proof::verify_proof_raw;
+
+    let keys: Vec<String> = vec![
+        "🦀".into(), "🦀🦀".into(), "日本語のキー".into(), "é".into(),
+        // around the boundary between short and long RLP strings
+        "k".repeat(55), "k".repeat(56),
+        "x".repeat(1023), "x".repeat(1024),
+    ];
+    let mut trie: Trie<MapDb, String, String> = Trie::new(MapDb::new());
+    for (i, key) in keys.iter().enumerate() {
+        trie = trie.insert(key, &format!("value-{}", i)).unwrap();
+    }
+    let root_hash = trie.commit().unwrap().unwrap();
+    trie = trie.insert(&"🦀".to_string(), &"changed".to_string()).unwrap();
+    trie.commit().unwrap();
+    trie = trie.revert(root_hash).unwrap();
+
+    for (i, key) in keys.iter().enumerate() {
+        assert_eq!(trie.get(key).unwrap(), Some(format!("value-{:040}", i)));
+        let (proof, exists) = trie.get_proof::<MapDb>(key).unwrap();
+        assert!(exists);
+        assert!(verify_proof(&root_hash, &proof, key).unwrap());
+    }
+    let (proof, exists) = trie.get_proof::<MapDb>(&"x".repeat(1025)).unwrap();
+    assert!(!exists);
+    assert!(!verify_proof(&root_hash, &proof, &"x".repeat(1025)).unwrap());
+
+    // raw byte keys, including ones that are not valid UTF-8
+    let raw_keys = [vec![0xff; 40], vec![0xff; 41], vec![0x00, 0xf0, 0x9f], vec![0x80; 1024]];
+    let mut trie: Trie<MapDb, Vec<u8>, Vec<u8>> = Trie::new(MapDb::new());
+    for key in &raw_keys {
+        trie = trie.insert_raw(key, key[..3].to_vec()).unwrap();
+    }
+    let root_hash = trie.commit().unwrap().unwrap();
+    for key in &raw_keys {
+        assert_eq!(trie.get_raw(key).unwrap(), Some(key[..3].to_vec()));
+        let (proof, exists) = trie.get_proof_raw::<MapDb>(key).unwrap();
+        assert!(exists);
+        assert!(verify_proof_raw(&root_hash, &proof, key).unwrap());
+    }
+}
+
+#[test]
+fn test_get_proof_both() {
+    use mpt_rs::keccak256;
+
+    let mut trie: Trie<MapDb, u64, String> = common::fill_numbered(Trie::new(MapDb::new()), 64);
+
+    for key in [7u64, 100] {
+        let (proof, ordered, exists) = trie.get_proof_both::<MapDb>(&key).unwrap();
+        assert_eq!(exists, key < 64);
+        assert_eq!(ordered.len(), proof.0.len());
+        let root_hash = trie.root_hash().unwrap();
+        assert_eq!(keccak256(&ordered[0]), root_hash);
+
+        let mut loaded = MapDb::new();
+        for rlp in ordered {
+            loaded.insert(&keccak256(&rlp), rlp).unwrap();
+        }
+        assert_eq!(
+            verify_proof(&root_hash, &loaded, &key).unwrap(),
+            verify_proof(&root_hash, &proof, &key).unwrap()
+        );
+        assert_eq!(verify_proof(&root_hash, &loaded, &key).unwrap(), exists);
+    }
+}
+
+#[test]
+fn test_proof_verifier() {
+    use mpt_rs::proof::{ProofVerifier, Step};
+    use serlp::rlp::to_bytes;
+
+    let mut trie: Trie<MapDb, u64, String> = common::fill_numbered(Trie::new(MapDb::new()), 64);
+
+    let feed_all = |verifier: &mut ProofVerifier, nodes: Vec<Vec<u8>>| {
+        let mut step = Step::Absent;
+        for (i, node) in nodes.iter().enumerate() {
+            step = verifier.feed(node).unwrap();
+            assert_eq!(matches!(step, Step::NeedMore(_)), i + 1 < nodes.len());
+        }
+        step
+    };
+
+    let (_, nodes, _) = trie.get_proof_both::<MapDb>(&7).unwrap();
+    let root_hash = trie.root_hash().unwrap();
+    let mut verifier = ProofVerifier::new(root_hash, &7u64).unwrap();
+    let value = to_bytes(&format!("value-{:040}", 7)).unwrap();
+    assert_eq!(feed_all(&mut verifier, nodes.clone()), Step::Found(value));
+    // nothing more is expected
+    assert!(verifier.feed(&nodes[0]).is_err());
+
+    let (_, nodes, _) = trie.get_proof_both::<MapDb>(&100).unwrap();
+    let mut verifier = ProofVerifier::new(root_hash, &100u64).unwrap();
+    assert_eq!(feed_all(&mut verifier, nodes), Step::Absent);
+
+    // nodes fed out of order do not match the expected hash
+    let (_, nodes, _) = trie.get_proof_both::<MapDb>(&7).unwrap();
+    let mut verifier = ProofVerifier::new(root_hash, &7u64).unwrap();
+    assert!(verifier.feed(&nodes[1]).is_err());
+}
+
+#[test]
+fn test_nested_branch_values() {
+    use mpt_rs::{db::MemoryDb, proof::{verify_proof_raw, ProofVerifier, Step}};
+
+    // each key is a prefix of the next one, so "a" and "ab" are held by branches
+    let kvs: [(&[u8], Vec<u8>); 3] = [
+        (b"a", b"one".to_vec()), (b"ab", b"two".repeat(20)), (b"abc", b"three".to_vec())
+    ];
+    for reversed in [false, true] {
+        let mut trie: Trie<MemoryDb, Vec<u8>, Vec<u8>> = Trie::new(MemoryDb::new());
+        // insert in both orders, so that branches are created by splits and by appends
+        let order: Vec<_> = if reversed { kvs.iter().rev().collect() } else { kvs.iter().collect() };
+        for (key, value) in order {
+            trie = trie.insert_raw(key, value.clone()).unwrap();
+        }
+        let root_hash = trie.commit().unwrap().unwrap();
+
+        for (key, value) in &kvs {
+            assert_eq!(trie.get_raw(key).unwrap().as_ref(), Some(value));
+            let (proof, exists) = trie.get_proof_raw::<MemoryDb>(key).unwrap();
+            assert!(exists);
+            assert!(verify_proof_raw(&root_hash, &proof, key).unwrap());
+
+            // the streaming verifier asks for the nodes it needs from the proof
+            let mut verifier = ProofVerifier::new_raw(root_hash, key);
+            let mut step = Step::NeedMore(root_hash);
+            while let Step::NeedMore(hash) = step {
+                step = verifier.feed(&proof.get(&hash).unwrap().unwrap()).unwrap();
+            }
+            assert_eq!(step, Step::Found(value.clone()));
+        }
+
+        for absent in [&b"ab\x00"[..], b"abcd", b"b"] {
+            let (proof, exists) = trie.get_proof_raw::<MemoryDb>(absent).unwrap();
+            assert!(!exists);
+            assert!(!verify_proof_raw(&root_hash, &proof, absent).unwrap());
+        }
+
+        let entries: Vec<_> = kvs.iter().map(|(k, v)| (k.to_vec(), v.clone())).collect();
+        assert_eq!(trie.entries_raw().unwrap(), entries);
+    }
+}
+
+#[test]
+fn test_proof_key_substitution() {
+    use mpt_rs::{db::MemoryDb, proof::verify_proof_raw};
+
+    let mut trie: Trie<MemoryDb, Vec<u8>, Vec<u8>> = Trie::new(MemoryDb::new());
+    for key in [&b"abcx"[..], b"abdx", b"bbbb"] {
+        trie = trie.insert_raw(key, b"v".repeat(40)).unwrap();
+    }
+    let root_hash = trie.commit().unwrap().unwrap();
+
+    let (proof, exists) = trie.get_proof_raw::<MemoryDb>(b"abcx").unwrap();
+    assert!(exists);
+    assert!(verify_proof_raw(&root_hash, &proof, b"abcx").unwrap());
+    // the lookups of these keys reach the leaf of "abcx", whose path does not match
+    for other in [&b"abcy"[..], b"abc", b"abcxx"] {
+        assert!(!verify_proof_raw(&root_hash, &proof, other).unwrap());
+    }
+
+    // a typed key whose path needs nodes missing from the proof
+    let mut trie: Trie<MapDb, u64, String> = common::fill_numbered(Trie::new(MapDb::new()), 64);
+    let (proof, _) = trie.get_proof::<MapDb>(&7).unwrap();
+    let root_hash = trie.root_hash().unwrap();
+    assert!(!matches!(verify_proof(&root_hash, &proof, &8u64), Ok(true)));
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+struct Log {
+    #[serde(with = "byte_array")]
+    address: [u8; 20],
+    topics: Vec<serde_bytes::ByteBuf>,
+    #[serde(with = "serde_bytes")]
+    data: Vec<u8>
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+struct Receipt {
+    #[serde(with = "serde_bytes")]
+    status: Vec<u8>,
+    cumulative_gas_used: u64,
+    #[serde(with = "serde_bytes")]
+    bloom: Vec<u8>,
+    logs: Vec<Log>
+}
+
+#[test]
+fn test_typed_receipts() {
+    use mpt_rs::envelope::TypedEnvelope;
+    use serlp::rlp::to_bytes;
+
+    let receipt = |gas: u64, logs: usize| Receipt {
+        status: vec![1],
+        cumulative_gas_used: gas,
+        bloom: vec![0; 256],
+        logs: (0..logs).map(|i| Log {
+            address: [i as u8; 20],
+            topics: vec![serde_bytes::ByteBuf::from(vec![0xdd; 32])],
+            data: vec![i as u8; 32]
+        }).collect()
+    };
+    let receipts = vec![
+        TypedEnvelope::legacy(receipt(21000, 0)),
+        TypedEnvelope::typed(2, receipt(74000, 1)).unwrap(),
+        TypedEnvelope::typed(1, receipt(95000, 2)).unwrap(),
+    ];
+
+    let mut trie: Trie<MapDb, usize, Receipt> = Trie::new(MapDb::new());
+    let mut raw: Trie<MapDb, usize, Receipt> = Trie::new(MapDb::new());
+    for (i, envelope) in receipts.iter().enumerate() {
+        let encoded = envelope.encode().unwrap();
+        match envelope.kind {
+            Some(kind) => assert_eq!(encoded[0], kind),
+            // a legacy receipt is an rlp list
+            None => assert!(encoded[0] >= 0xc0)
+        }
+        assert_eq!(TypedEnvelope::<Receipt>::decode(&encoded).unwrap(), *envelope);
+
+        trie = trie.insert_enveloped(&i, envelope).unwrap();
+        raw = raw.insert_raw(&to_bytes(&i).unwrap(), encoded).unwrap();
+    }
+    let root = trie.commit().unwrap();
+    assert_eq!(raw.commit().unwrap(), root);
+
+    for (i, envelope) in receipts.iter().enumerate() {
+        assert_eq!(trie.get_enveloped(&i).unwrap().as_ref(), Some(envelope));
+    }
+    // typed receipts are not rlp encoded as a whole, so they do not decode as plain values
+    assert!(matches!(
+        trie.get(&1),
+        Err(mpt_rs::error::Error::ValueDecodeError { key_hint, .. }) if key_hint == "01"
+    ));
+    assert!(TypedEnvelope::typed(0x80, receipt(0, 0)).is_err());
+
+    // the receipts root go-ethereum reports for a block holding a single 
+    // successful EIP-1559 transfer, as found on mainnet
+    let mut block: Trie<MapDb, usize, Receipt> = Trie::new(MapDb::new());
+    block = block.insert_enveloped(&0, &TypedEnvelope::typed(2, receipt(21000, 0)).unwrap()).unwrap();
+    assert_eq!(
+        hex::encode(block.commit().unwrap().unwrap()),
+        "f78dfb743fbd92ade140711c8bbc542b5e307f0ab7984eff35d751969fe57efa"
+    );
+}
+
+#[test]
+fn test_serve_range() {
+    use mpt_rs::proof::verify_range_proof;
+    use serlp::rlp::to_bytes;
+
+    let mut trie: Trie<MapDb, u64, String> = common::fill_numbered(Trie::new(MapDb::new()), 100);
+    let root_hash = trie.commit().unwrap().unwrap();
+
+    // rlp(20) is 0x14, so the keys 20 to 29 come first
+    let (entries, proof) = trie.serve_range::<MapDb>(&20, 10).unwrap();
+    let origin = to_bytes(&20u64).unwrap();
+    assert_eq!(entries.len(), 10);
+    assert_eq!(entries[0].0, origin);
+    assert_eq!(verify_range_proof(&root_hash, &origin, &entries, &proof).unwrap(), Some(true));
+
+    // a missing, an extra or a modified entry is detected
+    let mut missing = entries.clone();
+    missing.remove(4);
+    assert_eq!(verify_range_proof(&root_hash, &origin, &missing, &proof).unwrap(), None);
+    let mut modified = entries.clone();
+    modified[4].1 = b"forged".to_vec();
+    assert_eq!(verify_range_proof(&root_hash, &origin, &modified, &proof).unwrap(), None);
+    let mut extra = entries.clone();
+    extra.insert(5, ([entries[4].0.clone(), vec![0]].concat(), b"extra".to_vec()));
+    assert_eq!(verify_range_proof(&root_hash, &origin, &extra, &proof).unwrap(), None);
+
+    // the rest of the keys, the last one being rlp(0) = 0x80
+    let (entries, proof) = trie.serve_range::<MapDb>(&90, 100).unwrap();
+    let origin = to_bytes(&90u64).unwrap();
+    assert_eq!(entries.len(), 11);
+    assert_eq!(entries.last().unwrap().0, to_bytes(&0u64).unwrap());
+    assert_eq!(verify_range_proof(&root_hash, &origin, &entries, &proof).unwrap(), Some(false));
+    // the same entries cut short are only complete up to their last key
+    let (entries, proof) = trie.serve_range::<MapDb>(&90, 5).unwrap();
+    assert_eq!(verify_range_proof(&root_hash, &origin, &entries, &proof).unwrap(), Some(true));
+
+    // nothing after the origin
+    let (entries, proof) = trie.serve_range::<MapDb>(&200, 10).unwrap();
+    assert!(entries.is_empty());
+    let origin = to_bytes(&200u64).unwrap();
+    assert_eq!(verify_range_proof(&root_hash, &origin, &entries, &proof).unwrap(), Some(false));
+}
+
+/// counts the nodes loaded from it
+#[derive(Debug)]
+struct CountingDb(MapDb, Cell<usize>);
+
+impl Database for CountingDb {
+    type Error = mpt_rs::error::Error;
+
+    fn new() -> Self {
+        Self(MapDb::new(), Cell::new(0))
+    }
+
+    fn insert(&mut self, key: &KecHash, value: Vec<u8>) -> Result<()> {
+        self.0.insert(key, value)
+    }
+
+    fn exists(&mut self, key: &KecHash) -> Result<bool> {
+        self.0.exists(key)
+    }
+
+    fn get(&self, key: &KecHash) -> Result<Option<Vec<u8>>> {
+        self.1.set(self.1.get() + 1);
+        self.0.get(key)
+    }
+}
+
+#[test]
+fn test_serve_range_large_trie() {
+    use mpt_rs::proof::verify_range_proof;
+    use serlp::rlp::to_bytes;
+
+    let mut trie: Trie<CountingDb, u64, String> = common::fill(Trie::new(CountingDb::new()), 10_000);
+    let root_hash = trie.commit().unwrap().unwrap();
+    // start from the root alone, with everything else in the database
+    trie = trie.revert(root_hash).unwrap();
+
+    trie.db.1.set(0);
+    let (entries, proof) = trie.serve_range::<MapDb>(&5000, 100).unwrap();
+    // walking the whole trie would load more than 10,000 nodes
+    assert!(trie.db.1.get() < 1_000);
+
+    let origin = to_bytes(&5000u64).unwrap();
+    let expected: Vec<_> = trie.entries_raw().unwrap().into_iter()
+        .filter(|(key, _)| *key >= origin)
+        .take(100)
+        .collect();
+    assert_eq!(entries, expected);
+    assert_eq!(verify_range_proof(&root_hash, &origin, &entries, &proof).unwrap(), Some(true));
+}
+
+#[test]
+fn test_proof_db_wire_format() {
+    use mpt_rs::db::ProofDb;
+
+    let mut trie: Trie<MapDb, u64, String> = common::fill_numbered(Trie::new(MapDb::new()), 64);
+    let root_hash = trie.commit().unwrap().unwrap();
+
+    let (proof, exists) = trie.get_proof::<ProofDb>(&42).unwrap();
+    assert!(exists);
+    let bytes = proof.to_bytes().unwrap();
+
+    let received = ProofDb::from_bytes(&bytes).unwrap();
+    assert_eq!(received, proof);
+    assert_eq!(received.to_bytes().unwrap(), bytes);
+    assert!(verify_proof(&root_hash, &received, &42u64).unwrap());
+
+    // an absence proof survives the round trip as well
+    let (proof, exists) = trie.get_proof::<ProofDb>(&100).unwrap();
+    assert!(!exists);
+    let received = ProofDb::from_bytes(&proof.to_bytes().unwrap()).unwrap();
+    assert!(!verify_proof(&root_hash, &received, &100u64).unwrap());
+}
+
+#[test]
+fn test_secure_absence_proof() {
+    use mpt_rs::{db::ProofDb, keccak256, secure::{SecureTrie, verify_absence}, proof::verify_proof_raw};
+
+    let mut trie: SecureTrie<MapDb, [u8; 20], u64> = SecureTrie::new(MapDb::new());
+    for i in 0..32u8 {
+        trie = trie.insert(&[i; 20], &(i as u64 * 1000)).unwrap();
+    }
+    let root_hash = trie.commit().unwrap().unwrap();
+
+    let missing = [0xee; 20];
+    let (proof, exists) = trie.get_proof::<ProofDb>(&missing).unwrap();
+    assert!(!exists);
+    assert!(verify_absence(&root_hash, &proof, &missing).unwrap());
+
+    let present = [7; 20];
+    let (proof, exists) = trie.get_proof::<ProofDb>(&present).unwrap();
+    assert!(exists);
+    assert!(!verify_absence(&root_hash, &proof, &present).unwrap());
+    assert!(verify_proof_raw(&root_hash, &proof, &keccak256(&present)).unwrap());
+
+    // an empty proof, or one against another root, proves nothing
+    assert!(!verify_absence(&root_hash, &ProofDb::new(), &missing).unwrap());
+    let (proof, _) = trie.get_proof::<ProofDb>(&missing).unwrap();
+    assert!(!verify_absence(&[0; 32], &proof, &missing).unwrap());
+}
+
+#[test]
+fn test_get_proofs() {
+    use mpt_rs::{db::ProofDb, keccak256};
+
+    let mut trie: Trie<MapDb, u64, String> = common::fill_numbered(Trie::new(MapDb::new()), 64);
+    // pending changes are committed first
+    let proofs = trie.get_proofs(&[3, 40, 100]).unwrap();
+    let root_hash = trie.root_hash().unwrap();
+
+    assert_eq!(proofs.len(), 3);
+    for (key, nodes, exists) in proofs {
+        assert_eq!(exists, key < 64);
+        assert_eq!(keccak256(&nodes[0]), root_hash);
+
+        let mut proof = ProofDb::new();
+        for node in nodes {
+            proof.insert(&keccak256(&node), node).unwrap();
+        }
+        assert_eq!(verify_proof(&root_hash, &proof, &key).unwrap(), exists);
+    }
+}
+
+#[test]
+fn test_prove_subtree() {
+    use mpt_rs::{db::ProofDb, proof::{verify_subtree_proof, verify_proof_raw}, error::{Error, TrieError}};
+
+    // an "account" prefix with storage slots below it, next to other accounts
+    let account = b"acct".to_vec();
+    let mut trie: Trie<MapDb, Vec<u8>, Vec<u8>> = Trie::new(MapDb::new());
+    for slot in (0..16u8).map(|i| i << 4) {
+        trie = trie.insert_raw(&[&account[..], &[slot, 0xaa]].concat(), vec![slot; 40]).unwrap();
+    }
+    for other in [b"acc0", b"bcct", b"zzzz"] {
+        trie = trie.insert_raw(other, vec![1; 40]).unwrap();
+    }
+
+    let (subtree_hash, proof) = trie.prove_subtree::<ProofDb>(&account).unwrap();
+    let root_hash = trie.root_hash().unwrap();
+    assert_eq!(trie.subtree_root(&account).unwrap(), Some(subtree_hash));
+    assert!(verify_subtree_proof(&root_hash, &proof, &account, &subtree_hash).unwrap());
+    assert!(!verify_subtree_proof(&root_hash, &proof, &account, &[0; 32]).unwrap());
+    assert!(!verify_subtree_proof(&root_hash, &proof, b"bcct", &subtree_hash).unwrap());
+
+    // slots are verified against the subtree with the rest of their keys
+    let slot = [&account[..], &[0x70, 0xaa]].concat();
+    let (slot_proof, exists) = trie.get_proof_raw::<ProofDb>(&slot).unwrap();
+    assert!(exists);
+    assert!(verify_proof_raw(&subtree_hash, &slot_proof, &[0x70, 0xaa]).unwrap());
+    assert!(!verify_proof_raw(&subtree_hash, &slot_proof, &[0x75, 0xaa]).unwrap());
+
+    // the prefix must end at a node
+    assert!(matches!(
+        trie.prove_subtree::<ProofDb>(b"ac"),
+        Err(Error::TrieError(TrieError::SubtreeNotFound))
+    ));
+    assert_eq!(trie.subtree_root(b"ac").unwrap(), None);
+    assert_eq!(trie.subtree_root(b"").unwrap(), Some(root_hash));
+}
+
+#[test]
+fn test_multiproof_encoding() {
+    use mpt_rs::{db::ProofDb, proof::{encode_multiproof, decode_multiproof, verify_proof_raw}};
+
+    let mut trie: Trie<MapDb, u64, String> = common::fill_numbered(Trie::new(MapDb::new()), 256);
+    let root_hash = trie.commit().unwrap().unwrap();
+
+    let keys = [3u64, 4, 5, 130, 131, 1000];
+    let mut merged = ProofDb::new();
+    let mut concatenated = 0;
+    for key in &keys {
+        let (_, nodes, _) = trie.get_proof_both::<ProofDb>(key).unwrap();
+        for node in nodes {
+            concatenated += node.len();
+            merged.insert(&mpt_rs::keccak256(&node), node).unwrap();
+        }
+    }
+
+    let roots = vec![root_hash; keys.len()];
+    let blob = encode_multiproof(&merged, &roots, &keys).unwrap();
+    assert!(blob.len() < concatenated);
+
+    let (proof, decoded) = decode_multiproof(&blob).unwrap();
+    assert_eq!(decoded.len(), keys.len());
+    for ((root, key), expected) in decoded.iter().zip(keys) {
+        assert_eq!(*root, root_hash);
+        assert_eq!(*key, serlp::rlp::to_bytes(&expected).unwrap());
+        assert_eq!(verify_proof_raw(root, &proof, key).unwrap(), expected < 256);
+    }
+
+    assert!(encode_multiproof(&merged, &roots[1..], &keys).is_err());
+    assert!(encode_multiproof(&ProofDb::new(), &roots, &keys).is_err());
+}
@@ -0,0 +1,94 @@
+//! Property tests checking the trie against a `BTreeMap` reference model.
+//! Keys are inserted as raw bytes, so a key may be a prefix of another one.
+
+use std::collections::BTreeMap;
+
+use proptest::prelude::*;
+use mpt_rs::{mpt::{Trie, Database}, db::MemoryDb};
+
+type RawTrie = Trie<MemoryDb, Vec<u8>, Vec<u8>>;
+
+#[derive(Clone, Debug)]
+enum Op {
+    Insert(Vec<u8>, Vec<u8>),
+    Remove(Vec<u8>),
+    Get(Vec<u8>),
+    Commit,
+}
+
+/// Short keys over a tiny alphabet, so that paths share prefixes often.
+fn key() -> impl Strategy<Value = Vec<u8>> {
+    prop::collection::vec(prop::sample::select(vec![0x00u8, 0x01, 0x10, 0x11, 0xff]), 0..5)
+}
+
+/// An empty value is indistinguishable from a missing one.
+fn value() -> impl Strategy<Value = Vec<u8>> {
+    prop::collection::vec(any::<u8>(), 1..40)
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        4 => (key(), value()).prop_map(|(k, v)| Op::Insert(k, v)),
+        2 => key().prop_map(Op::Remove),
+        3 => key().prop_map(Op::Get),
+        1 => Just(Op::Commit),
+    ]
+}
+
+fn build<'a, I>(items: I) -> RawTrie
+where
+    I: IntoIterator<Item = (&'a Vec<u8>, &'a Vec<u8>)>
+{
+    let mut trie = Trie::new(MemoryDb::new());
+    for (key, value) in items {
+        trie = trie.insert_raw(key, value.clone()).unwrap();
+    }
+    trie
+}
+
+proptest! {
+    #[test]
+    fn trie_matches_btreemap(ops in prop::collection::vec(op(), 1..64)) {
+        let mut trie: RawTrie = Trie::new(MemoryDb::new());
+        let mut model = BTreeMap::new();
+
+        for op in ops {
+            match op {
+                Op::Insert(key, value) => {
+                    trie = trie.insert_raw(&key, value.clone()).unwrap();
+                    model.insert(key, value);
+                },
+                Op::Remove(key) => {
+                    let (new, removed) = trie.remove_raw(&key).unwrap();
+                    trie = new;
+                    prop_assert_eq!(removed, model.remove(&key).is_some());
+                },
+                Op::Get(key) => {
+                    prop_assert_eq!(trie.get_raw(&key).unwrap().as_ref(), model.get(&key));
+                },
+                Op::Commit => {
+                    trie.commit().unwrap();
+                }
+            }
+        }
+
+        for (key, value) in &model {
+            prop_assert_eq!(trie.get_raw(key).unwrap().as_ref(), Some(value));
+        }
+
+        // entries come out in ascending byte order of the keys
+        let entries: Vec<_> = model.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+        prop_assert_eq!(trie.entries_raw().unwrap(), entries);
+
+        // the root only depends on the final key set
+        let root = trie.commit().unwrap();
+        prop_assert_eq!(build(model.iter()).commit().unwrap(), root);
+        prop_assert_eq!(build(model.iter().rev()).commit().unwrap(), root);
+
+        // removing every key leaves the empty trie
+        for key in model.keys() {
+            trie = trie.remove_raw(key).unwrap().0;
+        }
+        prop_assert_eq!(trie.commit().unwrap(), None);
+    }
+}
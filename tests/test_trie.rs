@@ -0,0 +1,1633 @@
+use std::{borrow::Cow, cell::Cell, collections::HashMap, sync::Arc};
+
+use mpt_rs::{mpt::{Trie, Database, KecHash, node_hash}, db::MemoryDb, error::{Error, Result}};
+
+mod common;
+
+/// Counts how many times stored bytes are copied out of the database.
+struct CountingDb {
+    inner: MemoryDb,
+    borrow: bool,
+    copies: Cell<usize>,
+}
+
+impl Database for CountingDb {
+    type Error = Error;
+
+    fn new() -> Self {
+        Self { inner: MemoryDb::new(), borrow: true, copies: Cell::new(0) }
+    }
+
+    fn insert(&mut self, key: &KecHash, value: Vec<u8>) -> Result<()> {
+        self.inner.insert(key, value)
+    }
+
+    fn exists(&mut self, key: &KecHash) -> Result<bool> {
+        self.inner.exists(key)
+    }
+
+    fn get(&self, key: &KecHash) -> Result<Option<Vec<u8>>> {
+        self.copies.set(self.copies.get() + 1);
+        self.inner.get(key)
+    }
+
+    fn get_ref(&self, key: &KecHash) -> Result<Option<Cow<'_, [u8]>>> {
+        if self.borrow {
+            self.inner.get_ref(key)
+        } else {
+            Ok(self.get(key)?.map(Cow::Owned))
+        }
+    }
+}
+
+#[test]
+fn test_dump_restore() {
+    let mut trie: Trie<MemoryDb, _, _> = Trie::new(MemoryDb::new());
+
+    let kvs: Vec<(String, String)> = (0..64)
+        .map(|i| (format!("key-{}", i), "v".repeat(i)))
+        .collect();
+    for (k, v) in &kvs {
+        trie = trie.insert(k, v).unwrap();
+    }
+    let root_hash = trie.commit().unwrap();
+
+    let blob = trie.dump().unwrap();
+    let restored: Trie<MemoryDb, String, String> = Trie::restore(MemoryDb::new(), &blob).unwrap();
+
+    assert_eq!(restored.root_hash(), root_hash);
+    for (k, v) in &kvs {
+        assert_eq!(restored.get(k).unwrap().as_ref(), Some(v));
+    }
+
+    // unknown versions are rejected
+    let mut bad = blob.clone();
+    bad[0] += 1;
+    assert!(Trie::<MemoryDb, String, String>::restore(MemoryDb::new(), &bad).is_err());
+}
+
+#[test]
+fn test_restore_with_options() {
+    use mpt_rs::mpt::TrieBuilder;
+
+    let builder = || TrieBuilder::new(MemoryDb::new()).inline_threshold(0);
+    let mut trie: Trie<MemoryDb, String, String> = builder().build();
+    for i in 0..16 {
+        trie = trie.insert(&format!("key-{}", i), &format!("v{}", i)).unwrap();
+    }
+    let root_hash = trie.commit().unwrap();
+    let blob = trie.dump().unwrap();
+
+    let restored: Trie<MemoryDb, String, String> = builder().restore(&blob).unwrap();
+    let defaults: Trie<MemoryDb, String, String> = Trie::restore(MemoryDb::new(), &blob).unwrap();
+    assert_eq!(restored.root_hash(), root_hash);
+    assert_eq!(defaults.root_hash(), root_hash);
+
+    // only the restored options keep hashing further changes the same way
+    let (key, value) = ("key-16".to_string(), "v16".to_string());
+    let expected = trie.insert(&key, &value).unwrap().commit().unwrap();
+    assert_eq!(restored.insert(&key, &value).unwrap().commit().unwrap(), expected);
+    assert_ne!(defaults.insert(&key, &value).unwrap().commit().unwrap(), expected);
+}
+
+#[test]
+fn test_export_import_kv() {
+    let mut trie: Trie<MemoryDb, String, String> = Trie::new(MemoryDb::new());
+    for i in 0..64 {
+        trie = trie.insert(&format!("key-{}", i), &"v".repeat(i)).unwrap();
+    }
+    let root_hash = trie.commit().unwrap();
+
+    let mut out = Vec::new();
+    trie.export_kv(&mut out).unwrap();
+    let text = String::from_utf8(out.clone()).unwrap();
+    assert_eq!(text.lines().count(), 64);
+    // rlp("key-0") followed by rlp("")
+    assert!(text.lines().any(|line| line == "856b65792d30\t80"));
+
+    let imported: Trie<MemoryDb, String, String> = Trie::import_kv(MemoryDb::new(), out.as_slice()).unwrap();
+    assert_eq!(imported.root_hash(), root_hash);
+    assert_eq!(imported.get(&"key-7".to_string()).unwrap(), Some("v".repeat(7)));
+
+    for bad in ["856b65792d30", "zz\t80", "856b65792d30\t8"] {
+        let input = format!("856b65792d31\t76\n{}\n", bad);
+        match Trie::<MemoryDb, String, String>::import_kv(MemoryDb::new(), input.as_bytes()) {
+            Err(Error::EncodingError(msg)) => assert!(msg.contains("line 2")),
+            other => panic!("unexpected result {:?}", other.map(|trie| trie.root_hash()))
+        }
+    }
+}
+
+#[test]
+fn test_import_kv_with_options() {
+    use mpt_rs::{mpt::TrieBuilder, error::TrieError};
+
+    let builder = || TrieBuilder::new(MemoryDb::new()).inline_threshold(0);
+    let mut trie: Trie<MemoryDb, String, String> = builder().build();
+    for i in 0..16 {
+        trie = trie.insert(&format!("key-{}", i), &format!("v{}", i)).unwrap();
+    }
+    let root_hash = trie.commit().unwrap();
+    let mut out = Vec::new();
+    trie.export_kv(&mut out).unwrap();
+
+    let imported: Trie<MemoryDb, String, String> = builder().import_kv(out.as_slice()).unwrap();
+    assert_eq!(imported.root_hash(), root_hash);
+    let defaults: Trie<MemoryDb, String, String> = Trie::import_kv(MemoryDb::new(), out.as_slice()).unwrap();
+    assert_ne!(defaults.root_hash(), root_hash);
+
+    // the limits of the builder apply to the imported keys
+    let limited = TrieBuilder::new(MemoryDb::new()).max_key_nibbles(4)
+        .import_kv::<String, String, _>(out.as_slice());
+    assert!(matches!(limited, Err(Error::TrieError(TrieError::KeyTooLong(_)))));
+}
+
+#[test]
+fn test_values_matching() {
+    let mut trie: Trie<MemoryDb, Vec<u8>, Vec<u8>> = Trie::new(MemoryDb::new());
+    for i in 0..32u8 {
+        let value = if i % 3 == 0 { vec![0xaa, i] } else { vec![0xbb, i] };
+        trie = trie.insert_raw(&[i, i], value).unwrap();
+    }
+    trie.commit().unwrap();
+
+    let matching = trie.values_matching(|value| value.starts_with(&[0xaa])).unwrap();
+    let expected: Vec<_> = (0..32u8).filter(|i| i % 3 == 0)
+        .map(|i| (vec![i, i], vec![0xaa, i]))
+        .collect();
+    assert_eq!(matching, expected);
+    assert!(trie.values_matching(|value| value.is_empty()).unwrap().is_empty());
+}
+
+#[test]
+fn test_get_path() {
+    use mpt_rs::mpt::NodeKind;
+
+    let mut trie: Trie<MemoryDb, _, _> = Trie::new(MemoryDb::new());
+    // rlp("abcd") and rlp("abce") share all but the last nibble
+    trie = trie.insert(&"abcd", &"x".repeat(40)).unwrap();
+    trie = trie.insert(&"abce", &"y".repeat(40)).unwrap();
+
+    let kinds = |trie: &Trie<MemoryDb, &str, String>| trie.get_path(&"abcd").unwrap()
+        .into_iter()
+        .map(|node| node.kind)
+        .collect::<Vec<_>>();
+    let expected = vec![NodeKind::Extension, NodeKind::Branch, NodeKind::Leaf];
+    assert_eq!(kinds(&trie), expected);
+
+    let root_hash = trie.commit().unwrap();
+    assert_eq!(kinds(&trie), expected);
+
+    let path = trie.get_path(&"abcd").unwrap();
+    assert_eq!(path[0].hash, root_hash);
+    assert_eq!(path[0].consumed.as_slice(), &[8, 4, 6, 1, 6, 2, 6, 3, 6]);
+    assert_eq!(path[1].consumed.to_string(), "4");
+    assert!(path[2].hash.is_some());
+}
+
+#[test]
+fn test_get_borrows_from_db() {
+    let deep_get = |borrow| {
+        let mut db = CountingDb::new();
+        db.borrow = borrow;
+        let mut trie: Trie<CountingDb, _, _> = common::fill(Trie::new(db), 256);
+        trie.commit().unwrap();
+
+        trie.db.copies.set(0);
+        assert_eq!(trie.get(&200).unwrap(), Some("v".repeat(40)));
+        trie.db.copies.get()
+    };
+
+    assert!(deep_get(false) > 0);
+    assert_eq!(deep_get(true), 0);
+}
+
+#[test]
+fn test_first_last_key() {
+    use serlp::rlp::to_bytes;
+
+    let mut trie: Trie<MemoryDb, &str, u64> = Trie::new(MemoryDb::new());
+    assert_eq!(trie.first_key().unwrap(), None);
+
+    for (i, key) in ["ab", "b", "aa", "abc"].iter().enumerate() {
+        trie = trie.insert(key, &(i as u64)).unwrap();
+    }
+    // keys are ordered by their RLP encoding, "b" is encoded as a single byte
+    assert_eq!(trie.first_key().unwrap(), Some(to_bytes(&"b").unwrap()));
+    assert_eq!(trie.last_key().unwrap(), Some(to_bytes(&"abc").unwrap()));
+}
+
+#[test]
+fn test_extend_raw() {
+    use serlp::rlp::to_bytes;
+
+    let kvs: Vec<(u64, String)> = (0..200).map(|i| (i, format!("value-{}", i))).collect();
+
+    let mut reference: Trie<MemoryDb, u64, String> = Trie::new(MemoryDb::new());
+    for (k, v) in &kvs {
+        reference = reference.insert(k, v).unwrap();
+    }
+
+    let mut items: Vec<(Vec<u8>, Vec<u8>)> = kvs.iter()
+        .map(|(k, v)| (to_bytes(k).unwrap(), to_bytes(v).unwrap()))
+        .collect();
+    items.sort();
+    let mut trie: Trie<MemoryDb, u64, String> = Trie::new(MemoryDb::new())
+        .extend_raw(items)
+        .unwrap();
+
+    assert_eq!(trie.commit().unwrap(), reference.commit().unwrap());
+    assert_eq!(trie.get(&42).unwrap(), Some("value-42".to_string()));
+}
+
+#[test]
+fn test_total_value_bytes() {
+    use serlp::rlp::to_bytes;
+
+    let mut trie: Trie<MemoryDb, u64, String> = Trie::new(MemoryDb::new());
+    assert_eq!(trie.total_value_bytes().unwrap(), 0);
+
+    let mut expected = 0;
+    for i in 0..100u64 {
+        let value = "v".repeat(i as usize * 3);
+        // the trie stores the RLP encoded value
+        expected += to_bytes(&value).unwrap().len() as u64;
+        trie = trie.insert(&i, &value).unwrap();
+    }
+    assert_eq!(trie.total_value_bytes().unwrap(), expected);
+
+    trie.commit().unwrap();
+    assert_eq!(trie.total_value_bytes().unwrap(), expected);
+}
+
+#[test]
+fn test_transaction() {
+    let mut trie: Trie<MemoryDb, u64, String> = Trie::new(MemoryDb::new());
+    trie = trie.insert(&1, &"one".to_string()).unwrap();
+    let root_hash = trie.commit().unwrap();
+
+    // a failing transaction leaves the trie untouched
+    let result = trie.transaction(|trie| {
+        trie.insert_mut(&2, &"two".to_string())?;
+        trie.insert_mut(&1, &"uno".to_string())?;
+        Err(Error::DatabaseError("abort".into()))
+    });
+    assert!(result.is_err());
+    assert_eq!(trie.root_hash(), root_hash);
+    assert_eq!(trie.get(&1).unwrap(), Some("one".to_string()));
+    assert_eq!(trie.get(&2).unwrap(), None);
+
+    // a successful one is committed
+    trie.transaction(|trie| trie.insert_mut(&2, &"two".to_string())).unwrap();
+    assert_ne!(trie.root_hash(), root_hash);
+    assert_eq!(trie.get(&2).unwrap(), Some("two".to_string()));
+    assert_eq!(trie.commit().unwrap(), trie.root_hash());
+}
+
+#[test]
+fn test_keccak256() {
+    // values from go-ethereum's crypto.Keccak256Hash and types.EmptyRootHash
+    assert_eq!(
+        hex::encode(mpt_rs::keccak256(b"")),
+        "c5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470"
+    );
+    assert_eq!(
+        hex::encode(mpt_rs::keccak256(&[0x80])),
+        "56e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421"
+    );
+}
+
+#[derive(Debug)]
+struct BackendError {
+    code: u32,
+}
+
+impl std::fmt::Display for BackendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "backend failed with code {}", self.code)
+    }
+}
+
+impl std::error::Error for BackendError {}
+
+impl From<BackendError> for Error {
+    fn from(err: BackendError) -> Self {
+        Error::Backend(Arc::new(err))
+    }
+}
+
+impl From<Error> for BackendError {
+    fn from(_: Error) -> Self {
+        BackendError { code: 0 }
+    }
+}
+
+/// A database that starts failing reads once `offline` is set.
+struct FlakyDb {
+    inner: MemoryDb,
+    offline: bool,
+}
+
+impl Database for FlakyDb {
+    type Error = BackendError;
+
+    fn new() -> Self {
+        Self { inner: MemoryDb::new(), offline: false }
+    }
+
+    fn insert(&mut self, key: &KecHash, value: Vec<u8>) -> std::result::Result<(), BackendError> {
+        self.inner.insert(key, value).map_err(|_| BackendError { code: 1 })
+    }
+
+    fn exists(&mut self, key: &KecHash) -> std::result::Result<bool, BackendError> {
+        self.inner.exists(key).map_err(|_| BackendError { code: 1 })
+    }
+
+    fn get(&self, key: &KecHash) -> std::result::Result<Option<Vec<u8>>, BackendError> {
+        if self.offline {
+            return Err(BackendError { code: 42 })
+        }
+        self.inner.get(key).map_err(|_| BackendError { code: 1 })
+    }
+}
+
+#[test]
+fn test_backend_error() {
+    let mut trie: Trie<FlakyDb, u64, String> = common::fill(Trie::new(FlakyDb::new()), 64);
+    trie.commit().unwrap();
+    assert_eq!(trie.get(&7).unwrap(), Some("v".repeat(40)));
+
+    trie.db.offline = true;
+    match trie.get(&7) {
+        Err(Error::Backend(err)) => {
+            assert_eq!(err.downcast_ref::<BackendError>().unwrap().code, 42);
+        },
+        other => panic!("unexpected result {:?}", other)
+    }
+}
+
+#[test]
+fn test_merge() {
+    use mpt_rs::mpt::MergePolicy;
+
+    let build = |kvs: &[(u64, &str)]| {
+        let mut trie: Trie<MemoryDb, u64, String> = Trie::new(MemoryDb::new());
+        for (k, v) in kvs {
+            trie = trie.insert(k, &v.to_string()).unwrap();
+        }
+        trie
+    };
+
+    let ours: Vec<(u64, &str)> = (0..40).map(|i| (i, "ours")).collect();
+    let theirs: Vec<(u64, &str)> = (20..60).map(|i| (i, "theirs")).collect();
+
+    let mut other = build(&theirs);
+    // a committed trie stores its nodes by hash
+    other.commit().unwrap();
+
+    let mut kept = build(&ours).merge(&other, MergePolicy::KeepSelf).unwrap();
+    let expected: Vec<_> = theirs.iter().chain(ours.iter()).cloned().collect();
+    assert_eq!(kept.commit().unwrap(), build(&expected).commit().unwrap());
+    assert_eq!(kept.get(&30).unwrap(), Some("ours".to_string()));
+
+    let mut taken = build(&ours).merge(&other, MergePolicy::TakeOther).unwrap();
+    let expected: Vec<_> = ours.iter().chain(theirs.iter()).cloned().collect();
+    assert_eq!(taken.commit().unwrap(), build(&expected).commit().unwrap());
+    assert_eq!(taken.get(&30).unwrap(), Some("theirs".to_string()));
+}
+
+#[test]
+fn test_iter_nodes() {
+    use mpt_rs::{keccak256, mpt::NodeKind};
+
+    let mut trie: Trie<MemoryDb, u64, String> = common::fill(Trie::new(MemoryDb::new()), 100);
+    assert!(trie.iter_nodes().is_err());
+    trie.commit().unwrap();
+
+    let nodes = trie.iter_nodes().unwrap().collect::<Result<Vec<_>>>().unwrap();
+    // every node is large enough to be stored by hash
+    assert_eq!(nodes.len(), trie.db.len());
+    assert_eq!(nodes[0].0, trie.root_hash().unwrap());
+    for (hash, node) in &nodes {
+        assert_eq!(keccak256(&node.encode().unwrap()), *hash);
+        assert_eq!(trie.db.get(hash).unwrap(), Some(node.encode().unwrap()));
+    }
+    let leaves = nodes.iter().filter(|(_, node)| node.kind() == NodeKind::Leaf).count();
+    assert_eq!(leaves, 100);
+}
+
+#[test]
+fn test_iter_nodes_bfs() {
+    let mut trie: Trie<MemoryDb, u64, String> = common::fill(Trie::new(MemoryDb::new()), 100);
+    assert!(trie.iter_nodes_bfs().is_err());
+    trie.commit().unwrap();
+
+    let nodes = trie.iter_nodes_bfs().unwrap().collect::<Result<Vec<_>>>().unwrap();
+    assert_eq!(nodes.len(), trie.iter_nodes().unwrap().count());
+    assert_eq!(nodes[0].0, 0);
+    assert_eq!(nodes[0].1, trie.root_hash().unwrap());
+    assert!(nodes.windows(2).all(|pair| pair[0].0 <= pair[1].0));
+    assert!(nodes[1..].iter().all(|(depth, _, _)| *depth > 0));
+}
+
+#[test]
+fn test_summary_json() {
+    let mut trie: Trie<MemoryDb, u64, String> = common::fill(Trie::new(MemoryDb::new()), 16);
+    trie.commit().unwrap();
+
+    let path = trie.get_path(&3).unwrap();
+    let json: serde_json::Value = serde_json::to_value(&path).unwrap();
+    let root = json[0]["hash"].as_str().unwrap();
+    assert_eq!(root.len(), 64);
+    assert_eq!(root, hex::encode(trie.root_hash().unwrap()));
+    assert_eq!(json[0]["kind"], "Branch");
+}
+
+#[test]
+fn test_revert_inline_root() {
+    let mut trie: Trie<MemoryDb, u8, u8> = Trie::new(MemoryDb::new());
+    trie = trie.insert(&1, &2).unwrap();
+    // the root is a leaf far smaller than 32 bytes, it is still stored by hash
+    let tiny_root = trie.commit().unwrap().unwrap();
+
+    trie = trie.insert(&3, &4).unwrap();
+    trie.commit().unwrap();
+
+    let trie = trie.revert(tiny_root).unwrap();
+    assert_eq!(trie.root_hash(), Some(tiny_root));
+    assert_eq!(trie.get(&1).unwrap(), Some(2));
+    assert_eq!(trie.get(&3).unwrap(), None);
+}
+
+#[test]
+fn test_entries_typed() {
+    let mut trie: Trie<MemoryDb, u64, String> = Trie::new(MemoryDb::new());
+    let keys = [0u64, 1, 127, 128, 255, 256, 65535, 1 << 40, u64::MAX];
+    for k in keys {
+        trie = trie.insert(&k, &format!("value-{}", k)).unwrap();
+    }
+    trie.commit().unwrap();
+
+    let mut entries = trie.entries_typed().unwrap();
+    assert_eq!(entries.len(), keys.len());
+    // entries come in the order of encoded keys, not integers
+    entries.sort();
+    let expected: Vec<_> = keys.iter().map(|k| (*k, format!("value-{}", k))).collect();
+    assert_eq!(entries, expected);
+}
+
+#[test]
+fn test_read_only() {
+    use mpt_rs::proof::verify_proof;
+
+    let mut trie: Trie<MemoryDb, u64, String> = common::fill_numbered(Trie::new(MemoryDb::new()), 32);
+    // pending changes must be committed first
+    assert!(trie.clone().into_read_only().is_err());
+    let root_hash = trie.commit().unwrap().unwrap();
+
+    let trie = trie.into_read_only().unwrap();
+    assert_eq!(trie.get(&7).unwrap(), Some(format!("value-{:040}", 7)));
+    assert!(trie.contains_key(&31).unwrap());
+    assert!(!trie.contains_key(&32).unwrap());
+    assert_eq!(trie.entries_raw().unwrap().len(), 32);
+
+    let (proof, exists) = trie.get_proof::<MemoryDb>(&7).unwrap();
+    assert!(exists);
+    assert!(verify_proof(&root_hash, &proof, &7u64).unwrap());
+}
+
+#[test]
+fn test_inline_threshold() {
+    use mpt_rs::mpt::{TrieBuilder, INLINE_THRESHOLD};
+
+    let fill = |mut trie: Trie<MemoryDb, u8, u8>| {
+        for i in 0..64u8 {
+            trie = trie.insert(&i, &i).unwrap();
+        }
+        let root_hash = trie.commit().unwrap();
+        (root_hash, trie)
+    };
+
+    let (default_root, default) = fill(Trie::new(MemoryDb::new()));
+    let (built_root, _) = fill(
+        TrieBuilder::new(MemoryDb::new()).inline_threshold(INLINE_THRESHOLD + 10).build()
+    );
+    assert_eq!(built_root, default_root);
+
+    // with every node stored by hash, each small leaf gets its own entry
+    let (_, lowered) = fill(TrieBuilder::new(MemoryDb::new()).inline_threshold(0).build());
+    assert!(lowered.db.len() > default.db.len());
+    assert!(lowered.db.len() > 64);
+    for i in 0..64u8 {
+        assert_eq!(lowered.get(&i).unwrap(), Some(i));
+    }
+}
+
+#[test]
+fn test_update_raw() {
+    let mut trie: Trie<MemoryDb, Vec<u8>, Vec<u8>> = Trie::new(MemoryDb::new());
+    trie = trie.insert_raw(b"counter", vec![1, 2]).unwrap();
+    trie.commit().unwrap();
+
+    let append = |old: Option<&[u8]>| {
+        let mut value = old.map(<[u8]>::to_vec).unwrap_or_default();
+        value.push(0xff);
+        value
+    };
+    trie = trie.update_raw(b"counter", append).unwrap();
+    assert_eq!(trie.get_raw(b"counter").unwrap(), Some(vec![1, 2, 0xff]));
+
+    trie = trie.update_raw(b"fresh", append).unwrap();
+    assert_eq!(trie.get_raw(b"fresh").unwrap(), Some(vec![0xff]));
+}
+
+#[test]
+fn test_insert_if_absent() {
+    let mut trie: Trie<MemoryDb, u64, String> = common::fill(Trie::new(MemoryDb::new()), 32);
+    let root_hash = trie.commit().unwrap();
+
+    let (trie, inserted) = trie.insert_if_absent(&100, &"first".to_string()).unwrap();
+    assert!(inserted);
+    let (trie, inserted) = trie.insert_if_absent(&100, &"second".to_string()).unwrap();
+    assert!(!inserted);
+    assert_eq!(trie.get(&100).unwrap(), Some("first".to_string()));
+
+    // an existing key is left untouched
+    let (mut trie, inserted) = trie.insert_if_absent(&3, &"other".to_string()).unwrap();
+    assert!(!inserted);
+    assert_eq!(trie.get(&3).unwrap(), Some("v".repeat(40)));
+    assert_ne!(trie.commit().unwrap(), root_hash);
+}
+
+#[test]
+fn test_secure_preimages() {
+    use mpt_rs::{keccak256, secure::SecureTrie};
+
+    let addresses: Vec<[u8; 20]> = (0..16u8).map(|i| [i; 20]).collect();
+
+    let mut trie: SecureTrie<MemoryDb, [u8; 20], u64> = 
+        SecureTrie::with_preimages(MemoryDb::new(), MemoryDb::new());
+    for (i, address) in addresses.iter().enumerate() {
+        trie = trie.insert(address, &(i as u64)).unwrap();
+    }
+    trie.commit().unwrap();
+    assert_eq!(trie.get(&addresses[3]).unwrap(), Some(3));
+
+    let entries = trie.trie().entries_raw().unwrap();
+    assert_eq!(entries.len(), addresses.len());
+    for (hashed, _) in entries {
+        let hashed: KecHash = hashed.try_into().unwrap();
+        let original = trie.preimage(&hashed).unwrap().unwrap();
+        assert_eq!(keccak256(&original), hashed);
+        assert!(addresses.iter().any(|address| address[..] == original[..]));
+    }
+
+    // preimages are not recorded by default
+    let trie: SecureTrie<MemoryDb, [u8; 20], u64> = SecureTrie::new(MemoryDb::new())
+        .insert(&addresses[0], &0)
+        .unwrap();
+    assert_eq!(trie.preimage(&keccak256(&addresses[0])).unwrap(), None);
+}
+
+#[test]
+fn test_floor_ceil_key() {
+    use serlp::rlp::to_bytes;
+
+    let mut trie: Trie<MemoryDb, u64, u64> = Trie::new(MemoryDb::new());
+    // small integers are encoded as a single byte, so the key order is numeric
+    for k in (10..=120).step_by(10) {
+        trie = trie.insert(&k, &k).unwrap();
+    }
+    trie.commit().unwrap();
+
+    let encoded = |k: u64| Some(to_bytes(&k).unwrap());
+    // between existing keys
+    assert_eq!(trie.floor_key(&15).unwrap(), encoded(10));
+    assert_eq!(trie.ceil_key(&15).unwrap(), encoded(20));
+    // an existing key
+    assert_eq!(trie.floor_key(&70).unwrap(), encoded(70));
+    assert_eq!(trie.ceil_key(&70).unwrap(), encoded(70));
+    // before and after all keys
+    assert_eq!(trie.floor_key(&5).unwrap(), None);
+    assert_eq!(trie.ceil_key(&5).unwrap(), encoded(10));
+    assert_eq!(trie.floor_key(&125).unwrap(), encoded(120));
+    assert_eq!(trie.ceil_key(&125).unwrap(), None);
+}
+
+#[test]
+fn test_stack_trie() {
+    use serlp::rlp::to_bytes;
+    use mpt_rs::stack_trie::StackTrie;
+
+    let mut trie: Trie<MemoryDb, u64, String> = Trie::new(MemoryDb::new());
+    let mut items = Vec::new();
+    for i in 0..500u64 {
+        let value = "v".repeat((i % 50) as usize + 1);
+        trie = trie.insert(&i, &value).unwrap();
+        items.push((to_bytes(&i).unwrap(), to_bytes(&value).unwrap()));
+    }
+    items.sort();
+
+    let mut stack = StackTrie::new(MemoryDb::new());
+    for (key, value) in &items {
+        stack.push(key, value.clone()).unwrap();
+    }
+    let (root_hash, db) = stack.finalize().unwrap();
+    assert_eq!(root_hash, trie.commit().unwrap());
+
+    // the nodes are usable by a normal trie
+    let restored: Trie<MemoryDb, u64, String> = Trie::new(db).revert(root_hash.unwrap()).unwrap();
+    assert_eq!(restored.get(&42).unwrap(), trie.get(&42).unwrap());
+}
+
+#[test]
+fn test_stack_trie_unordered() {
+    use mpt_rs::{stack_trie::StackTrie, error::TrieError};
+
+    let mut stack = StackTrie::new(MemoryDb::new());
+    stack.push(b"b", b"1".to_vec()).unwrap();
+    assert!(matches!(
+        stack.push(b"a", b"2".to_vec()),
+        Err(Error::TrieError(TrieError::UnorderedKey))
+    ));
+    assert!(stack.push(b"b", b"3".to_vec()).is_err());
+    stack.push(b"bc", b"4".to_vec()).unwrap();
+}
+
+#[test]
+fn test_capacity_hint() {
+    let build = |hint: Option<usize>| {
+        let mut trie: Trie<MemoryDb, u64, String> = Trie::new(MemoryDb::new());
+        if let Some(nodes) = hint {
+            trie.capacity_hint(nodes);
+        }
+        let capacity = trie.db.capacity();
+        trie = common::fill(trie, 1000);
+        let root_hash = trie.commit().unwrap();
+        (root_hash, capacity, trie)
+    };
+
+    let (root_hash, reserved, trie) = build(Some(4096));
+    assert!(reserved >= 4096);
+    assert!(trie.db.len() <= 4096);
+    // no reallocation happened during the load
+    assert_eq!(trie.db.capacity(), reserved);
+    assert_eq!(root_hash, build(None).0);
+}
+
+#[test]
+fn test_dirty_keys() {
+    let mut trie: Trie<MemoryDb, &str, u64> = Trie::new(MemoryDb::new());
+    assert!(!trie.is_dirty());
+
+    trie = trie.insert(&"dog", &1).unwrap();
+    trie = trie.insert(&"horse", &2).unwrap();
+    assert!(trie.is_dirty());
+
+    let mut keys: Vec<Vec<u8>> = trie.dirty_keys().map(|key| key.as_slice().to_vec()).collect();
+    keys.sort();
+    // rlp("dog") and rlp("horse") as nibbles
+    assert_eq!(keys, vec![
+        vec![8, 3, 6, 4, 6, 15, 6, 7],
+        vec![8, 5, 6, 8, 6, 15, 7, 2, 7, 3, 6, 5],
+    ]);
+
+    trie.commit().unwrap();
+    assert!(!trie.is_dirty());
+    assert_eq!(trie.dirty_keys().count(), 0);
+}
+
+#[test]
+fn test_file_db() {
+    use mpt_rs::db::FileDb;
+
+    let db = FileDb::new();
+    let dir = db.dir().to_path_buf();
+
+    let mut trie: Trie<FileDb, u64, String> = common::fill_numbered(Trie::new(db), 50);
+    let root_hash = trie.commit().unwrap().unwrap();
+    drop(trie);
+
+    // every node is a file named by its hash
+    assert!(dir.join(hex::encode(root_hash)).is_file());
+
+    let reopened: Trie<FileDb, u64, String> = Trie::new(FileDb::open(&dir).unwrap())
+        .revert(root_hash)
+        .unwrap();
+    for i in 0..50u64 {
+        assert_eq!(reopened.get(&i).unwrap(), Some(format!("value-{:040}", i)));
+    }
+    assert_eq!(reopened.get(&50).unwrap(), None);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_remove() {
+    let build = |keys: &[u64]| {
+        let mut trie: Trie<MemoryDb, u64, String> = Trie::new(MemoryDb::new());
+        for k in keys {
+            trie = trie.insert(k, &format!("value-{}", k)).unwrap();
+        }
+        trie
+    };
+
+    let keys: Vec<u64> = (0..100).collect();
+    let mut trie = build(&keys);
+    let root_hash = trie.commit().unwrap();
+
+    // an absent key is a no-op
+    let (mut trie, removed) = trie.remove(&1000).unwrap();
+    assert!(!removed);
+    assert!(!trie.is_dirty());
+    assert_eq!(trie.commit().unwrap(), root_hash);
+
+    let (mut trie, removed) = trie.remove(&42).unwrap();
+    assert!(removed);
+    assert!(trie.is_dirty());
+    assert_eq!(trie.get(&42).unwrap(), None);
+    let expected: Vec<u64> = keys.iter().cloned().filter(|k| *k != 42).collect();
+    assert_eq!(trie.commit().unwrap(), build(&expected).commit().unwrap());
+
+    // removing everything leaves an empty trie
+    for k in &expected {
+        trie = trie.remove(k).unwrap().0;
+    }
+    assert_eq!(trie.commit().unwrap(), None);
+}
+
+/// Records whether it was flushed since the last insert.
+struct FlushDb {
+    inner: MemoryDb,
+    flushed: bool,
+}
+
+impl Database for FlushDb {
+    type Error = Error;
+
+    fn new() -> Self {
+        Self { inner: MemoryDb::new(), flushed: false }
+    }
+
+    fn insert(&mut self, key: &KecHash, value: Vec<u8>) -> Result<()> {
+        self.flushed = false;
+        self.inner.insert(key, value)
+    }
+
+    fn exists(&mut self, key: &KecHash) -> Result<bool> {
+        self.inner.exists(key)
+    }
+
+    fn get(&self, key: &KecHash) -> Result<Option<Vec<u8>>> {
+        self.inner.get(key)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.flushed = true;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_flush() {
+    let mut trie: Trie<FlushDb, u64, u64> = Trie::new(FlushDb::new());
+    trie = trie.insert(&1, &1).unwrap();
+    trie.commit().unwrap();
+    assert!(!trie.db().flushed);
+
+    trie.flush().unwrap();
+    assert!(trie.db().flushed);
+    trie.db_mut().flushed = false;
+    assert!(!trie.db.flushed);
+}
+
+#[test]
+fn test_overlay_db() {
+    let mut trie: Trie<MemoryDb, u64, String> = common::fill(Trie::new(MemoryDb::new()), 16);
+    let base_root = trie.commit().unwrap().unwrap();
+    let base_len = trie.db.len();
+
+    let mut overlaid = trie.checkpoint_db();
+    for i in 16..32u64 {
+        overlaid = overlaid.insert(&i, &"w".repeat(40)).unwrap();
+    }
+    let new_root = overlaid.commit().unwrap().unwrap();
+    assert!(overlaid.db.pending() > 0);
+    assert_eq!(overlaid.db.base().len(), base_len);
+    assert_eq!(overlaid.get(&3).unwrap(), Some("v".repeat(40)));
+
+    // dropping the overlay leaves the base as it was
+    let base = overlaid.clone().discard_overlay();
+    assert_eq!(base.len(), base_len);
+    let reopened: Trie<MemoryDb, u64, String> = Trie::new(base).revert(base_root).unwrap();
+    assert_eq!(reopened.get(&20).unwrap(), None);
+
+    let mut trie = overlaid.commit_overlay().unwrap();
+    assert!(trie.db.len() > base_len);
+    assert!(trie.db.exists(&new_root).unwrap());
+    assert_eq!(trie.root_hash(), Some(new_root));
+    assert_eq!(trie.get(&20).unwrap(), Some("w".repeat(40)));
+}
+
+#[test]
+fn test_destroy() {
+    use mpt_rs::db::FileDb;
+
+    let mut trie: Trie<MemoryDb, u64, String> = common::fill(Trie::new(MemoryDb::new()), 32);
+    trie.commit().unwrap();
+    assert!(!trie.db.is_empty());
+    trie.db_mut().clear().unwrap();
+    assert!(trie.db.is_empty());
+
+    let db = FileDb::new();
+    let dir = db.dir().to_path_buf();
+    let mut trie: Trie<FileDb, u64, String> = common::fill(Trie::new(db), 32);
+    trie.commit().unwrap();
+    assert!(std::fs::read_dir(&dir).unwrap().next().is_some());
+    trie.destroy().unwrap();
+    assert!(std::fs::read_dir(&dir).unwrap().next().is_none());
+    std::fs::remove_dir_all(dir).unwrap();
+
+    // backends without support refuse to be wiped
+    let trie: Trie<FlushDb, u64, String> = Trie::new(FlushDb::new());
+    assert!(matches!(trie.destroy(), Err(Error::DatabaseError(_))));
+}
+
+#[test]
+fn test_db_size_bytes() {
+    let mut trie: Trie<MemoryDb, u64, String> = Trie::new(MemoryDb::new());
+    assert_eq!(trie.db_size_bytes().unwrap(), 0);
+    trie = common::fill(trie, 100);
+    assert!(trie.db_size_bytes().is_err());
+    trie.commit().unwrap();
+
+    // every node is stored by hash and reachable after a single commit
+    let stored: usize = trie.db.iter().map(|(_, rlp)| rlp.len()).sum();
+    assert_eq!(trie.db_size_bytes().unwrap(), stored as u64);
+    assert!(trie.db_size_bytes().unwrap() > trie.total_value_bytes().unwrap());
+}
+
+#[test]
+fn test_current_root_hash() {
+    let mut trie: Trie<MemoryDb, u64, String> = Trie::new(MemoryDb::new());
+    assert_eq!(trie.current_root_hash().unwrap(), None);
+    trie = common::fill(trie, 16);
+    let committed = trie.commit().unwrap();
+    assert_eq!(trie.current_root_hash().unwrap(), committed);
+
+    trie = trie.insert(&100, &"new".to_string()).unwrap();
+    let current = trie.current_root_hash().unwrap();
+    assert_ne!(current, committed);
+    // the committed hash is dropped rather than left stale
+    assert_eq!(trie.root_hash(), None);
+    assert!(trie.is_dirty());
+
+    trie.commit().unwrap();
+    assert_eq!(trie.root_hash(), current);
+}
+
+#[test]
+fn test_prepare_apply_commit() {
+    let mut trie: Trie<MemoryDb, u64, String> = common::fill(Trie::new(MemoryDb::new()), 64);
+    trie.commit().unwrap();
+    for i in 32..96u64 {
+        trie = trie.insert(&i, &"w".repeat(40)).unwrap();
+    }
+    let mut monolithic = trie.clone();
+    let expected = monolithic.commit().unwrap();
+
+    let pending = trie.prepare_commit().unwrap();
+    assert!(!pending.is_empty());
+    assert_eq!(pending.root_hash(), expected);
+    // nothing is written until applied
+    assert!(trie.is_dirty());
+    assert!(!trie.db.exists(&expected.unwrap()).unwrap());
+
+    assert_eq!(trie.apply_commit(pending).unwrap(), expected);
+    assert!(!trie.is_dirty());
+    assert_eq!(trie.db.len(), monolithic.db.len());
+    assert_eq!(trie.get(&80).unwrap(), Some("w".repeat(40)));
+
+    // a clean trie has nothing to write
+    assert!(trie.prepare_commit().unwrap().is_empty());
+}
+
+#[test]
+fn test_max_key_nibbles() {
+    use mpt_rs::{mpt::{TrieBuilder, MAX_KEY_NIBBLES}, error::TrieError};
+
+    let mut trie: Trie<MemoryDb, String, u8> = TrieBuilder::new(MemoryDb::new())
+        .max_key_nibbles(64)
+        .build();
+    // rlp adds a single byte header to strings shorter than 56 bytes
+    trie = trie.insert(&"k".repeat(31), &1).unwrap();
+    assert_eq!(trie.get(&"k".repeat(31)).unwrap(), Some(1));
+
+    let long = "k".repeat(32);
+    match trie.clone().insert(&long, &2) {
+        Err(Error::TrieError(TrieError::KeyTooLong(len))) => assert_eq!(len, 66),
+        Err(err) => panic!("unexpected error {:?}", err),
+        Ok(_) => panic!("inserted a key over the limit")
+    }
+    assert!(matches!(trie.get(&long), Err(Error::TrieError(TrieError::KeyTooLong(66)))));
+
+    // the default limit is generous
+    let trie: Trie<MemoryDb, String, u8> = Trie::new(MemoryDb::new());
+    let trie = trie.insert(&"k".repeat(1024), &3).unwrap();
+    assert!(trie.get(&"k".repeat(MAX_KEY_NIBBLES / 2)).is_err());
+}
+
+#[test]
+fn test_get_or_default() {
+    let mut counters: Trie<MemoryDb, String, u64> = Trie::new(MemoryDb::new());
+    counters = counters.insert(&"hits".to_string(), &42).unwrap();
+    assert_eq!(counters.get_or_default(&"hits".to_string()).unwrap(), 42);
+    assert_eq!(counters.get_or_default(&"misses".to_string()).unwrap(), 0);
+
+    let mut blobs: Trie<MemoryDb, u64, Vec<u8>> = Trie::new(MemoryDb::new());
+    blobs = blobs.insert(&1, &vec![1, 2, 3]).unwrap();
+    assert_eq!(blobs.get_or_default(&1).unwrap(), vec![1, 2, 3]);
+    assert_eq!(blobs.get_or_default(&2).unwrap(), Vec::<u8>::new());
+}
+
+#[test]
+fn test_entries_byte_order() {
+    let mut keys: Vec<Vec<u8>> = vec![
+        vec![0xff], vec![0x10, 0x00], vec![0x01, 0x02], vec![0x10], vec![0x0f, 0xff], vec![0xf0]
+    ];
+    let mut trie: Trie<MemoryDb, Vec<u8>, Vec<u8>> = Trie::new(MemoryDb::new());
+    for key in &keys {
+        trie = trie.insert_raw(key, key.clone()).unwrap();
+    }
+    trie.commit().unwrap();
+
+    keys.sort();
+    let stored: Vec<_> = trie.entries_raw().unwrap().into_iter().map(|(key, _)| key).collect();
+    assert_eq!(stored, keys);
+    assert_eq!(stored[..3], [vec![0x01, 0x02], vec![0x0f, 0xff], vec![0x10]]);
+}
+
+#[test]
+fn test_fork() {
+    let mut trie: Trie<MemoryDb, u64, String> = common::fill(Trie::new(MemoryDb::new()), 32);
+    assert!(trie.fork().is_err());
+    let root_hash = trie.commit().unwrap();
+
+    let mut fork = trie.fork().unwrap();
+    assert_eq!(fork.root_hash(), root_hash);
+    fork = fork.insert(&3, &"forked".to_string()).unwrap();
+    fork = fork.insert(&100, &"new".to_string()).unwrap();
+    fork.commit().unwrap();
+
+    assert_eq!(fork.get(&3).unwrap(), Some("forked".to_string()));
+    assert_eq!(trie.get(&3).unwrap(), Some("v".repeat(40)));
+    assert_eq!(trie.get(&100).unwrap(), None);
+    assert_eq!(trie.root_hash(), root_hash);
+}
+
+#[test]
+fn test_into_db() {
+    let mut trie: Trie<MemoryDb, u64, String> = common::fill_numbered(Trie::new(MemoryDb::new()), 32);
+    let (db, root_hash) = trie.into_db().unwrap();
+    assert!(!db.is_empty());
+
+    let reopened: Trie<MemoryDb, u64, String> = Trie::new(db).revert(root_hash.unwrap()).unwrap();
+    for i in 0..32u64 {
+        assert_eq!(reopened.get(&i).unwrap(), Some(format!("value-{:040}", i)));
+    }
+
+    let (db, root_hash) = Trie::<MemoryDb, u64, String>::new(MemoryDb::new()).into_db().unwrap();
+    assert!(db.is_empty());
+    assert_eq!(root_hash, None);
+}
+
+#[test]
+fn test_map_values() {
+    let mut trie: Trie<MemoryDb, Vec<u8>, Vec<u8>> = Trie::new(MemoryDb::new());
+    for i in 0..32u8 {
+        trie = trie.insert_raw(&[i, 0xaa], vec![i; 3]).unwrap();
+    }
+    let before = trie.commit().unwrap();
+
+    let mut trie = trie.map_values(|value| Ok([&[1][..], value].concat())).unwrap();
+    assert_ne!(trie.commit().unwrap(), before);
+    let entries = trie.entries_raw().unwrap();
+    assert_eq!(entries.len(), 32);
+    for (i, (key, value)) in entries.into_iter().enumerate() {
+        let i = i as u8;
+        assert_eq!(key, vec![i, 0xaa]);
+        assert_eq!(value, vec![1, i, i, i]);
+    }
+
+    // errors of the closure are passed on
+    assert!(trie.map_values(|_| Err(Error::EncodingError("bad".into()))).is_err());
+}
+
+#[test]
+fn test_root_hash_invalidated() {
+    let mut trie: Trie<MemoryDb, u64, String> = common::fill(Trie::new(MemoryDb::new()), 16);
+    let first = trie.commit().unwrap().unwrap();
+    trie = trie.insert(&100, &"new".to_string()).unwrap();
+    trie.commit().unwrap();
+
+    trie = trie.revert(first).unwrap();
+    assert_eq!(trie.root_hash(), Some(first));
+    trie = trie.insert(&200, &"other".to_string()).unwrap();
+    assert_eq!(trie.root_hash(), None);
+    let committed = trie.commit().unwrap();
+    assert!(committed.is_some());
+    assert_eq!(trie.root_hash(), committed);
+
+    let (mut trie, removed) = trie.remove(&200).unwrap();
+    assert!(removed);
+    assert_eq!(trie.root_hash(), None);
+    assert_eq!(trie.commit().unwrap(), Some(first));
+
+    // nothing written, nothing invalidated
+    let (trie, inserted) = trie.insert_if_absent(&3, &"other".to_string()).unwrap();
+    assert!(!inserted);
+    assert_eq!(trie.root_hash(), Some(first));
+}
+
+#[test]
+fn test_yellow_paper_vector() {
+    let kvs: [(&[u8], &[u8]); 4] = [
+        (b"do", b"verb"), (b"dog", b"puppy"), (b"doge", b"coin"), (b"horse", b"stallion")
+    ];
+    let expected = "5991bb8c6514148a29db676a14ac506cd2cd5775ace63c30a4fe457715e9ac84";
+
+    // the root depends only on the contents, not on the insertion order
+    for order in [[0, 1, 2, 3], [3, 2, 1, 0], [1, 3, 0, 2]] {
+        let mut trie: Trie<MemoryDb, Vec<u8>, Vec<u8>> = Trie::new(MemoryDb::new());
+        for i in order {
+            let (key, value) = kvs[i];
+            trie = trie.insert_raw(key, value.to_vec()).unwrap();
+        }
+        let root_hash = trie.commit().unwrap().unwrap();
+        assert_eq!(hex::encode(root_hash), expected);
+
+        for (key, value) in kvs {
+            assert_eq!(trie.get_raw(key).unwrap().as_deref(), Some(value));
+        }
+    }
+}
+
+#[test]
+fn test_bulk_remove() {
+    fn build(keys: impl Iterator<Item = u32>) -> Trie<MemoryDb, u32, String> {
+        let mut trie = Trie::new(MemoryDb::new());
+        for i in keys {
+            trie = trie.insert(&i, &format!("value {}", i)).unwrap();
+        }
+        trie
+    }
+    let mut survivors = build((0..200).filter(|i| i % 2 == 1));
+    let expected = survivors.commit().unwrap();
+
+    let mut forward = build(0..200).bulk_remove((0..200).filter(|i| i % 2 == 0)).unwrap();
+    let mut backward = build(0..200).bulk_remove((0..200).rev().filter(|i| i % 2 == 0)).unwrap();
+    assert_eq!(forward.commit().unwrap(), expected);
+    assert_eq!(backward.commit().unwrap(), expected);
+
+    // missing keys are skipped, removing everything empties the trie
+    let mut trie = forward.bulk_remove(0..400).unwrap();
+    assert_eq!(trie.commit().unwrap(), None);
+}
+
+#[test]
+fn test_commit_collecting_writes() {
+    let mut source: Trie<MemoryDb, u32, String> = Trie::new(MemoryDb::new());
+    let mut replica_db = MemoryDb::new();
+
+    for round in 0..3u32 {
+        for i in 0..50 {
+            source = source.insert(&(round * 25 + i), &format!("{}-{}", round, i)).unwrap();
+        }
+        let (root_hash, writes) = source.commit_collecting_writes().unwrap();
+        assert!(!writes.is_empty());
+        for (hash, rlp) in writes {
+            replica_db.insert(&hash, rlp).unwrap();
+        }
+
+        let root_hash = root_hash.unwrap();
+        let replica: Trie<MemoryDb, u32, String> = Trie::new(replica_db.clone())
+            .revert(root_hash)
+            .unwrap();
+        assert_eq!(replica.entries_raw().unwrap(), source.entries_raw().unwrap());
+        assert_eq!(replica.get(&(round * 25)).unwrap(), Some(format!("{}-0", round)));
+    }
+
+    // nothing to write without changes
+    let (root_hash, writes) = source.commit_collecting_writes().unwrap();
+    assert!(writes.is_empty());
+    assert_eq!(root_hash, source.root_hash());
+}
+
+#[test]
+fn test_store_full_keys() {
+    use mpt_rs::mpt::TrieBuilder;
+
+    let keys: Vec<Vec<u8>> = vec![b"do".to_vec(), b"dog".to_vec(), vec![0, 0xff], vec![0x10; 40]];
+    let mut trie: Trie<MemoryDb, Vec<u8>, Vec<u8>> = TrieBuilder::new(MemoryDb::new())
+        .store_full_keys()
+        .build();
+    for key in &keys {
+        trie = trie.insert_raw(key, b"value".to_vec()).unwrap();
+    }
+    let root_hash = trie.commit().unwrap();
+
+    let full_keys = trie.full_keys().unwrap();
+    assert_eq!(full_keys.len(), keys.len());
+    let mut recorded: Vec<_> = full_keys.values().cloned().collect();
+    recorded.sort();
+    let entries: Vec<_> = trie.entries_raw().unwrap().into_iter().map(|(key, _)| key).collect();
+    assert_eq!(entries, recorded);
+
+    let (mut trie, removed) = trie.remove_raw(b"dog").unwrap();
+    assert!(removed);
+    assert_eq!(trie.full_keys().unwrap().len(), keys.len() - 1);
+
+    // the side map does not affect the hash, and is off by default
+    let mut plain: Trie<MemoryDb, Vec<u8>, Vec<u8>> = Trie::new(MemoryDb::new());
+    for key in &keys {
+        plain = plain.insert_raw(key, b"value".to_vec()).unwrap();
+    }
+    assert_eq!(plain.commit().unwrap(), root_hash);
+    assert!(plain.full_keys().is_none());
+    assert_ne!(trie.commit().unwrap(), root_hash);
+}
+
+#[test]
+fn test_validate_against() {
+    use mpt_rs::error::TrieError;
+
+    let mut trie: Trie<MemoryDb, u32, String> = Trie::new(MemoryDb::new());
+    for i in 0..100 {
+        trie = trie.insert(&i, &format!("value {}", i)).unwrap();
+    }
+    let root_hash = trie.commit().unwrap().unwrap();
+
+    let mut copy = MemoryDb::new();
+    for (hash, rlp) in trie.db.iter() {
+        copy.insert(hash, rlp.to_vec()).unwrap();
+    }
+    assert!(trie.validate_against(&copy).unwrap());
+
+    // leave out a node below the root
+    let (gap, rlp) = trie.db.iter()
+        .find(|(hash, _)| **hash != root_hash)
+        .map(|(hash, rlp)| (*hash, rlp.to_vec()))
+        .unwrap();
+    let mut partial = MemoryDb::new();
+    for (hash, rlp) in trie.db.iter().filter(|(hash, _)| **hash != gap) {
+        partial.insert(hash, rlp.to_vec()).unwrap();
+    }
+    assert!(!trie.validate_against(&partial).unwrap());
+    partial.insert(&gap, rlp.clone()).unwrap();
+    assert!(trie.validate_against(&partial).unwrap());
+
+    // same key, different bytes
+    partial.insert(&gap, [&rlp[..], &[0]].concat()).unwrap();
+    assert!(!trie.validate_against(&partial).unwrap());
+
+    let trie = trie.insert(&100, &"new".to_string()).unwrap();
+    assert!(matches!(
+        trie.validate_against(&copy),
+        Err(Error::TrieError(TrieError::Uncommitted))
+    ));
+}
+
+#[test]
+fn test_insert_with() {
+    let calls = Cell::new(0);
+    let make = |value: &str| {
+        calls.set(calls.get() + 1);
+        Ok(value.to_string())
+    };
+
+    let mut trie: Trie<MemoryDb, u32, String> = Trie::new(MemoryDb::new());
+    trie = trie.insert_with(&1, || make("first")).unwrap();
+    trie = trie.insert_with(&1, || make("second")).unwrap();
+    assert_eq!(calls.get(), 2);
+    assert_eq!(trie.get(&1).unwrap(), Some("second".to_string()));
+
+    // the root hash matches an eager insert
+    let mut eager: Trie<MemoryDb, u32, String> = Trie::new(MemoryDb::new());
+    eager = eager.insert(&1, &"second".to_string()).unwrap();
+    assert_eq!(trie.commit().unwrap(), eager.commit().unwrap());
+
+    // errors of the closure are passed on
+    let result = trie.insert_with(&2, || Err(Error::EncodingError("bad".into())));
+    assert!(matches!(result, Err(Error::EncodingError(_))));
+}
+
+#[test]
+fn test_height() {
+    let mut trie: Trie<MemoryDb, String, u32> = Trie::new(MemoryDb::new());
+    assert_eq!(trie.height().unwrap(), 0);
+    trie = trie.insert(&"a".to_string(), &0).unwrap();
+    assert_eq!(trie.height().unwrap(), 1);
+
+    // shallow single byte keys next to a deep chain of keys sharing long prefixes
+    let mut keys: Vec<String> = ["b", "c", "d"].iter().map(|key| key.to_string()).collect();
+    for i in 0..8 {
+        keys.push(format!("{}{}", "k".repeat(i), "z".repeat(16 - i)));
+    }
+    for (i, key) in keys.iter().enumerate() {
+        trie = trie.insert(key, &(i as u32)).unwrap();
+    }
+    keys.push("a".to_string());
+
+    for _ in 0..2 {
+        let longest = keys.iter()
+            .map(|key| trie.get_path(key).unwrap().len())
+            .max()
+            .unwrap();
+        assert!(longest > trie.get_path(&"a".to_string()).unwrap().len());
+        assert_eq!(trie.height().unwrap(), longest);
+        // hashed children are loaded from the database once committed
+        trie.commit().unwrap();
+    }
+}
+
+#[test]
+fn test_debug_summary() {
+    let trie: Trie<MemoryDb, u32, String> = Trie::new(MemoryDb::new());
+    let debug = format!("{:?}", trie);
+    assert!(debug.contains("empty: true"));
+    assert!(debug.contains("dirty: false"));
+    assert!(debug.contains("root_hash: None"));
+
+    let mut trie = trie.insert(&1, &"one".to_string()).unwrap()
+        .insert(&32, &"two".to_string()).unwrap();
+    assert!(format!("{:?}", trie).contains("dirty: true"));
+
+    let root_hash = trie.commit().unwrap().unwrap();
+    let debug = format!("{:?}", trie);
+    assert!(debug.contains(&hex::encode(root_hash)));
+    assert!(debug.contains("dirty: false"));
+    assert!(debug.contains("Branch"));
+    // values are not dumped
+    assert!(!debug.contains("one"));
+}
+
+#[test]
+fn test_namespaced_tries() {
+    use mpt_rs::{mpt::TrieBuilder, db::{NamespacedDb, NAMESPACED_KEY_LEN}};
+
+    type SharedDb = MemoryDb<NAMESPACED_KEY_LEN>;
+    type NamespacedTrie = Trie<NamespacedDb<SharedDb>, u32, String>;
+
+    fn fill(db: SharedDb, namespace: [u8; 4]) -> (SharedDb, KecHash) {
+        let mut trie: NamespacedTrie = TrieBuilder::new(db)
+            .namespace(namespace)
+            .build();
+        for i in 0..50 {
+            trie = trie.insert(&i, &format!("slot {}", i)).unwrap();
+        }
+        let (db, root_hash) = trie.into_db().unwrap();
+        (db.into_base(), root_hash.unwrap())
+    }
+
+    // identical contents, hence identical nodes and root
+    let (shared, root_a) = fill(SharedDb::new(), *b"aaaa");
+    let nodes = shared.len();
+    let (shared, root_b) = fill(shared, *b"bbbb");
+    assert_eq!(root_a, root_b);
+    assert_eq!(shared.len(), 2 * nodes);
+    assert!(shared.iter().all(|(key, _)| key.starts_with(b"aaaa") || key.starts_with(b"bbbb")));
+
+    let plain: Trie<MemoryDb, u32, String> = (0..50).fold(Trie::new(MemoryDb::new()), |trie, i| {
+        trie.insert(&i, &format!("slot {}", i)).unwrap()
+    });
+    assert_eq!(plain.into_db().unwrap().1, Some(root_a));
+
+    let open = |db: SharedDb, namespace| -> Result<NamespacedTrie> {
+        Trie::new(NamespacedDb::with_namespace(db, namespace)).revert(root_a)
+    };
+
+    // garbage collecting the first trie only prunes its own stale nodes
+    let mut trie = open(shared, *b"aaaa").unwrap();
+    for i in 0..25 {
+        trie = trie.remove(&i).unwrap().0;
+    }
+    let removed = trie.gc_to_current().unwrap();
+    assert!(removed > 0);
+    let (db, _) = trie.into_db().unwrap();
+    let shared = db.into_base();
+    assert!(shared.len() > nodes);
+    let trie = open(shared, *b"bbbb").unwrap();
+    for i in 0..50 {
+        assert_eq!(trie.get(&i).unwrap(), Some(format!("slot {}", i)));
+    }
+
+    // clearing the first namespace, as `Trie::destroy` does, leaves the second one whole
+    let mut db = NamespacedDb::with_namespace(trie.into_db().unwrap().0.into_base(), *b"aaaa");
+    db.clear().unwrap();
+    let shared = db.into_base();
+    assert_eq!(shared.len(), nodes);
+    assert!(matches!(open(shared.clone(), *b"aaaa"), Err(Error::StateNotFound(_))));
+    let trie = open(shared, *b"bbbb").unwrap();
+    for i in 0..50 {
+        assert_eq!(trie.get(&i).unwrap(), Some(format!("slot {}", i)));
+    }
+}
+
+#[test]
+fn test_extend_trait() {
+    use serde::{Serialize, Serializer, Deserialize, Deserializer, ser::Error as _};
+
+    let pairs: Vec<(u32, String)> = (0..40).map(|i| (i, format!("value {}", i))).collect();
+
+    let mut extended: Trie<MemoryDb, u32, String> = Trie::new(MemoryDb::new());
+    extended.extend(pairs.clone());
+    let mut inserted: Trie<MemoryDb, u32, String> = Trie::new(MemoryDb::new());
+    for (key, value) in &pairs {
+        inserted = inserted.insert(key, value).unwrap();
+    }
+    assert!(extended.take_error().is_none());
+    assert_eq!(extended.commit().unwrap(), inserted.commit().unwrap());
+
+    /// a value failing to serialize once it reaches the limit
+    #[derive(Debug, Clone, PartialEq)]
+    struct Capped(u32);
+
+    impl Serialize for Capped {
+        fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+            if self.0 >= 10 {
+                return Err(S::Error::custom("over the cap"))
+            }
+            serializer.serialize_u32(self.0)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Capped {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+            u32::deserialize(deserializer).map(Capped)
+        }
+    }
+
+    let mut trie: Trie<MemoryDb, u32, Capped> = Trie::new(MemoryDb::new());
+    trie.extend((0..20).map(|i| (i, Capped(i))));
+    // the error fails the commit, loading stops at the failing pair
+    assert!(matches!(trie.commit(), Err(Error::EncodingError(_))));
+    assert_eq!(trie.get(&9).unwrap(), Some(Capped(9)));
+    assert_eq!(trie.get(&10).unwrap(), None);
+    // the error is kept, retrying does not commit the partial load
+    assert!(matches!(trie.commit(), Err(Error::EncodingError(_))));
+    assert!(trie.db.is_empty());
+    assert!(matches!(trie.clone().commit(), Err(Error::EncodingError(_))));
+
+    // further loads are ignored until the error is taken
+    trie.extend(vec![(30, Capped(3))]);
+    assert_eq!(trie.get(&30).unwrap(), None);
+    assert!(matches!(trie.take_error(), Some(Error::EncodingError(_))));
+    assert!(trie.take_error().is_none());
+
+    trie.extend(vec![(30, Capped(3))]);
+    assert!(trie.take_error().is_none());
+    assert_eq!(trie.get(&30).unwrap(), Some(Capped(3)));
+    assert!(trie.commit().unwrap().is_some());
+}
+
+#[test]
+fn test_identical_insert_is_noop() {
+    let mut trie: Trie<MemoryDb, u32, String> = Trie::new(MemoryDb::new());
+    for i in 0..64 {
+        trie = trie.insert(&i, &format!("value {}", i)).unwrap();
+    }
+    let root_hash = trie.commit().unwrap();
+    let size = trie.db_size_bytes().unwrap();
+
+    trie = trie.insert(&7, &"value 7".to_string()).unwrap();
+    assert!(!trie.is_dirty());
+    assert_eq!(trie.root_hash(), root_hash);
+    assert_eq!(trie.dirty_keys().count(), 0);
+    // the nodes loaded on the way are not kept in memory
+    assert_eq!(trie.db_size_bytes().unwrap(), size);
+    let (committed, writes) = trie.commit_collecting_writes().unwrap();
+    assert_eq!(committed, root_hash);
+    assert!(writes.is_empty());
+
+    let mut raw: Trie<MemoryDb, Vec<u8>, Vec<u8>> = Trie::new(MemoryDb::new());
+    raw = raw.insert_raw(b"key", b"value".to_vec()).unwrap();
+    let raw_hash = raw.commit().unwrap();
+    raw = raw.insert_raw(b"key", b"value".to_vec()).unwrap();
+    assert!(!raw.is_dirty());
+    assert_eq!(raw.root_hash(), raw_hash);
+
+    // another value is still written
+    trie = trie.insert(&7, &"other".to_string()).unwrap();
+    assert!(trie.is_dirty());
+    assert_eq!(trie.root_hash(), None);
+    assert_ne!(trie.commit().unwrap(), root_hash);
+}
+
+#[test]
+fn test_versioned_roots() {
+    use mpt_rs::error::TrieError;
+
+    let mut trie: Trie<MemoryDb, u32, String> = Trie::new(MemoryDb::new());
+    let mut roots = Vec::new();
+    for version in 1..=4u64 {
+        for i in 0..10 {
+            trie = trie.insert(&(i * version as u32), &format!("v{}", version)).unwrap();
+        }
+        roots.push(trie.commit_versioned(version * 10).unwrap().unwrap());
+    }
+    assert_eq!(trie.root_at(20), Some(roots[1]));
+    assert_eq!(trie.root_at(25), None);
+
+    trie = trie.insert(&1000, &"late".to_string()).unwrap();
+    assert!(matches!(
+        trie.commit_versioned(40),
+        Err(Error::TrieError(TrieError::StaleVersion(40)))
+    ));
+
+    // reopen on the same database
+    let (db, _) = trie.into_db().unwrap();
+    let trie: Trie<MemoryDb, u32, String> = Trie::open_versioned(db).unwrap();
+    assert_eq!(trie.root_hash(), Some(roots[3]));
+    assert_eq!(trie.get(&1000).unwrap(), None);
+
+    let root = trie.root_at(20).unwrap();
+    assert_eq!(root, roots[1]);
+    let mut trie = trie.revert(root).unwrap();
+    assert_eq!(trie.get(&18).unwrap(), Some("v2".to_string()));
+    assert_eq!(trie.get(&27).unwrap(), None);
+
+    // the index keeps growing from where it was
+    trie = trie.insert(&27, &"v5".to_string()).unwrap();
+    let latest = trie.commit_versioned(50).unwrap();
+    assert_eq!(trie.root_at(50), latest);
+    assert_eq!(trie.root_at(10), Some(roots[0]));
+
+    // a plain trie has no index
+    let empty: Trie<MemoryDb, u32, String> = Trie::open_versioned(MemoryDb::new()).unwrap();
+    assert_eq!(empty.root_hash(), None);
+    assert_eq!(empty.root_at(10), None);
+}
+
+#[test]
+fn test_value_decode_error() {
+    let mut trie: Trie<MemoryDb, u32, String> = Trie::new(MemoryDb::new());
+    trie = trie.insert(&1, &"a string value".to_string()).unwrap();
+    let (db, root_hash) = trie.into_db().unwrap();
+
+    // read the same state with an incompatible value type, a list instead of a string
+    #[derive(serde::Serialize, serde::Deserialize, Debug)]
+    #[allow(dead_code)]
+    struct Pair {
+        a: u64,
+        b: u64
+    }
+    let trie: Trie<MemoryDb, u32, Pair> = Trie::new(db).revert(root_hash.unwrap()).unwrap();
+    match trie.get(&1) {
+        Err(Error::ValueDecodeError { key_hint, cause }) => {
+            assert_eq!(key_hint, "01");
+            assert!(!cause.is_empty());
+        },
+        other => panic!("unexpected result {:?}", other)
+    }
+    assert!(matches!(trie.entries_typed(), Err(Error::ValueDecodeError { .. })));
+    assert_eq!(trie.get(&2).unwrap(), None);
+}
+
+#[test]
+fn test_gc_to_current() {
+    let mut trie: Trie<MemoryDb, u32, String> = Trie::new(MemoryDb::new());
+    let mut roots = Vec::new();
+    for round in 0..4 {
+        for i in 0..20u32 {
+            trie = trie.insert(&i, &format!("value {} of round {}", i, round)).unwrap();
+        }
+        roots.push(trie.commit().unwrap().unwrap());
+    }
+    trie = trie.insert(&100, &"uncommitted".to_string()).unwrap();
+
+    let before = trie.db.len();
+    let removed = trie.gc_to_current().unwrap();
+    assert!(removed > 0);
+    assert_eq!(trie.db.len(), before - removed);
+    assert!(!trie.is_dirty());
+
+    // only nodes of the current state are left
+    let reachable: Vec<KecHash> = trie.iter_nodes().unwrap().map(|node| node.unwrap().0).collect();
+    assert!(trie.db.iter().all(|(key, _)| reachable.contains(key)));
+    for i in 0..20u32 {
+        assert_eq!(trie.get(&i).unwrap(), Some(format!("value {} of round 3", i)));
+    }
+    assert_eq!(trie.get(&100).unwrap(), Some("uncommitted".to_string()));
+    let current = trie.root_hash().unwrap();
+    assert!(!trie.db.exists(&roots[0]).unwrap());
+
+    assert_eq!(trie.gc_to_current().unwrap(), 0);
+    assert_eq!(trie.root_hash(), Some(current));
+}
+
+#[test]
+fn test_iter_committed() {
+    use mpt_rs::error::TrieError;
+    use serlp::rlp::from_bytes;
+
+    let mut trie: Trie<MemoryDb, u32, String> = Trie::new(MemoryDb::new());
+    let mut inserted = HashMap::new();
+    for i in 0..300u32 {
+        let value = format!("value {}", i * 7);
+        trie = trie.insert(&i, &value).unwrap();
+        inserted.insert(i, value);
+    }
+    assert!(matches!(trie.iter_committed(), Err(Error::TrieError(TrieError::Uncommitted))));
+    trie.commit().unwrap();
+
+    let entries: Vec<(Vec<u8>, Vec<u8>)> = trie.iter_committed().unwrap().map(|entry| entry.unwrap()).collect();
+    assert_eq!(entries, trie.entries_raw().unwrap());
+    let iterated: HashMap<u32, String> = entries.iter()
+        .map(|(key, value)| (from_bytes(key).unwrap(), from_bytes(value).unwrap()))
+        .collect();
+    assert_eq!(iterated, inserted);
+
+    // keys held in branch values come before their extensions
+    let mut raw: Trie<MemoryDb, Vec<u8>, Vec<u8>> = Trie::new(MemoryDb::new());
+    for key in [&b"dogs"[..], b"do", b"dog", b"horse"] {
+        raw = raw.insert_raw(key, key.to_vec()).unwrap();
+    }
+    raw.commit().unwrap();
+    let keys: Vec<Vec<u8>> = raw.iter_committed().unwrap().map(|entry| entry.unwrap().0).collect();
+    assert_eq!(keys, vec![b"do".to_vec(), b"dog".to_vec(), b"dogs".to_vec(), b"horse".to_vec()]);
+
+    let empty: Trie<MemoryDb, u32, String> = Trie::new(MemoryDb::new());
+    assert_eq!(empty.iter_committed().unwrap().count(), 0);
+}
+
+#[test]
+fn test_key_ending_at_extension() {
+    let keys: [&[u8]; 4] = [b"abcdef\x10", b"abcdef\x20", b"abcdef", b"abc"];
+
+    // "abcdef" ends exactly where the extension over the first two keys 
+    // ends, "abc" ends in the middle of it, in every insertion order
+    for order in [[0, 1, 2, 3], [0, 1, 3, 2], [3, 0, 1, 2], [2, 3, 0, 1]] {
+        let mut trie: Trie<MemoryDb, Vec<u8>, Vec<u8>> = Trie::new(MemoryDb::new());
+        for &i in &order {
+            trie = trie.insert_raw(keys[i], vec![i as u8]).unwrap();
+        }
+        let root_hash = trie.commit().unwrap().unwrap();
+        let trie: Trie<MemoryDb, Vec<u8>, Vec<u8>> = Trie::new(trie.db).revert(root_hash).unwrap();
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(trie.get_raw(key).unwrap(), Some(vec![i as u8]));
+        }
+        assert_eq!(trie.get_raw(b"abcd").unwrap(), None);
+        assert_eq!(trie.entries_raw().unwrap().len(), 4);
+    }
+
+    let mut trie: Trie<MemoryDb, Vec<u8>, Vec<u8>> = Trie::new(MemoryDb::new());
+    trie = trie.insert_raw(b"abcdef", b"long".to_vec()).unwrap();
+    trie = trie.insert_raw(b"abc", b"short".to_vec()).unwrap();
+    trie.commit().unwrap();
+    assert_eq!(trie.get_raw(b"abcdef").unwrap(), Some(b"long".to_vec()));
+    assert_eq!(trie.get_raw(b"abc").unwrap(), Some(b"short".to_vec()));
+}
+
+/// Stores nodes under hashes of 64 bytes.
+struct WideDb(HashMap<KecHash<64>, Vec<u8>>);
+
+impl Database<64> for WideDb {
+    type Error = Error;
+
+    fn new() -> Self {
+        Self(HashMap::new())
+    }
+
+    fn insert(&mut self, key: &KecHash<64>, value: Vec<u8>) -> Result<()> {
+        self.0.insert(*key, value);
+        Ok(())
+    }
+
+    fn exists(&mut self, key: &KecHash<64>) -> Result<bool> {
+        Ok(self.0.contains_key(key))
+    }
+
+    fn get(&self, key: &KecHash<64>) -> Result<Option<Vec<u8>>> {
+        Ok(self.0.get(key).cloned())
+    }
+}
+
+#[test]
+fn test_wide_hash() {
+    let mut trie: Trie<WideDb, u64, String, 64> = Trie::new(WideDb::new());
+    for i in 0..32u64 {
+        trie = trie.insert(&i, &format!("value-{}", i)).unwrap();
+    }
+    let root_hash = trie.commit().unwrap().unwrap();
+    assert_eq!(root_hash.len(), 64);
+    assert_eq!(root_hash, node_hash::<64>(&trie.db.0[&root_hash]));
+    // every stored node is referenced by a 64 byte hash of its encoding
+    assert!(trie.db.0.iter().all(|(key, rlp)| *key == node_hash::<64>(rlp)));
+
+    let (db, _) = trie.into_db().unwrap();
+    let reopened: Trie<WideDb, u64, String, 64> = Trie::new(db).revert(root_hash).unwrap();
+    for i in 0..32u64 {
+        assert_eq!(reopened.get(&i).unwrap(), Some(format!("value-{}", i)));
+    }
+    assert_eq!(reopened.get(&32).unwrap(), None);
+}